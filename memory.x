@@ -0,0 +1,7 @@
+MEMORY
+{
+  /* STM32F412RG: 1 MB of flash, the last sector (0x080E0000, 128 KB) holds the
+     settings, see `config`, and must never be reached by the firmware image */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 896K
+  RAM   : ORIGIN = 0x20000000, LENGTH = 256K
+}
@@ -0,0 +1,50 @@
+use crate::Frame;
+
+// Transport used by the CAN tasks, implemented by the bxCAN driver of the firmware
+// and by the virtual bus of the host tests
+
+// number of transmit mailboxes of the bxCAN
+pub const TX_SLOTS: usize = 3;
+
+// error reported by the receive path, the last error code of the controller
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusError {
+    Stuff,
+    Form,
+    Acknowledge,
+    BitRecessive,
+    BitDominant,
+    Crc,
+    Software,
+    BusOff,
+    BusPassive,
+    BusWarning,
+}
+
+// result of a frame placed in a transmit slot
+pub struct Transmitted {
+    pub slot: usize,
+    // lower priority frame which was removed from the slot to make room
+    pub dequeued: Option<Frame>,
+}
+
+#[allow(async_fn_in_trait)]
+pub trait CanTransmitter {
+    // place the frame in a free slot, or in place of a pending frame with lower
+    // priority, return None if all slots hold frames with higher priority
+    fn try_transmit(&mut self, frame: &Frame) -> Option<Transmitted>;
+
+    // remove a pending frame, return true if it was not sent yet
+    fn abort(&mut self, slot: usize) -> bool;
+
+    fn is_idle(&self, slot: usize) -> bool;
+
+    // wait until any slot is free
+    async fn wait_slot(&mut self);
+}
+
+#[allow(async_fn_in_trait)]
+pub trait CanReceiver {
+    async fn receive(&mut self) -> Result<Frame, BusError>;
+}
@@ -1,8 +1,36 @@
+use crate::Frame;
+
+// bars of the battery gauge, each one is 20% of SOC
+const SOC_BARS: u8 = 5;
+
+// requests of the other tasks to the CanTx task, which sends the frames of the display
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScreenRequest {
+    Power(bool),
+    LeftIndicator,
+    RightIndicator,
+    Ready,
+    Speed(u8),
+    Soc(u8),
+    // remaining range in km
+    Range(u16),
+    // there is no ABS controller on the bus yet, nothing sends this request
+    Abs(bool),
+    HeadLight(bool),
+}
+
 pub struct CanMessage {
     pub id: u32,
     pub data: [u8; 8],
 }
 
+impl From<CanMessage> for Frame {
+    fn from(message: CanMessage) -> Self {
+        Frame::new_extended(message.id, &message.data).unwrap()
+    }
+}
+
 #[allow(dead_code)]
 pub struct SegLcd {
     status_1: CanMessage,
@@ -13,14 +41,17 @@ pub struct SegLcd {
 #[allow(dead_code)]
 impl SegLcd {
     pub fn init() -> Self {
+        // J1939 priority 4, PGN 0xF810, source 0xA3
         let status_1 = CanMessage {
             id: 0x10F810A3,
             data: [0x00; 8],
         };
+        // J1939 priority 4, PGN 0xF810, source 0x9A
         let status_2 = CanMessage {
             id: 0x10F8109A,
             data: [0x00; 8],
         };
+        // J1939 priority 6, PGN 0x0000 to the address 0xF9, source 0x07
         let status_3 = CanMessage {
             id: 0x1800F907,
             data: [0x00; 8],
@@ -117,6 +148,7 @@ impl SegLcd {
         }
     }
 
+    // status_1: byte 0 bit 3 ABS telltale
     pub fn abs_on(&mut self) -> CanMessage {
         self.status_1.data[0] |= 0x01 << 3;
         CanMessage {
@@ -180,4 +212,51 @@ impl SegLcd {
             data: self.status_1.data,
         }
     }
+
+    // status_2: byte 0 speed in km/h
+    pub fn speed(&mut self, speed: u8) -> CanMessage {
+        self.status_2.data[0] = speed;
+        CanMessage {
+            id: self.status_2.id,
+            data: self.status_2.data,
+        }
+    }
+
+    // status_2: byte 1 SOC in %, byte 2 one bit per bar of the gauge
+    pub fn soc(&mut self, soc: u8) -> CanMessage {
+        let soc = soc.min(100);
+        let bars = soc.div_ceil(100 / SOC_BARS);
+        self.status_2.data[1] = soc;
+        self.status_2.data[2] = (1u8 << bars) - 1;
+        CanMessage {
+            id: self.status_2.id,
+            data: self.status_2.data,
+        }
+    }
+
+    // status_2: byte 3 and 4 remaining range in km
+    pub fn range(&mut self, km: u16) -> CanMessage {
+        self.status_2.data[3..5].copy_from_slice(&km.to_le_bytes());
+        CanMessage {
+            id: self.status_2.id,
+            data: self.status_2.data,
+        }
+    }
+
+    // status_3: byte 0 bit 1 headlight telltale
+    pub fn headlight_on(&mut self) -> CanMessage {
+        self.status_3.data[0] |= 0x01 << 1;
+        CanMessage {
+            id: self.status_3.id,
+            data: self.status_3.data,
+        }
+    }
+
+    pub fn headlight_off(&mut self) -> CanMessage {
+        self.status_3.data[0] &= !(0x01 << 1);
+        CanMessage {
+            id: self.status_3.id,
+            data: self.status_3.data,
+        }
+    }
 }
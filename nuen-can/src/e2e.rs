@@ -0,0 +1,365 @@
+// Layout of a protected message, in the style of AUTOSAR E2E profile 1:
+//  byte 0: CRC8 (SAE J1850) over the data ID and the bytes 1..8
+//  byte 1: rolling counter 0..=14 in the low nibble
+const CRC_BYTE: usize = 0;
+const COUNTER_BYTE: usize = 1;
+const COUNTER_MAX: u8 = 14;
+
+// consecutive errors before a fault is raised, and consecutive
+// good messages before it is cleared again
+const ERROR_THRESHOLD: u8 = 3;
+const OK_THRESHOLD: u8 = 10;
+
+#[derive(Clone, Copy)]
+pub struct E2eConfig {
+    pub id: u32,
+    // unique identifier of the message, included in the CRC but not sent
+    pub data_id: u16,
+    // largest counter jump which is still accepted as "some lost"
+    pub max_delta_counter: u8,
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum E2eStatus {
+    // first message after start-up, counter not checked
+    Initial,
+    Ok,
+    OkSomeLost,
+    Repeated,
+    WrongSequence,
+    WrongCrc,
+}
+
+impl E2eStatus {
+    pub fn is_ok(&self) -> bool {
+        matches!(
+            self,
+            E2eStatus::Initial | E2eStatus::Ok | E2eStatus::OkSomeLost
+        )
+    }
+}
+
+// CRC8 SAE J1850: polynomial 0x1D, initial value 0xFF, final xor 0xFF
+pub fn crc8(data_id: u16, data: &[u8]) -> u8 {
+    let id = data_id.to_le_bytes();
+    crc8_j1850(id.iter().chain(data.iter()))
+}
+
+fn crc8_j1850<'a>(bytes: impl Iterator<Item = &'a u8>) -> u8 {
+    let mut crc: u8 = 0xFF;
+    for byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            if crc & 0x80 != 0 {
+                crc = (crc << 1) ^ 0x1D;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc ^ 0xFF
+}
+
+// adds counter and CRC to the messages of `configs`
+pub struct E2eProtector<const N: usize> {
+    configs: &'static [E2eConfig; N],
+    counters: [u8; N],
+}
+
+impl<const N: usize> E2eProtector<N> {
+    pub fn init(configs: &'static [E2eConfig; N]) -> Self {
+        E2eProtector {
+            configs,
+            counters: [0; N],
+        }
+    }
+
+    // write counter and CRC into the data, return false if the message is not protected
+    pub fn protect(&mut self, id: u32, data: &mut [u8; 8]) -> bool {
+        let Some(index) = self.configs.iter().position(|config| config.id == id) else {
+            return false;
+        };
+        let counter = self.counters[index];
+        data[COUNTER_BYTE] = (data[COUNTER_BYTE] & 0xF0) | counter;
+        data[CRC_BYTE] = crc8(self.configs[index].data_id, &data[CRC_BYTE + 1..]);
+        self.counters[index] = if counter >= COUNTER_MAX {
+            0
+        } else {
+            counter + 1
+        };
+        true
+    }
+}
+
+#[derive(Clone, Copy)]
+struct CheckState {
+    last_counter: Option<u8>,
+    errors: u8,
+    oks: u8,
+    faulty: bool,
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum E2eEvent {
+    Failed,
+    Recovered,
+}
+
+// checks counter and CRC of the messages of `configs`
+pub struct E2eChecker<const N: usize> {
+    configs: &'static [E2eConfig; N],
+    states: [CheckState; N],
+}
+
+impl<const N: usize> E2eChecker<N> {
+    pub fn init(configs: &'static [E2eConfig; N]) -> Self {
+        E2eChecker {
+            configs,
+            states: [CheckState {
+                last_counter: None,
+                errors: 0,
+                oks: 0,
+                faulty: false,
+            }; N],
+        }
+    }
+
+    // return None if the message is not protected
+    pub fn check(&mut self, id: u32, data: &[u8]) -> Option<E2eStatus> {
+        let index = self.configs.iter().position(|config| config.id == id)?;
+        let config = &self.configs[index];
+        let state = &mut self.states[index];
+
+        if data.len() <= COUNTER_BYTE
+            || crc8(config.data_id, &data[CRC_BYTE + 1..]) != data[CRC_BYTE]
+        {
+            return Some(E2eStatus::WrongCrc);
+        }
+        let counter = data[COUNTER_BYTE] & 0x0F;
+        if counter > COUNTER_MAX {
+            return Some(E2eStatus::WrongSequence);
+        }
+        let status = match state.last_counter {
+            None => E2eStatus::Initial,
+            Some(last) => {
+                let delta = (counter + COUNTER_MAX + 1 - last) % (COUNTER_MAX + 1);
+                if delta == 0 {
+                    E2eStatus::Repeated
+                } else if delta == 1 {
+                    E2eStatus::Ok
+                } else if delta <= config.max_delta_counter {
+                    E2eStatus::OkSomeLost
+                } else {
+                    E2eStatus::WrongSequence
+                }
+            }
+        };
+        // a repeated message must not become the new reference
+        if status != E2eStatus::Repeated {
+            state.last_counter = Some(counter);
+        }
+        Some(status)
+    }
+
+    // debounce the check results, return an event when the message
+    // changes between faulty and healthy
+    pub fn report(&mut self, id: u32, status: E2eStatus) -> Option<E2eEvent> {
+        let index = self.configs.iter().position(|config| config.id == id)?;
+        let state = &mut self.states[index];
+        if status.is_ok() {
+            state.errors = 0;
+            state.oks = state.oks.saturating_add(1);
+            if state.faulty && state.oks >= OK_THRESHOLD {
+                state.faulty = false;
+                return Some(E2eEvent::Recovered);
+            }
+        } else {
+            state.oks = 0;
+            state.errors = state.errors.saturating_add(1);
+            if !state.faulty && state.errors >= ERROR_THRESHOLD {
+                state.faulty = true;
+                return Some(E2eEvent::Failed);
+            }
+        }
+        None
+    }
+
+    pub fn any_faulty(&self) -> bool {
+        self.states.iter().any(|state| state.faulty)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATUS: u32 = 0x1806E501;
+    const CONFIGS: [E2eConfig; 2] = [
+        E2eConfig {
+            id: STATUS,
+            data_id: 0x0201,
+            max_delta_counter: 2,
+        },
+        E2eConfig {
+            id: 0x180301EF,
+            data_id: 0x0103,
+            max_delta_counter: 1,
+        },
+    ];
+
+    // protected status message with the given counter
+    fn message(counter: u8) -> [u8; 8] {
+        let mut data = [0x00, counter, 0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC];
+        data[0] = crc8(CONFIGS[0].data_id, &data[1..]);
+        data
+    }
+
+    #[test]
+    fn crc8_matches_the_sae_j1850_check_value() {
+        // check value of CRC-8/SAE-J1850 for "123456789"
+        assert_eq!(crc8_j1850(b"123456789".iter()), 0x4B);
+        assert_eq!(crc8_j1850([].iter()), 0x00);
+    }
+
+    #[test]
+    fn crc8_covers_the_data_id() {
+        let data = [0x01, 0x02, 0x03];
+        assert_eq!(
+            crc8(0x3231, &data),
+            crc8_j1850([0x31, 0x32, 0x01, 0x02, 0x03].iter())
+        );
+        assert_ne!(crc8(0x0201, &data), crc8(0x0202, &data));
+    }
+
+    #[test]
+    fn protected_message_passes_the_check() {
+        static TX: [E2eConfig; 1] = [CONFIGS[0]];
+        static RX: [E2eConfig; 1] = [CONFIGS[0]];
+        let mut protector = E2eProtector::init(&TX);
+        let mut checker = E2eChecker::init(&RX);
+        let mut data = [0x00, 0xF0, 1, 2, 3, 4, 5, 6];
+        assert!(protector.protect(STATUS, &mut data));
+        // the high nibble of the counter byte is kept
+        assert_eq!(data[1], 0xF0);
+        assert_eq!(checker.check(STATUS, &data), Some(E2eStatus::Initial));
+        assert!(protector.protect(STATUS, &mut data));
+        assert_eq!(data[1], 0xF1);
+        assert_eq!(checker.check(STATUS, &data), Some(E2eStatus::Ok));
+    }
+
+    #[test]
+    fn unknown_message_is_not_protected() {
+        static TX: [E2eConfig; 2] = CONFIGS;
+        let mut protector = E2eProtector::init(&TX);
+        let mut checker = E2eChecker::init(&TX);
+        let mut data = [0xAA; 8];
+        assert!(!protector.protect(0x123, &mut data));
+        assert_eq!(data, [0xAA; 8]);
+        assert_eq!(checker.check(0x123, &data), None);
+        assert!(checker.report(0x123, E2eStatus::WrongCrc).is_none());
+    }
+
+    #[test]
+    fn protector_counter_wraps_after_14() {
+        static TX: [E2eConfig; 2] = CONFIGS;
+        let mut protector = E2eProtector::init(&TX);
+        let mut data = [0x00; 8];
+        for counter in (0..=COUNTER_MAX).chain(0..2) {
+            assert!(protector.protect(STATUS, &mut data));
+            assert_eq!(data[1], counter);
+        }
+    }
+
+    #[test]
+    fn counter_wrap_is_in_sequence() {
+        static RX: [E2eConfig; 2] = CONFIGS;
+        let mut checker = E2eChecker::init(&RX);
+        assert_eq!(
+            checker.check(STATUS, &message(13)),
+            Some(E2eStatus::Initial)
+        );
+        assert_eq!(checker.check(STATUS, &message(14)), Some(E2eStatus::Ok));
+        assert_eq!(checker.check(STATUS, &message(0)), Some(E2eStatus::Ok));
+        assert_eq!(
+            checker.check(STATUS, &message(2)),
+            Some(E2eStatus::OkSomeLost)
+        );
+        assert_eq!(
+            checker.check(STATUS, &message(14)),
+            Some(E2eStatus::WrongSequence)
+        );
+        // 14 -> 1 lost the message with counter 0
+        assert_eq!(
+            checker.check(STATUS, &message(1)),
+            Some(E2eStatus::OkSomeLost)
+        );
+    }
+
+    #[test]
+    fn repeated_message_keeps_the_reference() {
+        static RX: [E2eConfig; 2] = CONFIGS;
+        let mut checker = E2eChecker::init(&RX);
+        checker.check(STATUS, &message(14));
+        assert_eq!(
+            checker.check(STATUS, &message(14)),
+            Some(E2eStatus::Repeated)
+        );
+        assert_eq!(checker.check(STATUS, &message(0)), Some(E2eStatus::Ok));
+    }
+
+    #[test]
+    fn corrupted_message_fails_the_crc() {
+        static RX: [E2eConfig; 2] = CONFIGS;
+        let mut checker = E2eChecker::init(&RX);
+        let mut data = message(3);
+        data[5] ^= 0x01;
+        assert_eq!(checker.check(STATUS, &data), Some(E2eStatus::WrongCrc));
+        assert_eq!(checker.check(STATUS, &data[..1]), Some(E2eStatus::WrongCrc));
+        // a counter of 15 is never sent
+        let mut data = [0x00, 0x0F, 0, 0, 0, 0, 0, 0];
+        data[0] = crc8(CONFIGS[0].data_id, &data[1..]);
+        assert_eq!(checker.check(STATUS, &data), Some(E2eStatus::WrongSequence));
+    }
+
+    #[test]
+    fn report_debounces_failure_and_recovery() {
+        static RX: [E2eConfig; 2] = CONFIGS;
+        let mut checker = E2eChecker::init(&RX);
+        for _ in 1..ERROR_THRESHOLD {
+            assert_eq!(checker.report(STATUS, E2eStatus::WrongCrc), None);
+        }
+        // a good message in between starts the count again
+        assert_eq!(checker.report(STATUS, E2eStatus::Ok), None);
+        for _ in 1..ERROR_THRESHOLD {
+            assert_eq!(checker.report(STATUS, E2eStatus::Repeated), None);
+        }
+        assert_eq!(
+            checker.report(STATUS, E2eStatus::WrongSequence),
+            Some(E2eEvent::Failed)
+        );
+        assert!(checker.any_faulty());
+        assert_eq!(checker.report(STATUS, E2eStatus::WrongCrc), None);
+        for _ in 1..OK_THRESHOLD {
+            assert_eq!(checker.report(STATUS, E2eStatus::OkSomeLost), None);
+        }
+        assert_eq!(
+            checker.report(STATUS, E2eStatus::Ok),
+            Some(E2eEvent::Recovered)
+        );
+        assert!(!checker.any_faulty());
+    }
+
+    #[test]
+    fn counter_wrap_over_many_messages_reports_nothing() {
+        static RX: [E2eConfig; 2] = CONFIGS;
+        let mut checker = E2eChecker::init(&RX);
+        for counter in (0..100).map(|n| n % (COUNTER_MAX + 1)) {
+            let status = checker.check(STATUS, &message(counter)).unwrap();
+            assert!(status.is_ok());
+            assert_eq!(checker.report(STATUS, status), None);
+        }
+        assert!(!checker.any_faulty());
+    }
+}
@@ -0,0 +1,72 @@
+// classic CAN data frame, the firmware converts it for the CAN driver
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    id: u32,
+    extended: bool,
+    data: [u8; 8],
+    len: u8,
+}
+
+impl Frame {
+    pub fn new_standard(id: u16, data: &[u8]) -> Option<Self> {
+        if id > 0x7FF {
+            return None;
+        }
+        Frame::from_parts(id as u32, false, data)
+    }
+
+    pub fn new_extended(id: u32, data: &[u8]) -> Option<Self> {
+        if id > 0x1FFF_FFFF {
+            return None;
+        }
+        Frame::from_parts(id, true, data)
+    }
+
+    // same kind of identifier as a received frame, e.g. to rewrite its ID or data
+    pub fn new(id: u32, extended: bool, data: &[u8]) -> Option<Self> {
+        if extended {
+            Frame::new_extended(id, data)
+        } else {
+            Frame::new_standard(u16::try_from(id).ok()?, data)
+        }
+    }
+
+    fn from_parts(id: u32, extended: bool, data: &[u8]) -> Option<Self> {
+        if data.len() > 8 {
+            return None;
+        }
+        let mut bytes = [0x00; 8];
+        bytes[..data.len()].copy_from_slice(data);
+        Some(Frame {
+            id,
+            extended,
+            data: bytes,
+            len: data.len() as u8,
+        })
+    }
+
+    // raw value of the standard or extended identifier
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn is_extended(&self) -> bool {
+        self.extended
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+
+    // arbitration field on the bus, the lower value wins: the base ID, the RTR or SRR
+    // bit and the IDE bit, so a standard frame wins against an extended one with the
+    // same base ID, then the extended ID bits
+    pub fn arbitration(&self) -> u32 {
+        if self.extended {
+            (self.id >> 18) << 20 | 0b11 << 18 | (self.id & 0x3_FFFF)
+        } else {
+            self.id << 20
+        }
+    }
+}
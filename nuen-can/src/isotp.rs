@@ -0,0 +1,663 @@
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+use crate::Frame;
+
+// ISO 15765-2 transport over classic CAN with normal addressing
+pub const ISOTP_MAX_LEN: usize = 4095;
+
+const PADDING: u8 = 0xCC;
+// time to wait for a flow control frame (N_Bs) and for the next consecutive frame (N_Cr)
+const N_BS: Duration = Duration::from_millis(1000);
+const N_CR: Duration = Duration::from_millis(1000);
+// maximum number of flow control WAIT frames accepted in a row (N_WFTmax)
+const MAX_WAIT_FRAMES: u8 = 10;
+
+const PCI_SINGLE: u8 = 0x0;
+const PCI_FIRST: u8 = 0x1;
+const PCI_CONSECUTIVE: u8 = 0x2;
+const PCI_FLOW_CONTROL: u8 = 0x3;
+
+const FLOW_CONTINUE: u8 = 0x0;
+const FLOW_WAIT: u8 = 0x1;
+const FLOW_OVERFLOW: u8 = 0x2;
+
+pub struct IsoTpConfig {
+    pub rx_id: u32,
+    pub tx_id: u32,
+    pub extended: bool,
+    // block size and separation time sent in our flow control frames
+    pub block_size: u8,
+    pub st_min: u8,
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsoTpError {
+    Busy,
+    TooLong,
+    Timeout,
+    WrongSequence,
+    Overflow,
+    WaitLimit,
+    InvalidFrame,
+}
+
+// STmin 0x00..=0x7F are milliseconds, 0xF1..=0xF9 are 100..=900 microseconds,
+// reserved values are handled as the longest time
+fn decode_st_min(st_min: u8) -> Duration {
+    match st_min {
+        0x00..=0x7F => Duration::from_millis(st_min as u64),
+        0xF1..=0xF9 => Duration::from_micros((st_min - 0xF0) as u64 * 100),
+        _ => Duration::from_millis(0x7F),
+    }
+}
+
+enum RxState {
+    Idle,
+    Receiving {
+        length: usize,
+        next_seq: u8,
+        block_count: u8,
+        deadline: Instant,
+    },
+}
+
+enum TxState {
+    Idle,
+    Start,
+    WaitFlowControl {
+        deadline: Instant,
+        waits: u8,
+    },
+    Sending {
+        // remaining frames in the block, None if the receiver wants no more flow control
+        block_left: Option<u8>,
+        st_min: Duration,
+        next_at: Instant,
+    },
+}
+
+pub struct IsoTp {
+    config: &'static IsoTpConfig,
+    rx_state: RxState,
+    rx_buffer: Vec<u8, ISOTP_MAX_LEN>,
+    tx_state: TxState,
+    tx_buffer: Vec<u8, ISOTP_MAX_LEN>,
+    tx_pos: usize,
+    tx_seq: u8,
+    // flow control frame which has to be sent before anything else
+    pending_fc: Option<Frame>,
+}
+
+impl IsoTp {
+    pub fn init(config: &'static IsoTpConfig) -> Self {
+        IsoTp {
+            config,
+            rx_state: RxState::Idle,
+            rx_buffer: Vec::new(),
+            tx_state: TxState::Idle,
+            tx_buffer: Vec::new(),
+            tx_pos: 0,
+            tx_seq: 0,
+            pending_fc: None,
+        }
+    }
+
+    pub fn is_busy(&self) -> bool {
+        !matches!(self.tx_state, TxState::Idle)
+    }
+
+    // queue a message for transmission, the frames are produced by `poll`
+    pub fn send(&mut self, payload: &[u8]) -> Result<(), IsoTpError> {
+        if self.is_busy() {
+            return Err(IsoTpError::Busy);
+        }
+        self.tx_buffer.clear();
+        self.tx_buffer
+            .extend_from_slice(payload)
+            .map_err(|_| IsoTpError::TooLong)?;
+        self.tx_pos = 0;
+        self.tx_state = TxState::Start;
+        Ok(())
+    }
+
+    // process a frame received on the rx ID, return the payload once a message is complete
+    pub fn receive(&mut self, data: &[u8], now: Instant) -> Result<Option<&[u8]>, IsoTpError> {
+        if data.is_empty() {
+            return Err(IsoTpError::InvalidFrame);
+        }
+        match data[0] >> 4 {
+            PCI_SINGLE => {
+                let length = (data[0] & 0x0F) as usize;
+                if length == 0 || length > 7 || length >= data.len() {
+                    return Err(IsoTpError::InvalidFrame);
+                }
+                // a new message aborts any reception in progress
+                self.rx_state = RxState::Idle;
+                self.rx_buffer.clear();
+                self.rx_buffer.extend_from_slice(&data[1..=length]).ok();
+                Ok(Some(&self.rx_buffer))
+            }
+            PCI_FIRST => {
+                if data.len() < 8 {
+                    return Err(IsoTpError::InvalidFrame);
+                }
+                // a length of 0 announces a message above 4095 bytes with a 32 bit length
+                let (length, start) = match (((data[0] & 0x0F) as usize) << 8) | data[1] as usize {
+                    0 => (
+                        u32::from_be_bytes([data[2], data[3], data[4], data[5]]) as usize,
+                        6,
+                    ),
+                    length => (length, 2),
+                };
+                if length < 8 {
+                    return Err(IsoTpError::InvalidFrame);
+                }
+                self.rx_state = RxState::Idle;
+                self.rx_buffer.clear();
+                if length > ISOTP_MAX_LEN {
+                    self.pending_fc = Some(self.flow_control(FLOW_OVERFLOW));
+                    return Err(IsoTpError::Overflow);
+                }
+                self.rx_buffer.extend_from_slice(&data[start..8]).ok();
+                self.rx_state = RxState::Receiving {
+                    length,
+                    next_seq: 1,
+                    block_count: 0,
+                    deadline: now + N_CR,
+                };
+                self.pending_fc = Some(self.flow_control(FLOW_CONTINUE));
+                Ok(None)
+            }
+            PCI_CONSECUTIVE => {
+                let RxState::Receiving {
+                    length,
+                    next_seq,
+                    block_count,
+                    ..
+                } = self.rx_state
+                else {
+                    // not waiting for consecutive frames, ignore it
+                    return Ok(None);
+                };
+                if data[0] & 0x0F != next_seq {
+                    self.rx_state = RxState::Idle;
+                    return Err(IsoTpError::WrongSequence);
+                }
+                let count = (length - self.rx_buffer.len()).min(7).min(data.len() - 1);
+                self.rx_buffer.extend_from_slice(&data[1..=count]).ok();
+                if self.rx_buffer.len() >= length {
+                    self.rx_state = RxState::Idle;
+                    return Ok(Some(&self.rx_buffer));
+                }
+                let mut block_count = block_count + 1;
+                if self.config.block_size != 0 && block_count >= self.config.block_size {
+                    block_count = 0;
+                    self.pending_fc = Some(self.flow_control(FLOW_CONTINUE));
+                }
+                self.rx_state = RxState::Receiving {
+                    length,
+                    next_seq: (next_seq + 1) & 0x0F,
+                    block_count,
+                    deadline: now + N_CR,
+                };
+                Ok(None)
+            }
+            PCI_FLOW_CONTROL => {
+                let TxState::WaitFlowControl { waits, .. } = self.tx_state else {
+                    // not waiting for flow control, ignore it
+                    return Ok(None);
+                };
+                if data.len() < 3 {
+                    return Err(IsoTpError::InvalidFrame);
+                }
+                match data[0] & 0x0F {
+                    FLOW_CONTINUE => {
+                        self.tx_state = TxState::Sending {
+                            block_left: if data[1] == 0 { None } else { Some(data[1]) },
+                            st_min: decode_st_min(data[2]),
+                            next_at: now,
+                        };
+                        Ok(None)
+                    }
+                    FLOW_WAIT if waits < MAX_WAIT_FRAMES => {
+                        self.tx_state = TxState::WaitFlowControl {
+                            deadline: now + N_BS,
+                            waits: waits + 1,
+                        };
+                        Ok(None)
+                    }
+                    FLOW_WAIT => {
+                        self.tx_state = TxState::Idle;
+                        Err(IsoTpError::WaitLimit)
+                    }
+                    FLOW_OVERFLOW => {
+                        self.tx_state = TxState::Idle;
+                        Err(IsoTpError::Overflow)
+                    }
+                    _ => {
+                        self.tx_state = TxState::Idle;
+                        Err(IsoTpError::InvalidFrame)
+                    }
+                }
+            }
+            _ => Err(IsoTpError::InvalidFrame),
+        }
+    }
+
+    // return the next frame to transmit and check the timeouts, must be called periodically
+    pub fn poll(&mut self, now: Instant) -> Result<Option<Frame>, IsoTpError> {
+        if let Some(frame) = self.pending_fc.take() {
+            return Ok(Some(frame));
+        }
+        if let RxState::Receiving { deadline, .. } = self.rx_state {
+            if now > deadline {
+                self.rx_state = RxState::Idle;
+                return Err(IsoTpError::Timeout);
+            }
+        }
+
+        match self.tx_state {
+            TxState::Idle => Ok(None),
+            TxState::Start => {
+                let length = self.tx_buffer.len();
+                if length <= 7 {
+                    let mut bytes = [PADDING; 8];
+                    bytes[0] = (PCI_SINGLE << 4) | length as u8;
+                    bytes[1..=length].copy_from_slice(&self.tx_buffer);
+                    self.tx_state = TxState::Idle;
+                    Ok(Some(self.frame(&bytes)))
+                } else {
+                    let mut bytes = [PADDING; 8];
+                    bytes[0] = (PCI_FIRST << 4) | (length >> 8) as u8;
+                    bytes[1] = length as u8;
+                    bytes[2..8].copy_from_slice(&self.tx_buffer[..6]);
+                    self.tx_pos = 6;
+                    self.tx_seq = 1;
+                    self.tx_state = TxState::WaitFlowControl {
+                        deadline: now + N_BS,
+                        waits: 0,
+                    };
+                    Ok(Some(self.frame(&bytes)))
+                }
+            }
+            TxState::WaitFlowControl { deadline, .. } => {
+                if now > deadline {
+                    self.tx_state = TxState::Idle;
+                    return Err(IsoTpError::Timeout);
+                }
+                Ok(None)
+            }
+            TxState::Sending {
+                block_left,
+                st_min,
+                next_at,
+            } => {
+                if now < next_at {
+                    return Ok(None);
+                }
+                let count = (self.tx_buffer.len() - self.tx_pos).min(7);
+                let mut bytes = [PADDING; 8];
+                bytes[0] = (PCI_CONSECUTIVE << 4) | self.tx_seq;
+                bytes[1..=count].copy_from_slice(&self.tx_buffer[self.tx_pos..self.tx_pos + count]);
+                self.tx_pos += count;
+                self.tx_seq = (self.tx_seq + 1) & 0x0F;
+
+                self.tx_state = if self.tx_pos >= self.tx_buffer.len() {
+                    TxState::Idle
+                } else {
+                    match block_left {
+                        Some(1) => TxState::WaitFlowControl {
+                            deadline: now + N_BS,
+                            waits: 0,
+                        },
+                        _ => TxState::Sending {
+                            block_left: block_left.map(|left| left - 1),
+                            st_min,
+                            next_at: now + st_min,
+                        },
+                    }
+                };
+                Ok(Some(self.frame(&bytes)))
+            }
+        }
+    }
+
+    fn flow_control(&self, status: u8) -> Frame {
+        let mut bytes = [PADDING; 8];
+        bytes[0] = (PCI_FLOW_CONTROL << 4) | status;
+        bytes[1] = self.config.block_size;
+        bytes[2] = self.config.st_min;
+        self.frame(&bytes)
+    }
+
+    fn frame(&self, bytes: &[u8; 8]) -> Frame {
+        if self.config.extended {
+            Frame::new_extended(self.config.tx_id, bytes).unwrap()
+        } else {
+            Frame::new_standard(self.config.tx_id as u16, bytes).unwrap()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIAGNOSTIC: IsoTpConfig = IsoTpConfig {
+        rx_id: 0x7E0,
+        tx_id: 0x7E8,
+        extended: false,
+        block_size: 0,
+        st_min: 0,
+    };
+    const BLOCKS: IsoTpConfig = IsoTpConfig {
+        rx_id: 0x18DAF1F4,
+        tx_id: 0x18DAF4F1,
+        extended: true,
+        block_size: 2,
+        st_min: 5,
+    };
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    fn message(len: usize) -> std::vec::Vec<u8> {
+        (0..len).map(|byte| byte as u8).collect()
+    }
+
+    // consecutive frame with the given sequence number and up to 7 bytes of the message
+    fn consecutive(seq: u8, message: &[u8], from: usize) -> [u8; 8] {
+        let mut bytes = [PADDING; 8];
+        bytes[0] = 0x20 | seq;
+        let to = (from + 7).min(message.len());
+        bytes[1..=to - from].copy_from_slice(&message[from..to]);
+        bytes
+    }
+
+    fn first(message: &[u8]) -> [u8; 8] {
+        let mut bytes = [
+            0x10 | (message.len() >> 8) as u8,
+            message.len() as u8,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ];
+        bytes[2..].copy_from_slice(&message[..6]);
+        bytes
+    }
+
+    fn next_frame(isotp: &mut IsoTp, now: Instant) -> Frame {
+        isotp.poll(now).unwrap().expect("a frame to send")
+    }
+
+    #[test]
+    fn single_frame_is_received() {
+        let mut isotp = IsoTp::init(&DIAGNOSTIC);
+        let request = [0x02, 0x10, 0x03, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC];
+        assert_eq!(isotp.receive(&request, at(0)), Ok(Some(&[0x10, 0x03][..])));
+        // no flow control for a single frame
+        assert_eq!(isotp.poll(at(0)), Ok(None));
+    }
+
+    #[test]
+    fn invalid_single_frames_are_rejected() {
+        let mut isotp = IsoTp::init(&DIAGNOSTIC);
+        assert_eq!(isotp.receive(&[], at(0)), Err(IsoTpError::InvalidFrame));
+        assert_eq!(
+            isotp.receive(&[0x00, 0x10], at(0)),
+            Err(IsoTpError::InvalidFrame)
+        );
+        assert_eq!(
+            isotp.receive(&[0x08; 8], at(0)),
+            Err(IsoTpError::InvalidFrame)
+        );
+        // the length does not fit in the frame
+        assert_eq!(
+            isotp.receive(&[0x03, 0x22, 0xF1], at(0)),
+            Err(IsoTpError::InvalidFrame)
+        );
+    }
+
+    #[test]
+    fn single_frame_is_sent_with_padding() {
+        let mut isotp = IsoTp::init(&DIAGNOSTIC);
+        isotp.send(&[0x50, 0x03, 0x00, 0x32, 0x01, 0xF4]).unwrap();
+        assert!(isotp.is_busy());
+        let frame = next_frame(&mut isotp, at(0));
+        assert_eq!(frame.id(), 0x7E8);
+        assert!(!frame.is_extended());
+        assert_eq!(
+            frame.data(),
+            &[0x06, 0x50, 0x03, 0x00, 0x32, 0x01, 0xF4, 0xCC]
+        );
+        assert!(!isotp.is_busy());
+        assert_eq!(isotp.poll(at(0)), Ok(None));
+    }
+
+    #[test]
+    fn multi_frame_message_is_reassembled() {
+        let mut isotp = IsoTp::init(&DIAGNOSTIC);
+        let message = message(20);
+        assert_eq!(isotp.receive(&first(&message), at(0)), Ok(None));
+        // continue to send, no block size and no separation time
+        let flow_control = next_frame(&mut isotp, at(0));
+        assert_eq!(flow_control.id(), 0x7E8);
+        assert_eq!(
+            flow_control.data(),
+            &[0x30, 0x00, 0x00, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC]
+        );
+        assert_eq!(isotp.poll(at(0)), Ok(None));
+        assert_eq!(isotp.receive(&consecutive(1, &message, 6), at(1)), Ok(None));
+        assert_eq!(
+            isotp.receive(&consecutive(2, &message, 13), at(2)),
+            Ok(Some(&message[..]))
+        );
+    }
+
+    #[test]
+    fn sequence_number_wraps_after_15() {
+        let mut isotp = IsoTp::init(&DIAGNOSTIC);
+        // first frame, 16 consecutive frames and one more byte
+        let message = message(6 + 16 * 7 + 1);
+        assert_eq!(isotp.receive(&first(&message), at(0)), Ok(None));
+        next_frame(&mut isotp, at(0));
+        for (index, from) in (6..message.len() - 1).step_by(7).enumerate() {
+            let seq = (index as u8 + 1) & 0x0F;
+            assert_eq!(
+                isotp.receive(&consecutive(seq, &message, from), at(1)),
+                Ok(None)
+            );
+        }
+        assert_eq!(
+            isotp.receive(&consecutive(1, &message, message.len() - 1), at(1)),
+            Ok(Some(&message[..]))
+        );
+    }
+
+    #[test]
+    fn wrong_sequence_aborts_the_reception() {
+        let mut isotp = IsoTp::init(&DIAGNOSTIC);
+        let message = message(20);
+        isotp.receive(&first(&message), at(0)).unwrap();
+        assert_eq!(
+            isotp.receive(&consecutive(2, &message, 6), at(1)),
+            Err(IsoTpError::WrongSequence)
+        );
+        // later consecutive frames are ignored
+        assert_eq!(isotp.receive(&consecutive(1, &message, 6), at(2)), Ok(None));
+    }
+
+    #[test]
+    fn flow_control_is_sent_after_each_block() {
+        let mut isotp = IsoTp::init(&BLOCKS);
+        let message = message(30);
+        assert_eq!(isotp.receive(&first(&message), at(0)), Ok(None));
+        let flow_control = next_frame(&mut isotp, at(0));
+        assert_eq!(flow_control.id(), 0x18DAF4F1);
+        assert!(flow_control.is_extended());
+        assert_eq!(flow_control.data()[..3], [0x30, 2, 5]);
+
+        assert_eq!(isotp.receive(&consecutive(1, &message, 6), at(5)), Ok(None));
+        assert_eq!(isotp.poll(at(5)), Ok(None));
+        // end of the block of 2 frames
+        assert_eq!(
+            isotp.receive(&consecutive(2, &message, 13), at(10)),
+            Ok(None)
+        );
+        assert_eq!(next_frame(&mut isotp, at(10)).data()[..3], [0x30, 2, 5]);
+        assert_eq!(
+            isotp.receive(&consecutive(3, &message, 20), at(15)),
+            Ok(None)
+        );
+        assert_eq!(isotp.poll(at(15)), Ok(None));
+        assert_eq!(
+            isotp.receive(&consecutive(4, &message, 27), at(20)),
+            Ok(Some(&message[..]))
+        );
+    }
+
+    #[test]
+    fn block_size_and_st_min_of_the_receiver_are_respected() {
+        let mut isotp = IsoTp::init(&DIAGNOSTIC);
+        let message = message(27);
+        isotp.send(&message).unwrap();
+        assert_eq!(next_frame(&mut isotp, at(0)).data(), &first(&message));
+        // nothing before the flow control
+        assert_eq!(isotp.poll(at(0)), Ok(None));
+
+        // one frame per block, 10 ms apart
+        assert_eq!(isotp.receive(&[0x30, 1, 10], at(1)), Ok(None));
+        assert_eq!(
+            next_frame(&mut isotp, at(1)).data(),
+            &consecutive(1, &message, 6)
+        );
+        assert_eq!(isotp.poll(at(50)), Ok(None));
+
+        // no more flow control until the end, 10 ms apart
+        assert_eq!(isotp.receive(&[0x30, 0, 10], at(50)), Ok(None));
+        assert_eq!(
+            next_frame(&mut isotp, at(50)).data(),
+            &consecutive(2, &message, 13)
+        );
+        assert_eq!(isotp.poll(at(55)), Ok(None));
+        assert_eq!(
+            next_frame(&mut isotp, at(60)).data(),
+            &consecutive(3, &message, 20)
+        );
+        assert!(!isotp.is_busy());
+        assert_eq!(isotp.poll(at(100)), Ok(None));
+    }
+
+    #[test]
+    fn st_min_is_decoded() {
+        assert_eq!(decode_st_min(0x00), Duration::from_millis(0));
+        assert_eq!(decode_st_min(0x7F), Duration::from_millis(127));
+        assert_eq!(decode_st_min(0xF1), Duration::from_micros(100));
+        assert_eq!(decode_st_min(0xF9), Duration::from_micros(900));
+        // reserved values
+        assert_eq!(decode_st_min(0x80), Duration::from_millis(127));
+        assert_eq!(decode_st_min(0xF0), Duration::from_millis(127));
+        assert_eq!(decode_st_min(0xFA), Duration::from_millis(127));
+    }
+
+    #[test]
+    fn wait_frames_are_limited() {
+        let mut isotp = IsoTp::init(&DIAGNOSTIC);
+        isotp.send(&message(20)).unwrap();
+        next_frame(&mut isotp, at(0));
+        for wait in 0..MAX_WAIT_FRAMES as u64 {
+            assert_eq!(isotp.receive(&[0x31, 0, 0], at(900 * (wait + 1))), Ok(None));
+            // each wait frame restarts N_Bs
+            assert_eq!(isotp.poll(at(900 * (wait + 1) + 999)), Ok(None));
+            assert!(isotp.is_busy());
+        }
+        assert_eq!(
+            isotp.receive(&[0x31, 0, 0], at(10_000)),
+            Err(IsoTpError::WaitLimit)
+        );
+        assert!(!isotp.is_busy());
+    }
+
+    #[test]
+    fn too_long_messages_are_refused() {
+        let mut isotp = IsoTp::init(&DIAGNOSTIC);
+        assert_eq!(
+            isotp.send(&message(ISOTP_MAX_LEN + 1)),
+            Err(IsoTpError::TooLong)
+        );
+        assert!(!isotp.is_busy());
+        assert_eq!(isotp.send(&message(ISOTP_MAX_LEN)), Ok(()));
+        assert_eq!(isotp.send(&[0x01]), Err(IsoTpError::Busy));
+    }
+
+    #[test]
+    fn overflow_is_reported_to_the_sender() {
+        let mut isotp = IsoTp::init(&DIAGNOSTIC);
+        // first frame with the escape length of 5000 bytes
+        let first = [0x10, 0x00, 0x00, 0x00, 0x13, 0x88, 0x00, 0x01];
+        assert_eq!(isotp.receive(&first, at(0)), Err(IsoTpError::Overflow));
+        assert_eq!(
+            next_frame(&mut isotp, at(0)).data()[..3],
+            [0x32, 0x00, 0x00]
+        );
+        assert_eq!(isotp.poll(at(0)), Ok(None));
+    }
+
+    #[test]
+    fn overflow_of_the_receiver_stops_the_transmission() {
+        let mut isotp = IsoTp::init(&DIAGNOSTIC);
+        isotp.send(&message(20)).unwrap();
+        next_frame(&mut isotp, at(0));
+        assert_eq!(
+            isotp.receive(&[0x32, 0, 0], at(1)),
+            Err(IsoTpError::Overflow)
+        );
+        assert!(!isotp.is_busy());
+        assert_eq!(isotp.poll(at(1)), Ok(None));
+    }
+
+    #[test]
+    fn missing_flow_control_times_out() {
+        let mut isotp = IsoTp::init(&DIAGNOSTIC);
+        isotp.send(&message(20)).unwrap();
+        next_frame(&mut isotp, at(0));
+        // N_Bs
+        assert_eq!(isotp.poll(at(1000)), Ok(None));
+        assert_eq!(isotp.poll(at(1001)), Err(IsoTpError::Timeout));
+        assert!(!isotp.is_busy());
+        assert_eq!(isotp.poll(at(1002)), Ok(None));
+        // the flow control comes too late
+        assert_eq!(isotp.receive(&[0x30, 0, 0], at(1003)), Ok(None));
+        assert_eq!(isotp.poll(at(1003)), Ok(None));
+    }
+
+    #[test]
+    fn missing_consecutive_frame_times_out() {
+        let mut isotp = IsoTp::init(&DIAGNOSTIC);
+        let message = message(27);
+        isotp.receive(&first(&message), at(0)).unwrap();
+        next_frame(&mut isotp, at(0));
+        // N_Cr starts again with each consecutive frame
+        assert_eq!(
+            isotp.receive(&consecutive(1, &message, 6), at(500)),
+            Ok(None)
+        );
+        assert_eq!(isotp.poll(at(1500)), Ok(None));
+        assert_eq!(isotp.poll(at(1501)), Err(IsoTpError::Timeout));
+        // the rest of the message is ignored
+        assert_eq!(
+            isotp.receive(&consecutive(2, &message, 13), at(1502)),
+            Ok(None)
+        );
+        assert_eq!(
+            isotp.receive(&consecutive(3, &message, 20), at(1503)),
+            Ok(None)
+        );
+    }
+}
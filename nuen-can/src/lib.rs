@@ -0,0 +1,13 @@
+#![cfg_attr(not(test), no_std)]
+
+pub mod bus;
+pub mod display;
+pub mod e2e;
+pub mod frame;
+pub mod isotp;
+pub mod pipeline;
+pub mod tx_queue;
+pub mod uds;
+pub mod virtual_bus;
+
+pub use frame::Frame;
@@ -0,0 +1,546 @@
+use embassy_futures::select::{select, select4, Either, Either4};
+use embassy_sync::{blocking_mutex::raw::RawMutex, channel::Channel};
+use embassy_time::{Instant, Timer};
+use log::{info, warn};
+
+use crate::{
+    bus::{BusError, CanReceiver, CanTransmitter},
+    display::{ScreenRequest, SegLcd},
+    tx_queue::{TxClassRule, TxQueue, TxStats, TX_CLASSES},
+    Frame,
+};
+
+// Transmit and receive loops of the CanTx and CanRx tasks, independent of the CAN
+// driver. The firmware adds its own handling of the frames with the hooks.
+
+pub struct CanTxConfig {
+    // check of the deadlines of the frames in the transmit slots, in ms
+    pub poll: u64,
+    pub rules: &'static [TxClassRule],
+}
+
+pub trait TxHooks {
+    // called for every frame before it is queued, e.g. to write the E2E protection
+    fn prepare(&mut self, frame: Frame) -> Frame {
+        frame
+    }
+
+    // frames built by the CanTx task itself, e.g. to forward them to another bus
+    fn built(&mut self, _frame: &Frame) {}
+
+    // every frame placed in a transmit slot
+    fn written(&mut self, _frame: &Frame) {}
+
+    // statistics of the queue after every cycle
+    fn stats(&mut self, _stats: &[TxStats; TX_CLASSES]) {}
+}
+
+pub async fn can_tx<T, H, M, const S: usize, const F: usize>(
+    tx: T,
+    channel: &Channel<M, ScreenRequest, S>,
+    frame_channel: &Channel<M, Frame, F>,
+    config: &CanTxConfig,
+    hooks: H,
+) -> !
+where
+    T: CanTransmitter,
+    H: TxHooks,
+    M: RawMutex,
+{
+    let mut display = SegLcd::init();
+    let mut sender = CanSender {
+        tx,
+        queue: TxQueue::init(config.rules),
+        hooks,
+    };
+
+    sender.send(display.get_status_1().into());
+    sender.send(display.get_status_2().into());
+    sender.send(display.get_status_3().into());
+    sender.pump();
+    info!("Started CANTX Task !!!");
+    loop {
+        // frames are only queued here, a slow frame on the bus does not block the requests
+        let pending = !sender.queue.is_empty();
+        let event = select4(
+            channel.receive(),
+            frame_channel.receive(),
+            async {
+                if pending {
+                    sender.tx.wait_slot().await
+                } else {
+                    core::future::pending().await
+                }
+            },
+            // check the deadlines of the frames in the mailboxes
+            Timer::after_millis(config.poll),
+        )
+        .await;
+        let start = Instant::now();
+        match event {
+            Either4::First(request) => sender.screen_request(&mut display, request),
+            Either4::Second(frame) => sender.write(frame),
+            Either4::Third(_) | Either4::Fourth(_) => {}
+        }
+        sender.pump();
+
+        let ms = Instant::now().duration_since(start).as_millis();
+        if ms > config.poll {
+            warn!("CanTx task done after {ms}ms > {}ms", config.poll);
+        }
+    }
+}
+
+struct CanSender<T: CanTransmitter, H: TxHooks> {
+    tx: T,
+    queue: TxQueue,
+    hooks: H,
+}
+
+impl<T: CanTransmitter, H: TxHooks> CanSender<T, H> {
+    fn screen_request(&mut self, display: &mut SegLcd, request: ScreenRequest) {
+        match request {
+            ScreenRequest::Power(en) => {
+                info!("send LeftIndicator to screen");
+                if en {
+                    self.send(display.lcd_on().into());
+                } else {
+                    self.send(display.lcd_off().into());
+                }
+            }
+            ScreenRequest::Ready => {
+                self.send(display.rdy_on().into());
+            }
+            ScreenRequest::LeftIndicator => {
+                info!("send LeftIndicator to screen");
+                self.send(display.left_ind_on().into());
+            }
+            ScreenRequest::RightIndicator => {
+                info!("send LeftIndicator to screen");
+                self.send(display.right_ind_on().into());
+            }
+            ScreenRequest::Speed(speed) => {
+                info!("send Speed {} to screen", speed);
+                self.send(display.speed(speed).into());
+            }
+            ScreenRequest::Soc(soc) => {
+                info!("send SOC {} to screen", soc);
+                self.send(display.soc(soc).into());
+            }
+            ScreenRequest::Range(km) => {
+                info!("send Range {} to screen", km);
+                self.send(display.range(km).into());
+            }
+            ScreenRequest::Abs(abs) => {
+                info!("send ABS {} to screen", abs);
+                if abs {
+                    self.send(display.abs_on().into());
+                } else {
+                    self.send(display.abs_off().into());
+                }
+            }
+            ScreenRequest::HeadLight(on) => {
+                info!("send HeadLight {} to screen", on);
+                if on {
+                    self.send(display.headlight_on().into());
+                } else {
+                    self.send(display.headlight_off().into());
+                }
+            }
+        }
+    }
+
+    // frames built by this task, the hooks may forward them to another bus
+    fn send(&mut self, frame: Frame) {
+        let frame = self.hooks.prepare(frame);
+        self.queue.push(frame, Instant::now());
+        self.hooks.built(&frame);
+    }
+
+    // frames of other tasks, not passed on so frames from another bus are not sent back
+    fn write(&mut self, frame: Frame) {
+        let frame = self.hooks.prepare(frame);
+        self.queue.push(frame, Instant::now());
+    }
+
+    // move the queued frames to the free mailboxes
+    fn pump(&mut self) {
+        let hooks = &mut self.hooks;
+        self.queue
+            .pump(&mut self.tx, Instant::now(), |frame| hooks.written(frame));
+        self.hooks.stats(self.queue.stats());
+    }
+}
+
+pub struct CanRxConfig {
+    // longest time to handle a frame, also the wait after a bus error, in ms
+    pub cycle: u64,
+}
+
+#[allow(async_fn_in_trait)]
+pub trait RxHooks {
+    // every frame received from the bus, not the injected ones
+    fn received(&mut self, _frame: &Frame) {}
+
+    fn error(&mut self, _error: BusError) {}
+
+    // every frame received or injected, e.g. to route it to the handler tasks
+    async fn dispatch(&mut self, frame: Frame);
+}
+
+pub async fn can_rx<R, H, M, const N: usize>(
+    mut rx: R,
+    inject_channel: &Channel<M, Frame, N>,
+    config: &CanRxConfig,
+    mut hooks: H,
+) -> !
+where
+    R: CanReceiver,
+    H: RxHooks,
+    M: RawMutex,
+{
+    info!("Started CANRX Task !!!");
+    loop {
+        let received = match select(rx.receive(), inject_channel.receive()).await {
+            Either::First(Ok(frame)) => {
+                info!("Receive CAN Frame {:?}", frame);
+                hooks.received(&frame);
+                Some(frame)
+            }
+            Either::First(Err(e)) => {
+                info!("Failed to receive CAN Frame: {:?}", e);
+                hooks.error(e);
+                // the driver reports the error again at once while the bus is
+                // in error, give the other tasks of this executor a chance
+                Timer::after_millis(config.cycle).await;
+                None
+            }
+            // frames from the command line take the same path as received ones
+            Either::Second(frame) => {
+                info!("Inject CAN Frame {:?}", frame);
+                Some(frame)
+            }
+        };
+        let start = Instant::now();
+        if let Some(frame) = received {
+            hooks.dispatch(frame).await;
+        }
+
+        let ms = Instant::now().duration_since(start).as_millis();
+        // no cycle delay here, the next frame may already be waiting in the FIFO
+        if ms > config.cycle {
+            warn!("CanRx task done after {ms}ms > {}ms", config.cycle);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{cell::RefCell, future::Future};
+
+    use embassy_futures::{block_on, yield_now};
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use embassy_time::{with_timeout, Duration};
+
+    use super::*;
+    use crate::{
+        tx_queue::TxClass,
+        virtual_bus::{VirtualBus, VirtualEndpoint},
+    };
+
+    const STATUS_1: u32 = 0x10F810A3;
+    const STATUS_2: u32 = 0x10F8109A;
+    const STATUS_3: u32 = 0x1800F907;
+    const COMMAND: u32 = 0x0C00_00EF;
+
+    static RULES: [TxClassRule; 3] = [
+        TxClassRule {
+            id: COMMAND,
+            mask: 0x1FFF_FFFF,
+            class: TxClass::Safety,
+        },
+        TxClassRule {
+            id: 0x00F8_1000,
+            mask: 0x03FF_FF00,
+            class: TxClass::Display,
+        },
+        TxClassRule {
+            id: 0x0000_F900,
+            mask: 0x03FF_FF00,
+            class: TxClass::Display,
+        },
+    ];
+    static TX_CONFIG: CanTxConfig = CanTxConfig {
+        poll: 5,
+        rules: &RULES,
+    };
+    static RX_CONFIG: CanRxConfig = CanRxConfig { cycle: 5 };
+
+    type Screen = Channel<NoopRawMutex, ScreenRequest, 4>;
+    type Frames = Channel<NoopRawMutex, Frame, 4>;
+
+    #[derive(Default)]
+    struct Log {
+        built: Vec<Frame>,
+        written: Vec<Frame>,
+        stats: [TxStats; TX_CLASSES],
+        received: Vec<Frame>,
+        errors: Vec<BusError>,
+        dispatched: Vec<Frame>,
+    }
+
+    struct Hooks<'a>(&'a RefCell<Log>);
+
+    impl TxHooks for Hooks<'_> {
+        // marks the command like the E2E protection
+        fn prepare(&mut self, frame: Frame) -> Frame {
+            if frame.id() != COMMAND {
+                return frame;
+            }
+            let mut data = [0x00; 8];
+            data[..frame.data().len()].copy_from_slice(frame.data());
+            data[7] = 0xAA;
+            Frame::new_extended(frame.id(), &data).unwrap()
+        }
+
+        fn built(&mut self, frame: &Frame) {
+            self.0.borrow_mut().built.push(*frame);
+        }
+
+        fn written(&mut self, frame: &Frame) {
+            self.0.borrow_mut().written.push(*frame);
+        }
+
+        fn stats(&mut self, stats: &[TxStats; TX_CLASSES]) {
+            self.0.borrow_mut().stats = *stats;
+        }
+    }
+
+    impl RxHooks for Hooks<'_> {
+        fn received(&mut self, frame: &Frame) {
+            self.0.borrow_mut().received.push(*frame);
+        }
+
+        fn error(&mut self, error: BusError) {
+            self.0.borrow_mut().errors.push(error);
+        }
+
+        async fn dispatch(&mut self, frame: Frame) {
+            self.0.borrow_mut().dispatched.push(frame);
+        }
+    }
+
+    fn command() -> Frame {
+        Frame::new_extended(COMMAND, &[0x01, 0x02]).unwrap()
+    }
+
+    // run the CAN tasks next to the test until the test is done
+    fn run<T: Future>(tasks: impl Future, test: T) -> T::Output {
+        block_on(async {
+            match select(tasks, with_timeout(Duration::from_secs(1), test)).await {
+                Either::First(_) => unreachable!(),
+                Either::Second(result) => result.expect("test timed out"),
+            }
+        })
+    }
+
+    // move the frames on the bus until the endpoint receives one
+    async fn next_frame(bus: &VirtualBus, endpoint: &mut VirtualEndpoint<'_>) -> Frame {
+        loop {
+            bus.transfer();
+            if let Some(received) = endpoint.try_receive() {
+                return received.unwrap();
+            }
+            yield_now().await;
+        }
+    }
+
+    async fn wait_until(condition: impl Fn() -> bool) {
+        while !condition() {
+            yield_now().await;
+        }
+    }
+
+    async fn transfer_until(bus: &VirtualBus, condition: impl Fn() -> bool) {
+        while !condition() {
+            bus.transfer();
+            yield_now().await;
+        }
+    }
+
+    #[test]
+    fn display_frames_are_sent_at_start() {
+        let bus = VirtualBus::new();
+        let log = RefCell::new(Log::default());
+        let (screen, frames) = (Screen::new(), Frames::new());
+        let mut display = bus.endpoint();
+        let tx = can_tx(bus.endpoint(), &screen, &frames, &TX_CONFIG, Hooks(&log));
+
+        let received = run(tx, async {
+            let mut received = Vec::new();
+            for _ in 0..3 {
+                received.push(next_frame(&bus, &mut display).await);
+            }
+            received
+        });
+        // in the order of the arbitration
+        let ids: Vec<u32> = received.iter().map(Frame::id).collect();
+        assert_eq!(ids, [STATUS_2, STATUS_1, STATUS_3]);
+        assert!(received.iter().all(|frame| frame.data() == [0x00; 8]));
+        let log = log.borrow();
+        assert_eq!(log.built.len(), 3);
+        assert_eq!(log.written.len(), 3);
+        assert_eq!(log.stats[TxClass::Display.index()].queued, 3);
+    }
+
+    #[test]
+    fn screen_requests_update_the_display_frames() {
+        let bus = VirtualBus::new();
+        let log = RefCell::new(Log::default());
+        let (screen, frames) = (Screen::new(), Frames::new());
+        let mut display = bus.endpoint();
+        let tx = can_tx(bus.endpoint(), &screen, &frames, &TX_CONFIG, Hooks(&log));
+
+        run(tx, async {
+            for _ in 0..3 {
+                next_frame(&bus, &mut display).await;
+            }
+            screen.send(ScreenRequest::Speed(42)).await;
+            let frame = next_frame(&bus, &mut display).await;
+            assert_eq!(frame.id(), STATUS_2);
+            assert_eq!(frame.data()[0], 42);
+
+            // the other values of the frame are kept
+            screen.send(ScreenRequest::Soc(45)).await;
+            let frame = next_frame(&bus, &mut display).await;
+            assert_eq!(frame.data()[..3], [42, 45, 0b111]);
+
+            screen.send(ScreenRequest::Abs(true)).await;
+            let frame = next_frame(&bus, &mut display).await;
+            assert_eq!(frame.id(), STATUS_1);
+            assert_eq!(frame.data()[0], 0x01 << 3);
+
+            screen.send(ScreenRequest::HeadLight(true)).await;
+            let frame = next_frame(&bus, &mut display).await;
+            assert_eq!(frame.id(), STATUS_3);
+            assert_eq!(frame.data()[0], 0x01 << 1);
+        });
+        assert_eq!(log.borrow().built.len(), 7);
+    }
+
+    #[test]
+    fn frames_of_other_tasks_are_prepared_and_not_passed_on() {
+        let bus = VirtualBus::new();
+        let log = RefCell::new(Log::default());
+        let (screen, frames) = (Screen::new(), Frames::new());
+        let mut node = bus.endpoint();
+        let tx = can_tx(bus.endpoint(), &screen, &frames, &TX_CONFIG, Hooks(&log));
+
+        let frame = run(tx, async {
+            for _ in 0..3 {
+                next_frame(&bus, &mut node).await;
+            }
+            frames.send(command()).await;
+            next_frame(&bus, &mut node).await
+        });
+        assert_eq!(frame.id(), COMMAND);
+        assert_eq!(frame.data(), [0x01, 0x02, 0, 0, 0, 0, 0, 0xAA]);
+        let log = log.borrow();
+        assert!(log.written.contains(&frame));
+        assert!(!log.built.contains(&frame));
+    }
+
+    #[test]
+    fn command_preempts_a_pending_display_frame() {
+        let bus = VirtualBus::new();
+        let log = RefCell::new(Log::default());
+        let (screen, frames) = (Screen::new(), Frames::new());
+        let tx = can_tx(bus.endpoint(), &screen, &frames, &TX_CONFIG, Hooks(&log));
+
+        let ids = run(tx, async {
+            // nothing acknowledges the display frames, they stay in the slots
+            wait_until(|| log.borrow().written.len() == 3).await;
+            frames.send(command()).await;
+            wait_until(|| log.borrow().written.len() == 4).await;
+
+            let mut node = bus.endpoint();
+            let mut ids = Vec::new();
+            for _ in 0..4 {
+                ids.push(next_frame(&bus, &mut node).await.id());
+            }
+            ids
+        });
+        // the display frame with the lowest priority was put back in the queue
+        assert_eq!(ids, [COMMAND, STATUS_2, STATUS_1, STATUS_3]);
+        assert_eq!(bus.pending(), 0);
+        let log = log.borrow();
+        assert_eq!(log.stats[TxClass::Display.index()].preempted, 1);
+        assert_eq!(log.stats[TxClass::Safety.index()].queued, 1);
+    }
+
+    #[test]
+    fn received_and_injected_frames_are_dispatched() {
+        let bus = VirtualBus::new();
+        let log = RefCell::new(Log::default());
+        let inject = Frames::new();
+        let mut node = bus.endpoint();
+        let rx = can_rx(bus.endpoint(), &inject, &RX_CONFIG, Hooks(&log));
+
+        let received = Frame::new_standard(0x181, &[0x10]).unwrap();
+        let injected = command();
+        run(rx, async {
+            node.try_transmit(&received).unwrap();
+            bus.transfer();
+            wait_until(|| log.borrow().dispatched.len() == 1).await;
+            inject.send(injected).await;
+            wait_until(|| log.borrow().dispatched.len() == 2).await;
+        });
+        let log = log.borrow();
+        // only the frames of the bus are passed to `received`
+        assert_eq!(log.received, [received]);
+        assert_eq!(log.dispatched, [received, injected]);
+    }
+
+    #[test]
+    fn bus_errors_are_reported_and_receiving_goes_on() {
+        let bus = VirtualBus::new();
+        let log = RefCell::new(Log::default());
+        let inject = Frames::new();
+        let mut node = bus.endpoint();
+        let rx = can_rx(bus.endpoint(), &inject, &RX_CONFIG, Hooks(&log));
+
+        run(rx, async {
+            bus.inject_error(BusError::Acknowledge);
+            node.try_transmit(&command()).unwrap();
+            bus.transfer();
+            wait_until(|| log.borrow().dispatched.len() == 1).await;
+        });
+        let log = log.borrow();
+        assert_eq!(log.errors, [BusError::Acknowledge]);
+        assert_eq!(log.dispatched, [command()]);
+    }
+
+    #[test]
+    fn display_frames_reach_the_receive_path_of_another_node() {
+        let bus = VirtualBus::new();
+        let tx_log = RefCell::new(Log::default());
+        let rx_log = RefCell::new(Log::default());
+        let (screen, frames, inject) = (Screen::new(), Frames::new(), Frames::new());
+        let tx = can_tx(bus.endpoint(), &screen, &frames, &TX_CONFIG, Hooks(&tx_log));
+        let rx = can_rx(bus.endpoint(), &inject, &RX_CONFIG, Hooks(&rx_log));
+
+        run(select(tx, rx), async {
+            transfer_until(&bus, || rx_log.borrow().dispatched.len() == 3).await;
+            screen.send(ScreenRequest::Power(true)).await;
+            screen.send(ScreenRequest::Range(120)).await;
+            transfer_until(&bus, || rx_log.borrow().dispatched.len() == 5).await;
+        });
+        let rx_log = rx_log.borrow();
+        assert_eq!(rx_log.received, rx_log.dispatched);
+        let ids: Vec<u32> = rx_log.dispatched.iter().map(Frame::id).collect();
+        assert_eq!(ids, [STATUS_2, STATUS_1, STATUS_3, STATUS_2, STATUS_1]);
+        // the range in status_2, LCD on in status_1
+        assert_eq!(rx_log.dispatched[3].data()[3..5], 120u16.to_le_bytes());
+        assert_eq!(rx_log.dispatched[4].data()[1], 0x01);
+    }
+}
@@ -0,0 +1,270 @@
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+use crate::{
+    bus::{CanTransmitter, TX_SLOTS},
+    Frame,
+};
+
+const TX_QUEUE_SIZE: usize = 32;
+
+pub const TX_CLASSES: usize = TxClass::ALL.len();
+
+// classes in order of priority, a frame of a higher class is always sent first
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TxClass {
+    Safety,
+    Control,
+    Diagnostic,
+    Display,
+}
+
+pub struct TxPolicy {
+    // a frame which is not on the bus after this time is dropped
+    pub lifetime: Duration,
+    // abort a frame which is still pending in a mailbox after its lifetime,
+    // otherwise the hardware keeps retransmitting it
+    pub abort_stale: bool,
+    // how often a frame may be put back in the queue after it was removed
+    // from a mailbox by a frame with higher priority
+    pub max_retries: u8,
+}
+
+impl TxClass {
+    pub const ALL: [TxClass; 4] = [
+        TxClass::Safety,
+        TxClass::Control,
+        TxClass::Diagnostic,
+        TxClass::Display,
+    ];
+
+    pub fn policy(&self) -> TxPolicy {
+        match self {
+            // an old command must never reach the node
+            TxClass::Safety => TxPolicy {
+                lifetime: Duration::from_millis(20),
+                abort_stale: true,
+                max_retries: 1,
+            },
+            TxClass::Control => TxPolicy {
+                lifetime: Duration::from_millis(100),
+                abort_stale: true,
+                max_retries: 3,
+            },
+            // the transport protocols have their own timeouts, a missing
+            // consecutive frame would break the whole message
+            TxClass::Diagnostic => TxPolicy {
+                lifetime: Duration::from_millis(1000),
+                abort_stale: false,
+                max_retries: 3,
+            },
+            TxClass::Display => TxPolicy {
+                lifetime: Duration::from_millis(500),
+                abort_stale: true,
+                max_retries: 3,
+            },
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+pub struct TxClassRule {
+    pub id: u32,
+    pub mask: u32,
+    pub class: TxClass,
+}
+
+// the first matching rule decides, other frames are of the control class
+pub fn class_of(rules: &[TxClassRule], id: u32) -> TxClass {
+    rules
+        .iter()
+        .find(|rule| id & rule.mask == rule.id & rule.mask)
+        .map_or(TxClass::Control, |rule| rule.class)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxStats {
+    pub queued: u32,
+    pub sent: u32,
+    pub overflow: u32,
+    pub stale: u32,
+    pub aborted: u32,
+    pub preempted: u32,
+    pub max_depth: u8,
+}
+
+impl TxStats {
+    pub const fn new() -> Self {
+        TxStats {
+            queued: 0,
+            sent: 0,
+            overflow: 0,
+            stale: 0,
+            aborted: 0,
+            preempted: 0,
+            max_depth: 0,
+        }
+    }
+}
+
+impl Default for TxStats {
+    fn default() -> Self {
+        TxStats::new()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct QueuedFrame {
+    frame: Frame,
+    class: TxClass,
+    // arbitration value on the bus, the lower one wins
+    arbitration: u32,
+    // insertion order for frames with the same priority
+    sequence: u32,
+    deadline: Instant,
+    retries: u8,
+}
+
+impl QueuedFrame {
+    fn key(&self) -> (TxClass, u32, u32) {
+        (self.class, self.arbitration, self.sequence)
+    }
+}
+
+pub struct TxQueue {
+    rules: &'static [TxClassRule],
+    frames: Vec<QueuedFrame, TX_QUEUE_SIZE>,
+    // frames placed in the transmit slots and not confirmed yet
+    slots: [Option<QueuedFrame>; TX_SLOTS],
+    sequence: u32,
+    stats: [TxStats; TX_CLASSES],
+}
+
+impl TxQueue {
+    pub fn init(rules: &'static [TxClassRule]) -> Self {
+        TxQueue {
+            rules,
+            frames: Vec::new(),
+            slots: [None; TX_SLOTS],
+            sequence: 0,
+            stats: [TxStats::new(); TX_CLASSES],
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn stats(&self) -> &[TxStats; TX_CLASSES] {
+        &self.stats
+    }
+
+    fn count(&mut self, class: TxClass, update: impl FnOnce(&mut TxStats)) {
+        update(&mut self.stats[class.index()]);
+    }
+
+    pub fn push(&mut self, frame: Frame, now: Instant) {
+        let class = class_of(self.rules, frame.id());
+        let entry = QueuedFrame {
+            frame,
+            class,
+            arbitration: frame.arbitration(),
+            sequence: self.sequence,
+            deadline: now + class.policy().lifetime,
+            retries: 0,
+        };
+        self.sequence = self.sequence.wrapping_add(1);
+        self.count(class, |stats| stats.queued += 1);
+        self.insert(entry, now);
+    }
+
+    // send the queued frames as transmit slots become free and drop the stale ones,
+    // `written` is called for every frame placed in a slot
+    pub fn pump<T: CanTransmitter>(
+        &mut self,
+        tx: &mut T,
+        now: Instant,
+        mut written: impl FnMut(&Frame),
+    ) {
+        for slot in 0..TX_SLOTS {
+            let Some(entry) = self.slots[slot] else {
+                continue;
+            };
+            if tx.is_idle(slot) {
+                self.count(entry.class, |stats| stats.sent += 1);
+                self.slots[slot] = None;
+            } else if now > entry.deadline && entry.class.policy().abort_stale && tx.abort(slot) {
+                self.count(entry.class, |stats| stats.aborted += 1);
+                self.slots[slot] = None;
+            }
+        }
+        self.drop_stale(now);
+
+        while let Some(index) = self.best() {
+            let Some(transmitted) = tx.try_transmit(&self.frames[index].frame) else {
+                break;
+            };
+            let entry = self.frames.swap_remove(index);
+            written(&entry.frame);
+            if let Some(previous) = self.slots[transmitted.slot].replace(entry) {
+                if transmitted.dequeued.is_some() {
+                    self.count(previous.class, |stats| stats.preempted += 1);
+                    if previous.retries < previous.class.policy().max_retries {
+                        self.insert(
+                            QueuedFrame {
+                                retries: previous.retries + 1,
+                                ..previous
+                            },
+                            now,
+                        );
+                    }
+                } else {
+                    // the slot became free since the check above
+                    self.count(previous.class, |stats| stats.sent += 1);
+                }
+            }
+        }
+    }
+
+    fn insert(&mut self, entry: QueuedFrame, now: Instant) {
+        if self.frames.is_full() {
+            self.drop_stale(now);
+        }
+        if self.frames.is_full() {
+            // make room by dropping the frame with the lowest priority
+            let lowest = (0..self.frames.len())
+                .max_by_key(|&index| self.frames[index].key())
+                .unwrap();
+            if self.frames[lowest].key() < entry.key() {
+                self.count(entry.class, |stats| stats.overflow += 1);
+                return;
+            }
+            let dropped = self.frames.swap_remove(lowest);
+            self.count(dropped.class, |stats| stats.overflow += 1);
+        }
+        self.frames.push(entry).ok();
+        let depth = self.frames.len() as u8;
+        self.count(entry.class, |stats| {
+            stats.max_depth = stats.max_depth.max(depth)
+        });
+    }
+
+    fn drop_stale(&mut self, now: Instant) {
+        let stats = &mut self.stats;
+        self.frames.retain(|entry| {
+            if now > entry.deadline {
+                stats[entry.class.index()].stale += 1;
+                return false;
+            }
+            true
+        });
+    }
+
+    fn best(&self) -> Option<usize> {
+        (0..self.frames.len()).min_by_key(|&index| self.frames[index].key())
+    }
+}
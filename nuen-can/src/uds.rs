@@ -0,0 +1,472 @@
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+// ISO 14229-1 diagnostic server
+pub const UDS_MAX_LEN: usize = 64;
+
+const SID_SESSION_CONTROL: u8 = 0x10;
+const SID_ECU_RESET: u8 = 0x11;
+const SID_READ_DATA_BY_ID: u8 = 0x22;
+const SID_TESTER_PRESENT: u8 = 0x3E;
+pub const NEGATIVE_RESPONSE: u8 = 0x7F;
+const POSITIVE_OFFSET: u8 = 0x40;
+// suppressPosRspMsgIndicationBit of the sub-function byte
+const SUPPRESS_RESPONSE: u8 = 0x80;
+
+// server timing reported in the session control response
+const P2_SERVER_MAX: Duration = Duration::from_millis(50);
+const P2_EXT_SERVER_MAX: Duration = Duration::from_millis(5000);
+// non-default sessions fall back to default without tester activity
+const S3_SERVER: Duration = Duration::from_millis(5000);
+
+const DID_VIN: u16 = 0xF190;
+const DID_SW_VERSION: u16 = 0xF195;
+const DID_VEHICLE_STATE: u16 = 0x0100;
+const DID_SOC: u16 = 0x0101;
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Nrc {
+    ServiceNotSupported = 0x11,
+    SubFunctionNotSupported = 0x12,
+    IncorrectMessageLength = 0x13,
+    ResponseTooLong = 0x14,
+    ConditionsNotCorrect = 0x22,
+    RequestOutOfRange = 0x31,
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Session {
+    Default = 0x01,
+    Programming = 0x02,
+    Extended = 0x03,
+}
+
+// fixed identification data of the ECU
+pub struct UdsIdentity {
+    pub vin: [u8; 17],
+    pub sw_version: &'static str,
+}
+
+// state of the vehicle when the request is handled
+pub struct VehicleData {
+    // an ECU reset is refused while riding
+    pub riding: bool,
+    pub state: u8,
+    // None while the BMS is silent
+    pub soc: Option<u8>,
+}
+
+pub type UdsResponse = Vec<u8, UDS_MAX_LEN>;
+
+pub struct UdsServer {
+    identity: &'static UdsIdentity,
+    session: Session,
+    last_request: Instant,
+    reset_pending: bool,
+}
+
+impl UdsServer {
+    pub fn init(identity: &'static UdsIdentity, now: Instant) -> Self {
+        UdsServer {
+            identity,
+            session: Session::Default,
+            last_request: now,
+            reset_pending: false,
+        }
+    }
+
+    pub fn session(&self) -> Session {
+        self.session
+    }
+
+    // process a request, return the response to send or None if it is suppressed
+    pub fn handle(
+        &mut self,
+        request: &[u8],
+        now: Instant,
+        data: &VehicleData,
+    ) -> Option<UdsResponse> {
+        let sid = *request.first()?;
+        self.last_request = now;
+        let mut response = UdsResponse::new();
+        response.push(sid.wrapping_add(POSITIVE_OFFSET)).ok();
+        let result = match sid {
+            SID_SESSION_CONTROL => self.session_control(request, &mut response),
+            SID_ECU_RESET => self.ecu_reset(request, data, &mut response),
+            SID_READ_DATA_BY_ID => self.read_data_by_id(request, data, &mut response),
+            SID_TESTER_PRESENT => self.tester_present(request, &mut response),
+            _ => Err(Nrc::ServiceNotSupported),
+        };
+        match result {
+            Ok(true) => Some(response),
+            Ok(false) => None,
+            // a negative response is never suppressed
+            Err(nrc) => {
+                let mut response = UdsResponse::new();
+                response
+                    .extend_from_slice(&[NEGATIVE_RESPONSE, sid, nrc as u8])
+                    .ok();
+                Some(response)
+            }
+        }
+    }
+
+    // check the session timeout, must be called periodically, return true
+    // when the server fell back to the default session
+    pub fn poll(&mut self, now: Instant) -> bool {
+        if self.session != Session::Default && now.duration_since(self.last_request) > S3_SERVER {
+            self.session = Session::Default;
+            return true;
+        }
+        false
+    }
+
+    // return true once if an ECU reset was accepted
+    pub fn take_reset(&mut self) -> bool {
+        core::mem::replace(&mut self.reset_pending, false)
+    }
+
+    fn session_control(&mut self, request: &[u8], response: &mut UdsResponse) -> Result<bool, Nrc> {
+        if request.len() != 2 {
+            return Err(Nrc::IncorrectMessageLength);
+        }
+        let sub_function = request[1] & !SUPPRESS_RESPONSE;
+        self.session = match sub_function {
+            0x01 => Session::Default,
+            0x02 => Session::Programming,
+            0x03 => Session::Extended,
+            _ => return Err(Nrc::SubFunctionNotSupported),
+        };
+        response.push(sub_function).ok();
+        response
+            .extend_from_slice(&(P2_SERVER_MAX.as_millis() as u16).to_be_bytes())
+            .ok();
+        // P2* is sent with a resolution of 10ms
+        response
+            .extend_from_slice(&((P2_EXT_SERVER_MAX.as_millis() / 10) as u16).to_be_bytes())
+            .ok();
+        Ok(request[1] & SUPPRESS_RESPONSE == 0)
+    }
+
+    fn ecu_reset(
+        &mut self,
+        request: &[u8],
+        data: &VehicleData,
+        response: &mut UdsResponse,
+    ) -> Result<bool, Nrc> {
+        if request.len() != 2 {
+            return Err(Nrc::IncorrectMessageLength);
+        }
+        let sub_function = request[1] & !SUPPRESS_RESPONSE;
+        match sub_function {
+            // hard reset and soft reset are both a system reset on this ECU
+            0x01 | 0x03 => {}
+            _ => return Err(Nrc::SubFunctionNotSupported),
+        }
+        // never while riding, whatever the session
+        if data.riding {
+            return Err(Nrc::ConditionsNotCorrect);
+        }
+        self.reset_pending = true;
+        response.push(sub_function).ok();
+        Ok(request[1] & SUPPRESS_RESPONSE == 0)
+    }
+
+    fn tester_present(&mut self, request: &[u8], response: &mut UdsResponse) -> Result<bool, Nrc> {
+        if request.len() != 2 {
+            return Err(Nrc::IncorrectMessageLength);
+        }
+        if request[1] & !SUPPRESS_RESPONSE != 0x00 {
+            return Err(Nrc::SubFunctionNotSupported);
+        }
+        response.push(0x00).ok();
+        Ok(request[1] & SUPPRESS_RESPONSE == 0)
+    }
+
+    fn read_data_by_id(
+        &mut self,
+        request: &[u8],
+        data: &VehicleData,
+        response: &mut UdsResponse,
+    ) -> Result<bool, Nrc> {
+        if request.len() < 3 || request.len() % 2 != 1 {
+            return Err(Nrc::IncorrectMessageLength);
+        }
+        for did in request[1..].chunks(2) {
+            let did = u16::from_be_bytes([did[0], did[1]]);
+            response
+                .extend_from_slice(&did.to_be_bytes())
+                .map_err(|_| Nrc::ResponseTooLong)?;
+            let result = match did {
+                DID_VIN => response.extend_from_slice(&self.identity.vin),
+                DID_SW_VERSION => response.extend_from_slice(self.identity.sw_version.as_bytes()),
+                DID_VEHICLE_STATE => response.push(data.state).map_err(|_| ()),
+                DID_SOC => match data.soc {
+                    Some(soc) => response.push(soc).map_err(|_| ()),
+                    None => return Err(Nrc::ConditionsNotCorrect),
+                },
+                _ => return Err(Nrc::RequestOutOfRange),
+            };
+            result.map_err(|_| Nrc::ResponseTooLong)?;
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static IDENTITY: UdsIdentity = UdsIdentity {
+        vin: *b"NUEN0000000000001",
+        sw_version: "1.2.3",
+    };
+    const PARKED: VehicleData = VehicleData {
+        riding: false,
+        state: 1,
+        soc: Some(80),
+    };
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    fn server() -> UdsServer {
+        UdsServer::init(&IDENTITY, at(0))
+    }
+
+    fn request(server: &mut UdsServer, request: &[u8]) -> Option<std::vec::Vec<u8>> {
+        request_at(server, request, 0, &PARKED)
+    }
+
+    fn request_at(
+        server: &mut UdsServer,
+        request: &[u8],
+        ms: u64,
+        data: &VehicleData,
+    ) -> Option<std::vec::Vec<u8>> {
+        server
+            .handle(request, at(ms), data)
+            .map(|response| response.to_vec())
+    }
+
+    fn negative(sid: u8, nrc: Nrc) -> Option<std::vec::Vec<u8>> {
+        Some(std::vec![NEGATIVE_RESPONSE, sid, nrc as u8])
+    }
+
+    #[test]
+    fn empty_request_has_no_response() {
+        assert_eq!(request(&mut server(), &[]), None);
+    }
+
+    #[test]
+    fn unknown_service_is_not_supported() {
+        let mut server = server();
+        assert_eq!(
+            request(&mut server, &[0x27, 0x01]),
+            negative(0x27, Nrc::ServiceNotSupported)
+        );
+        // no overflow of the positive response SID
+        assert_eq!(
+            request(&mut server, &[0xFF]),
+            negative(0xFF, Nrc::ServiceNotSupported)
+        );
+    }
+
+    #[test]
+    fn session_control_reports_the_timing() {
+        let mut server = server();
+        assert_eq!(
+            request(&mut server, &[0x10, 0x03]),
+            Some(std::vec![0x50, 0x03, 0x00, 0x32, 0x01, 0xF4])
+        );
+        assert_eq!(server.session(), Session::Extended);
+    }
+
+    #[test]
+    fn wrong_length_is_rejected() {
+        let mut server = server();
+        for sid in [SID_SESSION_CONTROL, SID_ECU_RESET, SID_TESTER_PRESENT] {
+            assert_eq!(
+                request(&mut server, &[sid]),
+                negative(sid, Nrc::IncorrectMessageLength)
+            );
+            assert_eq!(
+                request(&mut server, &[sid, 0x01, 0x00]),
+                negative(sid, Nrc::IncorrectMessageLength)
+            );
+        }
+        for len in [1, 2, 4] {
+            let request_bytes = [0x22, 0xF1, 0x90, 0xF1];
+            assert_eq!(
+                request(&mut server, &request_bytes[..len]),
+                negative(0x22, Nrc::IncorrectMessageLength)
+            );
+        }
+        // nothing was changed by the rejected requests
+        assert_eq!(server.session(), Session::Default);
+        assert!(!server.take_reset());
+    }
+
+    #[test]
+    fn unknown_sub_function_is_rejected() {
+        let mut server = server();
+        assert_eq!(
+            request(&mut server, &[0x10, 0x04]),
+            negative(0x10, Nrc::SubFunctionNotSupported)
+        );
+        assert_eq!(
+            request(&mut server, &[0x11, 0x02]),
+            negative(0x11, Nrc::SubFunctionNotSupported)
+        );
+        assert_eq!(
+            request(&mut server, &[0x3E, 0x01]),
+            negative(0x3E, Nrc::SubFunctionNotSupported)
+        );
+        assert!(!server.take_reset());
+    }
+
+    #[test]
+    fn positive_response_can_be_suppressed() {
+        let mut server = server();
+        assert_eq!(request(&mut server, &[0x3E, 0x80]), None);
+        assert_eq!(request(&mut server, &[0x10, 0x83]), None);
+        assert_eq!(server.session(), Session::Extended);
+        assert_eq!(request(&mut server, &[0x11, 0x81]), None);
+        assert!(server.take_reset());
+        assert!(!server.take_reset());
+    }
+
+    #[test]
+    fn negative_response_is_never_suppressed() {
+        let mut server = server();
+        assert_eq!(
+            request(&mut server, &[0x10, 0x85]),
+            negative(0x10, Nrc::SubFunctionNotSupported)
+        );
+        assert_eq!(
+            request(&mut server, &[0x3E, 0x81]),
+            negative(0x3E, Nrc::SubFunctionNotSupported)
+        );
+        let riding = VehicleData {
+            riding: true,
+            ..PARKED
+        };
+        assert_eq!(
+            request_at(&mut server, &[0x11, 0x81], 0, &riding),
+            negative(0x11, Nrc::ConditionsNotCorrect)
+        );
+    }
+
+    #[test]
+    fn ecu_reset_is_refused_while_riding() {
+        let mut server = server();
+        let riding = VehicleData {
+            riding: true,
+            ..PARKED
+        };
+        assert_eq!(
+            request_at(&mut server, &[0x11, 0x01], 0, &riding),
+            negative(0x11, Nrc::ConditionsNotCorrect)
+        );
+        assert!(!server.take_reset());
+        assert_eq!(
+            request(&mut server, &[0x11, 0x03]),
+            Some(std::vec![0x51, 0x03])
+        );
+        assert!(server.take_reset());
+    }
+
+    #[test]
+    fn data_identifiers_are_read_in_order() {
+        let mut server = server();
+        let mut expected = std::vec![0x62, 0x01, 0x01, 80, 0x01, 0x00, 1, 0xF1, 0x95];
+        expected.extend_from_slice(b"1.2.3");
+        assert_eq!(
+            request(&mut server, &[0x22, 0x01, 0x01, 0x01, 0x00, 0xF1, 0x95]),
+            Some(expected)
+        );
+        let mut expected = std::vec![0x62, 0xF1, 0x90];
+        expected.extend_from_slice(&IDENTITY.vin);
+        assert_eq!(request(&mut server, &[0x22, 0xF1, 0x90]), Some(expected));
+    }
+
+    #[test]
+    fn unknown_or_missing_data_is_rejected() {
+        let mut server = server();
+        assert_eq!(
+            request(&mut server, &[0x22, 0xF1, 0x90, 0x12, 0x34]),
+            negative(0x22, Nrc::RequestOutOfRange)
+        );
+        let silent = VehicleData {
+            soc: None,
+            ..PARKED
+        };
+        assert_eq!(
+            request_at(&mut server, &[0x22, 0x01, 0x01], 0, &silent),
+            negative(0x22, Nrc::ConditionsNotCorrect)
+        );
+    }
+
+    #[test]
+    fn response_too_long_is_rejected() {
+        let mut server = server();
+        // 1 + 3 * 19 bytes fit, a fourth VIN does not
+        let vin = [0xF1, 0x90];
+        let request_bytes: std::vec::Vec<u8> = core::iter::once(0x22)
+            .chain(vin.iter().copied().cycle().take(6))
+            .collect();
+        assert_eq!(request(&mut server, &request_bytes).unwrap().len(), 58);
+        let request_bytes: std::vec::Vec<u8> = core::iter::once(0x22)
+            .chain(vin.iter().copied().cycle().take(8))
+            .collect();
+        assert_eq!(
+            request(&mut server, &request_bytes),
+            negative(0x22, Nrc::ResponseTooLong)
+        );
+        // the software version after three VINs ends 1 byte after the buffer
+        let mut request_bytes = request_bytes[..7].to_vec();
+        request_bytes.extend_from_slice(&[0xF1, 0x95]);
+        assert_eq!(
+            request(&mut server, &request_bytes),
+            negative(0x22, Nrc::ResponseTooLong)
+        );
+        // state and SOC still fit after three VINs, the identifier of a
+        // fourth DID does not
+        let mut request_bytes = request_bytes[..7].to_vec();
+        request_bytes.extend_from_slice(&[0x01, 0x00, 0x01, 0x01]);
+        assert_eq!(request(&mut server, &request_bytes).unwrap().len(), 64);
+        request_bytes.extend_from_slice(&[0x01, 0x00]);
+        assert_eq!(
+            request(&mut server, &request_bytes),
+            negative(0x22, Nrc::ResponseTooLong)
+        );
+    }
+
+    #[test]
+    fn session_falls_back_to_default_after_s3() {
+        let mut server = server();
+        request_at(&mut server, &[0x10, 0x03], 1000, &PARKED);
+        assert!(!server.poll(at(6000)));
+        assert_eq!(server.session(), Session::Extended);
+        // the tester keeps the session alive
+        request_at(&mut server, &[0x3E, 0x80], 6000, &PARKED);
+        assert!(!server.poll(at(11_000)));
+        assert!(server.poll(at(11_001)));
+        assert_eq!(server.session(), Session::Default);
+        // the default session has no timeout
+        assert!(!server.poll(at(60_000)));
+    }
+
+    #[test]
+    fn any_request_restarts_s3() {
+        let mut server = server();
+        request_at(&mut server, &[0x10, 0x02], 0, &PARKED);
+        request_at(&mut server, &[0x31, 0x01], 4000, &PARKED);
+        assert!(!server.poll(at(9000)));
+        assert_eq!(server.session(), Session::Programming);
+        assert!(server.poll(at(9001)));
+    }
+}
@@ -0,0 +1,276 @@
+use core::{
+    cell::RefCell,
+    future::poll_fn,
+    task::{Poll, Waker},
+};
+
+use heapless::{Deque, Vec};
+
+use crate::{
+    bus::{BusError, CanReceiver, CanTransmitter, Transmitted, TX_SLOTS},
+    Frame,
+};
+
+// In-process CAN bus to run the CAN tasks without the hardware. Every endpoint has the
+// transmit slots of a bxCAN, `transfer` puts the pending frame which wins the
+// arbitration on the bus and delivers it to all other endpoints.
+
+pub const MAX_ENDPOINTS: usize = 4;
+const RX_QUEUE_SIZE: usize = 32;
+
+struct Endpoint {
+    slots: [Option<Frame>; TX_SLOTS],
+    rx: Deque<Result<Frame, BusError>, RX_QUEUE_SIZE>,
+    rx_waker: Option<Waker>,
+    tx_waker: Option<Waker>,
+}
+
+impl Endpoint {
+    const fn new() -> Self {
+        Endpoint {
+            slots: [None; TX_SLOTS],
+            rx: Deque::new(),
+            rx_waker: None,
+            tx_waker: None,
+        }
+    }
+
+    fn deliver(&mut self, received: Result<Frame, BusError>) {
+        // a full FIFO loses the frame like the hardware
+        if self.rx.push_back(received).is_ok() {
+            if let Some(waker) = self.rx_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+pub struct VirtualBus {
+    endpoints: RefCell<Vec<Endpoint, MAX_ENDPOINTS>>,
+}
+
+impl VirtualBus {
+    pub const fn new() -> Self {
+        VirtualBus {
+            endpoints: RefCell::new(Vec::new()),
+        }
+    }
+
+    // connect a node to the bus
+    pub fn endpoint(&self) -> VirtualEndpoint<'_> {
+        let mut endpoints = self.endpoints.borrow_mut();
+        let index = endpoints.len();
+        if endpoints.push(Endpoint::new()).is_err() {
+            panic!("more than {} endpoints on the virtual bus", MAX_ENDPOINTS);
+        }
+        VirtualEndpoint { bus: self, index }
+    }
+
+    // send the pending frame with the lowest arbitration value, None if no frame is
+    // pending or no other endpoint acknowledges it, then it stays in its slot
+    pub fn transfer(&self) -> Option<Frame> {
+        let mut endpoints = self.endpoints.borrow_mut();
+        if endpoints.len() < 2 {
+            return None;
+        }
+        let (sender, slot) = endpoints
+            .iter()
+            .enumerate()
+            .flat_map(|(index, endpoint)| {
+                (0..TX_SLOTS).filter_map(move |slot| {
+                    endpoint.slots[slot].map(|frame| (frame.arbitration(), index, slot))
+                })
+            })
+            .min()
+            .map(|(_, index, slot)| (index, slot))?;
+        let frame = endpoints[sender].slots[slot].take()?;
+        if let Some(waker) = endpoints[sender].tx_waker.take() {
+            waker.wake();
+        }
+        for (index, endpoint) in endpoints.iter_mut().enumerate() {
+            if index != sender {
+                endpoint.deliver(Ok(frame));
+            }
+        }
+        Some(frame)
+    }
+
+    // error seen by the receive path of every endpoint
+    pub fn inject_error(&self, error: BusError) {
+        for endpoint in self.endpoints.borrow_mut().iter_mut() {
+            endpoint.deliver(Err(error));
+        }
+    }
+
+    // frames waiting in the transmit slots of all endpoints
+    pub fn pending(&self) -> usize {
+        self.endpoints
+            .borrow()
+            .iter()
+            .map(|endpoint| endpoint.slots.iter().flatten().count())
+            .sum()
+    }
+}
+
+impl Default for VirtualBus {
+    fn default() -> Self {
+        VirtualBus::new()
+    }
+}
+
+pub struct VirtualEndpoint<'a> {
+    bus: &'a VirtualBus,
+    index: usize,
+}
+
+impl VirtualEndpoint<'_> {
+    fn with<R>(&self, f: impl FnOnce(&mut Endpoint) -> R) -> R {
+        f(&mut self.bus.endpoints.borrow_mut()[self.index])
+    }
+
+    // next received frame or error without waiting
+    pub fn try_receive(&mut self) -> Option<Result<Frame, BusError>> {
+        self.with(|endpoint| endpoint.rx.pop_front())
+    }
+}
+
+impl CanTransmitter for VirtualEndpoint<'_> {
+    fn try_transmit(&mut self, frame: &Frame) -> Option<Transmitted> {
+        self.with(|endpoint| {
+            if let Some(slot) = endpoint.slots.iter().position(Option::is_none) {
+                endpoint.slots[slot] = Some(*frame);
+                return Some(Transmitted {
+                    slot,
+                    dequeued: None,
+                });
+            }
+            // like the bxCAN, replace the pending frame with the lowest priority
+            let slot =
+                (0..TX_SLOTS).max_by_key(|&slot| endpoint.slots[slot].map(|f| f.arbitration()))?;
+            let pending = endpoint.slots[slot]?;
+            if pending.arbitration() <= frame.arbitration() {
+                return None;
+            }
+            endpoint.slots[slot] = Some(*frame);
+            Some(Transmitted {
+                slot,
+                dequeued: Some(pending),
+            })
+        })
+    }
+
+    fn abort(&mut self, slot: usize) -> bool {
+        self.with(|endpoint| endpoint.slots[slot].take().is_some())
+    }
+
+    fn is_idle(&self, slot: usize) -> bool {
+        self.with(|endpoint| endpoint.slots[slot].is_none())
+    }
+
+    async fn wait_slot(&mut self) {
+        poll_fn(|cx| {
+            self.with(|endpoint| {
+                if endpoint.slots.iter().any(Option::is_none) {
+                    Poll::Ready(())
+                } else {
+                    endpoint.tx_waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+}
+
+impl CanReceiver for VirtualEndpoint<'_> {
+    async fn receive(&mut self) -> Result<Frame, BusError> {
+        poll_fn(|cx| {
+            self.with(|endpoint| match endpoint.rx.pop_front() {
+                Some(received) => Poll::Ready(received),
+                None => {
+                    endpoint.rx_waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(id: u32) -> Frame {
+        Frame::new_extended(id, &[0x11, 0x22]).unwrap()
+    }
+
+    #[test]
+    fn lowest_identifier_wins_the_arbitration() {
+        let bus = VirtualBus::new();
+        let mut a = bus.endpoint();
+        let mut b = bus.endpoint();
+        let mut c = bus.endpoint();
+        a.try_transmit(&frame(0x18FF_0001)).unwrap();
+        b.try_transmit(&frame(0x0C00_0002)).unwrap();
+        // a standard frame wins against an extended one with the same base ID
+        let standard = Frame::new_standard(0x300, &[]).unwrap();
+        a.try_transmit(&standard).unwrap();
+
+        assert_eq!(bus.transfer(), Some(standard));
+        assert_eq!(bus.transfer(), Some(frame(0x0C00_0002)));
+        assert_eq!(bus.transfer(), Some(frame(0x18FF_0001)));
+        assert_eq!(bus.transfer(), None);
+
+        // every other endpoint receives the frame, the sender does not
+        assert_eq!(a.try_receive(), Some(Ok(frame(0x0C00_0002))));
+        assert_eq!(a.try_receive(), None);
+        assert_eq!(b.try_receive(), Some(Ok(standard)));
+        assert_eq!(b.try_receive(), Some(Ok(frame(0x18FF_0001))));
+        assert_eq!(c.try_receive(), Some(Ok(standard)));
+        assert_eq!(c.try_receive(), Some(Ok(frame(0x0C00_0002))));
+        assert_eq!(c.try_receive(), Some(Ok(frame(0x18FF_0001))));
+    }
+
+    #[test]
+    fn frame_without_acknowledge_stays_pending() {
+        let bus = VirtualBus::new();
+        let mut a = bus.endpoint();
+        let slot = a.try_transmit(&frame(0x100)).unwrap().slot;
+        assert_eq!(bus.transfer(), None);
+        assert!(!a.is_idle(slot));
+        assert_eq!(bus.pending(), 1);
+
+        let mut b = bus.endpoint();
+        assert_eq!(bus.transfer(), Some(frame(0x100)));
+        assert!(a.is_idle(slot));
+        assert_eq!(b.try_receive(), Some(Ok(frame(0x100))));
+    }
+
+    #[test]
+    fn full_slots_dequeue_the_lowest_priority() {
+        let bus = VirtualBus::new();
+        let mut a = bus.endpoint();
+        for id in [0x300, 0x200, 0x400] {
+            assert!(a.try_transmit(&frame(id)).unwrap().dequeued.is_none());
+        }
+        assert!(a.try_transmit(&frame(0x500)).is_none());
+
+        let transmitted = a.try_transmit(&frame(0x100)).unwrap();
+        assert_eq!(transmitted.slot, 2);
+        assert_eq!(transmitted.dequeued, Some(frame(0x400)));
+        assert!(a.abort(0));
+        assert!(!a.abort(0));
+        assert_eq!(bus.pending(), 2);
+    }
+
+    #[test]
+    fn errors_reach_every_endpoint() {
+        let bus = VirtualBus::new();
+        let mut a = bus.endpoint();
+        let mut b = bus.endpoint();
+        bus.inject_error(BusError::Acknowledge);
+        assert_eq!(a.try_receive(), Some(Err(BusError::Acknowledge)));
+        assert_eq!(b.try_receive(), Some(Err(BusError::Acknowledge)));
+    }
+}
@@ -0,0 +1,128 @@
+use crate::println;
+
+// adjust to the pack: cells in series and temperature probes, at most 31 of each
+// so the masks of the received values fit in an u32
+pub const CELL_COUNT: usize = 20;
+pub const PROBE_COUNT: usize = 8;
+const _: () = assert!(CELL_COUNT <= 31 && PROBE_COUNT <= 31);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct CellStatus {
+    // in mV
+    pub voltages: [u16; CELL_COUNT],
+    // in °C
+    pub temperatures: [i8; PROBE_COUNT],
+    // one bit per cell which is balanced
+    pub balancing: u32,
+}
+
+impl CellStatus {
+    const fn new() -> Self {
+        CellStatus {
+            voltages: [0; CELL_COUNT],
+            temperatures: [0; PROBE_COUNT],
+            balancing: 0,
+        }
+    }
+
+    // index and voltage of the cell with the lowest voltage
+    pub fn weakest(&self) -> (usize, u16) {
+        self.voltages
+            .iter()
+            .copied()
+            .enumerate()
+            .min_by_key(|(_, voltage)| *voltage)
+            .unwrap_or((0, 0))
+    }
+
+    // difference between the highest and the lowest cell in mV
+    pub fn imbalance(&self) -> u16 {
+        let max = self.voltages.iter().max().copied().unwrap_or(0);
+        max - self.weakest().1
+    }
+
+    pub fn is_balancing(&self, cell: usize) -> bool {
+        self.balancing & (1 << cell) != 0
+    }
+}
+
+// collect the cells and probes of several frames until every one was received
+pub struct CellAssembler {
+    cells: CellStatus,
+    voltages_received: u32,
+    temperatures_received: u32,
+}
+
+impl CellAssembler {
+    pub fn init() -> Self {
+        CellAssembler {
+            cells: CellStatus::new(),
+            voltages_received: 0,
+            temperatures_received: 0,
+        }
+    }
+
+    // voltages (mV) from the cell `first` on, bit n of `balancing` for the cell first + n
+    pub fn set_voltages(&mut self, first: usize, voltages: &[u16], balancing: u8) {
+        for (offset, voltage) in voltages.iter().enumerate() {
+            let cell = first + offset;
+            if cell >= CELL_COUNT {
+                break;
+            }
+            self.cells.voltages[cell] = *voltage;
+            if balancing & (1 << offset) != 0 {
+                self.cells.balancing |= 1 << cell;
+            } else {
+                self.cells.balancing &= !(1 << cell);
+            }
+            self.voltages_received |= 1 << cell;
+        }
+    }
+
+    // temperatures (°C) from the probe `first` on
+    pub fn set_temperatures(&mut self, first: usize, temperatures: &[i8]) {
+        for (offset, temperature) in temperatures.iter().enumerate() {
+            let probe = first + offset;
+            if probe >= PROBE_COUNT {
+                break;
+            }
+            self.cells.temperatures[probe] = *temperature;
+            self.temperatures_received |= 1 << probe;
+        }
+    }
+
+    // return the arrays each time the last missing cell or probe was set
+    pub fn complete(&mut self) -> Option<CellStatus> {
+        let complete = self.voltages_received == (1 << CELL_COUNT) - 1
+            && self.temperatures_received == (1 << PROBE_COUNT) - 1;
+        if !complete {
+            return None;
+        }
+        // the next arrays are complete once every frame was received again
+        self.voltages_received = 0;
+        self.temperatures_received = 0;
+        Some(self.cells)
+    }
+}
+
+pub(super) fn print(cells: &CellStatus) {
+    let (weakest, voltage) = cells.weakest();
+    println!(
+        "weakest cell: {} ({} mV), imbalance: {} mV, {} cells balancing",
+        weakest + 1,
+        voltage,
+        cells.imbalance(),
+        cells.balancing.count_ones()
+    );
+    for (cell, voltage) in cells.voltages.iter().enumerate() {
+        let balancing = if cells.is_balancing(cell) {
+            " balancing"
+        } else {
+            ""
+        };
+        println!("cell {}: {} mV{}", cell + 1, voltage, balancing);
+    }
+    for (probe, temperature) in cells.temperatures.iter().enumerate() {
+        println!("probe {}: {} C", probe + 1, temperature);
+    }
+}
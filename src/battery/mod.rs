@@ -0,0 +1,240 @@
+use core::cell::RefCell;
+
+use cortex_m::interrupt::Mutex;
+use defmt::Format;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, watch::Watch};
+
+use crate::println;
+
+mod cells;
+mod pack;
+pub mod protocol;
+pub mod range;
+mod soc;
+
+pub use cells::{CellAssembler, CellStatus};
+pub use pack::{PackArbiter, PackState, PackStatus, PACK_ADDRESSES, PACK_COUNT};
+pub use soc::SocEstimator;
+
+// tasks which may wait for a new battery status
+const MAX_RECEIVERS: usize = 4;
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContactorState {
+    Open,
+    Precharge,
+    Closed,
+    // welded or not following the command
+    Fault,
+}
+
+impl ContactorState {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0x03 {
+            0 => ContactorState::Open,
+            1 => ContactorState::Precharge,
+            2 => ContactorState::Closed,
+            _ => ContactorState::Fault,
+        }
+    }
+}
+
+// alarm flags reported by the BMS, one bit per alarm
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BmsAlarms(pub u8);
+
+impl BmsAlarms {
+    pub const CELL_OVER_VOLTAGE: u8 = 1 << 0;
+    pub const CELL_UNDER_VOLTAGE: u8 = 1 << 1;
+    pub const OVER_TEMPERATURE: u8 = 1 << 2;
+    pub const UNDER_TEMPERATURE: u8 = 1 << 3;
+    pub const DISCHARGE_OVER_CURRENT: u8 = 1 << 4;
+    pub const CHARGE_OVER_CURRENT: u8 = 1 << 5;
+    pub const INSULATION: u8 = 1 << 6;
+    pub const INTERNAL: u8 = 1 << 7;
+
+    pub const ALL: [(u8, &'static str); 8] = [
+        (Self::CELL_OVER_VOLTAGE, "cell over voltage"),
+        (Self::CELL_UNDER_VOLTAGE, "cell under voltage"),
+        (Self::OVER_TEMPERATURE, "over temperature"),
+        (Self::UNDER_TEMPERATURE, "under temperature"),
+        (Self::DISCHARGE_OVER_CURRENT, "discharge over current"),
+        (Self::CHARGE_OVER_CURRENT, "charge over current"),
+        (Self::INSULATION, "insulation"),
+        (Self::INTERNAL, "internal"),
+    ];
+
+    // alarms which forbid to ride, the others only limit the power
+    const CRITICAL: u8 = Self::CELL_UNDER_VOLTAGE
+        | Self::OVER_TEMPERATURE
+        | Self::DISCHARGE_OVER_CURRENT
+        | Self::INSULATION
+        | Self::INTERNAL;
+
+    pub fn contains(&self, alarm: u8) -> bool {
+        self.0 & alarm != 0
+    }
+
+    pub fn is_critical(&self) -> bool {
+        self.0 & Self::CRITICAL != 0
+    }
+}
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatteryStatus {
+    // in 0.1 V
+    pub pack_voltage: u16,
+    // in 0.1 A, positive while discharging
+    pub current: i16,
+    // in %, reported by the BMS or estimated by the VCU when the BMS has none
+    pub soc: u8,
+    // SOC reported by the BMS, not every BMS sends it
+    pub bms_soc: Option<u8>,
+    pub soc_estimate: u8,
+    pub soh: u8,
+    // in mV
+    pub cell_min: u16,
+    pub cell_max: u16,
+    // in °C
+    pub temperature_min: i8,
+    pub temperature_max: i8,
+    pub contactor: ContactorState,
+    pub alarms: BmsAlarms,
+    // currents allowed by the BMS, in 0.1 A
+    pub discharge_limit: u16,
+    pub charge_limit: u16,
+    // packs combined in this status
+    pub packs: u8,
+}
+
+impl BatteryStatus {
+    const fn new() -> Self {
+        BatteryStatus {
+            pack_voltage: 0,
+            current: 0,
+            soc: 0,
+            bms_soc: None,
+            soc_estimate: 0,
+            soh: 0,
+            cell_min: 0,
+            cell_max: 0,
+            temperature_min: 0,
+            temperature_max: 0,
+            contactor: ContactorState::Open,
+            alarms: BmsAlarms(0),
+            discharge_limit: 0,
+            charge_limit: 0,
+            packs: 1,
+        }
+    }
+}
+
+// latest status combined from the packs, empty while every BMS is silent
+static BATTERY: Watch<CriticalSectionRawMutex, BatteryStatus, MAX_RECEIVERS> = Watch::new();
+// latest status and state of each pack
+static PACKS: Mutex<RefCell<[PackStatus; PACK_COUNT]>> =
+    Mutex::new(RefCell::new([PackStatus::new(); PACK_COUNT]));
+// latest complete cell arrays of each pack, only used for diagnostics
+static CELLS: Mutex<RefCell<[Option<CellStatus>; PACK_COUNT]>> =
+    Mutex::new(RefCell::new([None; PACK_COUNT]));
+
+pub fn publish(status: BatteryStatus) {
+    BATTERY.sender().send(status);
+}
+
+// every BMS stopped sending, the last values must not be used any more
+pub fn invalidate() {
+    BATTERY.sender().clear();
+}
+
+pub fn publish_packs(packs: [PackStatus; PACK_COUNT]) {
+    cortex_m::interrupt::free(|cs| PACKS.borrow(cs).replace(packs));
+}
+
+pub fn packs() -> [PackStatus; PACK_COUNT] {
+    cortex_m::interrupt::free(|cs| *PACKS.borrow(cs).borrow())
+}
+
+// the pack may be connected to the DC bus
+pub fn pack_active(pack: usize) -> bool {
+    packs()[pack].state == PackState::Active
+}
+
+// None once the BMS of the pack is lost
+pub fn publish_cells(pack: usize, cells: Option<CellStatus>) {
+    cortex_m::interrupt::free(|cs| CELLS.borrow(cs).borrow_mut()[pack] = cells);
+}
+
+pub fn cells(pack: usize) -> Option<CellStatus> {
+    cortex_m::interrupt::free(|cs| CELLS.borrow(cs).borrow()[pack])
+}
+
+pub fn print_cells() {
+    for pack in 0..PACK_COUNT {
+        match cells(pack) {
+            Some(cells) => {
+                println!("pack {}:", pack + 1);
+                cells::print(&cells);
+            }
+            None => println!("pack {}: no cell data from the BMS", pack + 1),
+        }
+    }
+}
+
+pub fn status() -> Option<BatteryStatus> {
+    BATTERY.try_get()
+}
+
+pub fn print_status() {
+    for (pack, pack_status) in packs().iter().enumerate() {
+        match pack_status.status {
+            Some(status) => println!(
+                "pack {}: {}, {}.{} V, {} A, SOC {}%, {:?}",
+                pack + 1,
+                pack_status.state.name(),
+                status.pack_voltage / 10,
+                status.pack_voltage % 10,
+                status.current as f32 / 10.0,
+                status.bms_soc.unwrap_or(0),
+                status.contactor
+            ),
+            None => println!("pack {}: {}", pack + 1, pack_status.state.name()),
+        }
+    }
+    let Some(status) = status() else {
+        println!("no data from the BMS");
+        return;
+    };
+    println!(
+        "battery: {} packs, {}.{} V, {} A",
+        status.packs,
+        status.pack_voltage / 10,
+        status.pack_voltage % 10,
+        status.current as f32 / 10.0
+    );
+    match status.bms_soc {
+        Some(bms_soc) => println!(
+            "SOC: {}% (BMS {}%, estimate {}%), SOH: {}%",
+            status.soc, bms_soc, status.soc_estimate, status.soh
+        ),
+        None => println!(
+            "SOC: {}% (estimate, none from the BMS), SOH: {}%",
+            status.soc, status.soh
+        ),
+    }
+    println!(
+        "cells: {} mV .. {} mV, temperature: {} C .. {} C",
+        status.cell_min, status.cell_max, status.temperature_min, status.temperature_max
+    );
+    println!(
+        "limits: discharge {} A, charge {} A",
+        status.discharge_limit / 10,
+        status.charge_limit / 10
+    );
+    println!("contactor: {:?}", status.contactor);
+    for (alarm, name) in BmsAlarms::ALL.iter() {
+        if status.alarms.contains(*alarm) {
+            println!("alarm: {}", name);
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use defmt::Format;
+use log::{info, warn};
+
+use super::{BatteryStatus, BmsAlarms, ContactorState};
+
+// packs in parallel on the DC bus, each with its own BMS
+
+// adjust to the model: J1939 address of the BMS of each pack, one entry per pack
+pub const PACK_ADDRESSES: [u8; 2] = [0xF4, 0xF5];
+pub const PACK_COUNT: usize = PACK_ADDRESSES.len();
+
+// largest voltage difference (0.1 V) a pack may have to the connected packs, a pack
+// which is further away waits until the others are discharged to its voltage
+const MAX_VOLTAGE_DIFFERENCE: u16 = 20;
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackState {
+    // no status from its BMS
+    Missing,
+    // critical alarm or contactor fault
+    Fault,
+    // voltage too far from the connected packs
+    Standby,
+    // may be connected
+    Active,
+}
+
+impl PackState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PackState::Missing => "missing",
+            PackState::Fault => "fault",
+            PackState::Standby => "standby",
+            PackState::Active => "active",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PackStatus {
+    pub state: PackState,
+    pub status: Option<BatteryStatus>,
+}
+
+impl PackStatus {
+    pub const fn new() -> Self {
+        PackStatus {
+            state: PackState::Missing,
+            status: None,
+        }
+    }
+}
+
+// decide which packs may be connected and combine them into one status
+pub struct PackArbiter {
+    packs: [PackStatus; PACK_COUNT],
+}
+
+impl PackArbiter {
+    pub fn init() -> Self {
+        PackArbiter {
+            packs: [PackStatus::new(); PACK_COUNT],
+        }
+    }
+
+    pub fn packs(&self) -> [PackStatus; PACK_COUNT] {
+        self.packs
+    }
+
+    // new status of a pack, None once its BMS is lost
+    pub fn update(&mut self, pack: usize, status: Option<BatteryStatus>) {
+        self.packs[pack].status = status;
+        self.arbitrate();
+    }
+
+    fn arbitrate(&mut self) {
+        let healthy = |status: &BatteryStatus| {
+            !status.alarms.is_critical() && status.contactor != ContactorState::Fault
+        };
+        // the packs already connected set the DC bus voltage, otherwise
+        // riding starts on the fullest pack
+        let connected = self
+            .packs
+            .iter()
+            .filter_map(|pack| pack.status)
+            .filter(|status| healthy(status) && status.contactor == ContactorState::Closed);
+        let reference = connected
+            .map(|status| status.pack_voltage)
+            .max()
+            .or_else(|| {
+                self.packs
+                    .iter()
+                    .filter_map(|pack| pack.status)
+                    .filter(healthy)
+                    .map(|status| status.pack_voltage)
+                    .max()
+            });
+        for (index, pack) in self.packs.iter_mut().enumerate() {
+            let state = match (pack.status, reference) {
+                (None, _) => PackState::Missing,
+                (Some(status), _) if !healthy(&status) => PackState::Fault,
+                (Some(status), _) if status.contactor == ContactorState::Closed => {
+                    PackState::Active
+                }
+                (Some(status), Some(reference))
+                    if status.pack_voltage.abs_diff(reference) <= MAX_VOLTAGE_DIFFERENCE =>
+                {
+                    PackState::Active
+                }
+                _ => PackState::Standby,
+            };
+            if state != pack.state {
+                match state {
+                    PackState::Missing | PackState::Fault => {
+                        warn!("Pack {} {}", index + 1, state.name())
+                    }
+                    _ => info!("Pack {} {}", index + 1, state.name()),
+                }
+                pack.state = state;
+            }
+        }
+    }
+
+    // alarms of every pack with a status, also of the packs which are not used
+    pub fn alarms(&self) -> BmsAlarms {
+        BmsAlarms(
+            self.packs
+                .iter()
+                .filter_map(|pack| pack.status)
+                .fold(0, |alarms, status| alarms | status.alarms.0),
+        )
+    }
+
+    // one status of the active packs, of every pack with a status when none is
+    // active so the alarms keep the vehicle from riding, None without any
+    pub fn combine(&self) -> Option<BatteryStatus> {
+        let statuses = |state: Option<PackState>| {
+            self.packs
+                .iter()
+                .filter(move |pack| state.is_none_or(|state| pack.state == state))
+                .filter_map(|pack| pack.status)
+        };
+        combine(statuses(Some(PackState::Active))).or_else(|| combine(statuses(None)))
+    }
+}
+
+// the packs are in parallel: same voltage, the currents and limits add up
+fn combine(packs: impl Iterator<Item = BatteryStatus> + Clone) -> Option<BatteryStatus> {
+    let first = packs.clone().next()?;
+    let count = packs.clone().count() as u32;
+    let mean = |value: fn(&BatteryStatus) -> u32| {
+        packs.clone().map(|status| value(&status)).sum::<u32>() / count
+    };
+    let any = |state: ContactorState| packs.clone().any(|status| status.contactor == state);
+    let contactor = if any(ContactorState::Closed) {
+        ContactorState::Closed
+    } else if any(ContactorState::Fault) {
+        ContactorState::Fault
+    } else if any(ContactorState::Precharge) {
+        ContactorState::Precharge
+    } else {
+        ContactorState::Open
+    };
+    Some(BatteryStatus {
+        pack_voltage: mean(|status| status.pack_voltage as u32) as u16,
+        current: packs
+            .clone()
+            .fold(0, |sum, status| sum.saturating_add(status.current)),
+        // only with the SOC of every pack
+        bms_soc: packs
+            .clone()
+            .map(|status| status.bms_soc.map(u32::from))
+            .sum::<Option<u32>>()
+            .map(|sum| (sum / count) as u8),
+        soh: mean(|status| status.soh as u32) as u8,
+        cell_min: packs.clone().map(|status| status.cell_min).min()?,
+        cell_max: packs.clone().map(|status| status.cell_max).max()?,
+        temperature_min: packs.clone().map(|status| status.temperature_min).min()?,
+        temperature_max: packs.clone().map(|status| status.temperature_max).max()?,
+        contactor,
+        alarms: BmsAlarms(
+            packs
+                .clone()
+                .fold(0, |alarms, status| alarms | status.alarms.0),
+        ),
+        discharge_limit: packs
+            .clone()
+            .fold(0, |sum, status| sum.saturating_add(status.discharge_limit)),
+        charge_limit: packs
+            .clone()
+            .fold(0, |sum, status| sum.saturating_add(status.charge_limit)),
+        packs: count as u8,
+        ..first
+    })
+}
@@ -0,0 +1,220 @@
+use embassy_time::{Duration, Instant};
+use nuen_can::Frame;
+
+use super::{BmsKind, BmsProtocol, BmsUpdate};
+use crate::battery::{BatteryStatus, BmsAlarms, CellAssembler, ContactorState};
+
+// Daly smart BMS, every value is answered to a request of the VCU. The board number of
+// the BMS is the pack number (1 for the first pack).
+// Request 0x18<data id><board>40 with 8 bytes of 0, response 0x18<data id>40<board>,
+// big-endian:
+//  0x90: pack voltage (0.1 V), bytes 4..6 current (0.1 A + 30000, positive while charging),
+//        bytes 6..8 SOC (0.1 %)
+//  0x91: max cell voltage (mV), its cell, min cell voltage (mV), its cell
+//  0x92: max temperature (°C + 40), its probe, min temperature (°C + 40), its probe
+//  0x93: state, charge MOS on, discharge MOS on, life, remaining capacity (mAh)
+//  0x95: frame number from 1, 3 cell voltages (mV) in bytes 1..7
+//  0x96: frame number from 1, 7 temperatures (°C + 40) in bytes 1..8
+//  0x97: balancing, one bit per cell from bit 0 of byte 0
+//  0x98: failure codes, one bit per failure in bytes 0..7
+const REQUEST_ID: u32 = 0x1800_0040;
+const RESPONSE_ID: u32 = 0x1800_4000;
+const DATA_ID_MASK: u32 = 0x00FF_0000;
+
+const STATUS: u8 = 0x90;
+const CELL_RANGE: u8 = 0x91;
+const TEMPERATURE_RANGE: u8 = 0x92;
+const MOS: u8 = 0x93;
+const CELL_VOLTAGES: u8 = 0x95;
+const TEMPERATURES: u8 = 0x96;
+const BALANCING: u8 = 0x97;
+const FAILURES: u8 = 0x98;
+
+// the data IDs are requested one after the other
+const REQUESTS: [u8; 8] = [
+    STATUS,
+    CELL_RANGE,
+    TEMPERATURE_RANGE,
+    MOS,
+    CELL_VOLTAGES,
+    TEMPERATURES,
+    BALANCING,
+    FAILURES,
+];
+const REQUEST_PERIOD: Duration = Duration::from_millis(40);
+
+const CURRENT_OFFSET: i32 = 30_000;
+const CELLS_PER_FRAME: usize = 3;
+const PROBES_PER_FRAME: usize = 7;
+const TEMPERATURE_OFFSET: i16 = 40;
+
+// the BMS has no current limits, adjust to the pack (0.1 A)
+const DISCHARGE_LIMIT: u16 = 2000;
+const CHARGE_LIMIT: u16 = 300;
+
+const STATUS_RECEIVED: u8 = 1 << 0;
+const CELL_RANGE_RECEIVED: u8 = 1 << 1;
+const TEMPERATURE_RANGE_RECEIVED: u8 = 1 << 2;
+const MOS_RECEIVED: u8 = 1 << 3;
+const FAILURES_RECEIVED: u8 = 1 << 4;
+const ALL_RECEIVED: u8 = STATUS_RECEIVED
+    | CELL_RANGE_RECEIVED
+    | TEMPERATURE_RANGE_RECEIVED
+    | MOS_RECEIVED
+    | FAILURES_RECEIVED;
+
+// level 2 failures (byte, bit) of each alarm, the level 1 failures are only warnings
+const FAILURE_ALARMS: [(usize, u8, u8); 12] = [
+    (0, 1, BmsAlarms::CELL_OVER_VOLTAGE),
+    (0, 5, BmsAlarms::CELL_OVER_VOLTAGE),
+    (0, 3, BmsAlarms::CELL_UNDER_VOLTAGE),
+    (0, 7, BmsAlarms::CELL_UNDER_VOLTAGE),
+    (1, 1, BmsAlarms::OVER_TEMPERATURE),
+    (1, 5, BmsAlarms::OVER_TEMPERATURE),
+    (1, 3, BmsAlarms::UNDER_TEMPERATURE),
+    (1, 7, BmsAlarms::UNDER_TEMPERATURE),
+    (2, 1, BmsAlarms::CHARGE_OVER_CURRENT),
+    (2, 3, BmsAlarms::DISCHARGE_OVER_CURRENT),
+    // short circuit protection
+    (6, 2, BmsAlarms::DISCHARGE_OVER_CURRENT),
+    // precharge failure
+    (5, 5, BmsAlarms::INTERNAL),
+];
+// MOS, AFE, sensor and memory faults
+const INTERNAL_FAILURES: [(usize, u8); 3] = [(4, 0xFF), (5, 0xDF), (6, 0x03)];
+
+pub struct DalyBms {
+    board: u8,
+    status: BatteryStatus,
+    received: u8,
+    cells: CellAssembler,
+    // one bit per cell, applied to the next cell voltages
+    balancing: u32,
+    next_request: usize,
+    last_request: Option<Instant>,
+}
+
+impl DalyBms {
+    fn alarms(data: &[u8]) -> BmsAlarms {
+        let mut alarms = 0;
+        for (byte, bit, alarm) in FAILURE_ALARMS.iter() {
+            if data[*byte] & (1 << bit) != 0 {
+                alarms |= alarm;
+            }
+        }
+        if INTERNAL_FAILURES
+            .iter()
+            .any(|(byte, mask)| data[*byte] & mask != 0)
+        {
+            alarms |= BmsAlarms::INTERNAL;
+        }
+        BmsAlarms(alarms)
+    }
+}
+
+impl BmsProtocol for DalyBms {
+    fn init(pack: usize) -> Self {
+        let mut status = BatteryStatus::new();
+        status.discharge_limit = DISCHARGE_LIMIT;
+        status.charge_limit = CHARGE_LIMIT;
+        DalyBms {
+            board: pack as u8 + 1,
+            status,
+            received: 0,
+            cells: CellAssembler::init(),
+            balancing: 0,
+            next_request: 0,
+            last_request: None,
+        }
+    }
+
+    fn kind(&self) -> BmsKind {
+        BmsKind::Daly
+    }
+
+    fn decode(&mut self, frame: &Frame) -> Option<BmsUpdate> {
+        let id = frame.id();
+        let data = frame.data();
+        if id & !DATA_ID_MASK != RESPONSE_ID | self.board as u32 || data.len() < 8 {
+            return None;
+        }
+        let word = |at: usize| u16::from_be_bytes([data[at], data[at + 1]]);
+        let temperature = |byte: u8| {
+            (byte as i16 - TEMPERATURE_OFFSET).clamp(i8::MIN as i16, i8::MAX as i16) as i8
+        };
+        match ((id & DATA_ID_MASK) >> 16) as u8 {
+            STATUS => {
+                self.status.pack_voltage = word(0);
+                self.status.current = (CURRENT_OFFSET - word(4) as i32) as i16;
+                self.status.bms_soc = Some((word(6) / 10).min(100) as u8);
+                self.received |= STATUS_RECEIVED;
+            }
+            CELL_RANGE => {
+                self.status.cell_max = word(0);
+                self.status.cell_min = word(3);
+                self.received |= CELL_RANGE_RECEIVED;
+            }
+            TEMPERATURE_RANGE => {
+                self.status.temperature_max = temperature(data[0]);
+                self.status.temperature_min = temperature(data[2]);
+                self.received |= TEMPERATURE_RANGE_RECEIVED;
+            }
+            MOS => {
+                // the discharge MOS connects the pack to the DC bus
+                self.status.contactor = if data[2] != 0 {
+                    ContactorState::Closed
+                } else {
+                    ContactorState::Open
+                };
+                self.received |= MOS_RECEIVED;
+            }
+            FAILURES => {
+                self.status.alarms = DalyBms::alarms(data);
+                self.received |= FAILURES_RECEIVED;
+            }
+            CELL_VOLTAGES if data[0] > 0 => {
+                let first = (data[0] as usize - 1) * CELLS_PER_FRAME;
+                let mut voltages = [0; CELLS_PER_FRAME];
+                for (voltage, bytes) in voltages.iter_mut().zip(data[1..7].chunks(2)) {
+                    *voltage = u16::from_be_bytes([bytes[0], bytes[1]]);
+                }
+                let balancing = self.balancing.checked_shr(first as u32).unwrap_or(0) as u8;
+                self.cells.set_voltages(first, &voltages, balancing);
+                return self.cells.complete().map(BmsUpdate::Cells);
+            }
+            TEMPERATURES if data[0] > 0 => {
+                let first = (data[0] as usize - 1) * PROBES_PER_FRAME;
+                let mut temperatures = [0; PROBES_PER_FRAME];
+                for (probe, byte) in temperatures.iter_mut().zip(data[1..8].iter()) {
+                    *probe = temperature(*byte);
+                }
+                self.cells.set_temperatures(first, &temperatures);
+                return self.cells.complete().map(BmsUpdate::Cells);
+            }
+            BALANCING => {
+                self.balancing = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+                return None;
+            }
+            _ => return None,
+        }
+        (self.received == ALL_RECEIVED).then_some(BmsUpdate::Status(self.status))
+    }
+
+    fn request(&mut self, now: Instant) -> Option<Frame> {
+        if self
+            .last_request
+            .is_some_and(|last| now.duration_since(last) < REQUEST_PERIOD)
+        {
+            return None;
+        }
+        self.last_request = Some(now);
+        let data_id = REQUESTS[self.next_request];
+        self.next_request = (self.next_request + 1) % REQUESTS.len();
+        let id = REQUEST_ID | (data_id as u32) << 16 | (self.board as u32) << 8;
+        Frame::new_extended(id, &[0x00; 8])
+    }
+
+    fn reset(&mut self) {
+        *self = DalyBms::init(self.board as usize - 1);
+    }
+}
@@ -0,0 +1,62 @@
+use defmt::Format;
+use embassy_time::Instant;
+use nuen_can::Frame;
+
+use super::{BatteryStatus, CellStatus};
+
+mod daly;
+mod nuen;
+
+pub use daly::DalyBms;
+pub use nuen::NuenBms;
+
+// BMS vendors with a built-in driver, selected with `config bms <name>`
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BmsKind {
+    // broadcasts its frames, see `nuen`
+    Nuen = 0,
+    // answers the requests of the VCU, see `daly`
+    Daly = 1,
+}
+
+impl BmsKind {
+    pub const ALL: [BmsKind; 2] = [BmsKind::Nuen, BmsKind::Daly];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BmsKind::Nuen => "nuen",
+            BmsKind::Daly => "daly",
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        BmsKind::ALL
+            .iter()
+            .copied()
+            .find(|kind| *kind as u8 == value)
+    }
+}
+
+pub enum BmsUpdate {
+    Status(BatteryStatus),
+    Cells(CellStatus),
+}
+
+// frames of one BMS vendor, every driver fills the same `BatteryStatus`.
+// The IDs of the driver must be listed for the BMS node in `can::supervision`.
+pub trait BmsProtocol {
+    // driver of the BMS of the given pack, see `battery::PACK_ADDRESSES`
+    fn init(pack: usize) -> Self;
+
+    fn kind(&self) -> BmsKind;
+
+    // return the status each time it was updated once every part was received,
+    // and the cell arrays each time they are complete
+    fn decode(&mut self, frame: &Frame) -> Option<BmsUpdate>;
+
+    // next request to the BMS, called on every frame and every BMS cycle
+    fn request(&mut self, now: Instant) -> Option<Frame>;
+
+    // forget the received parts after the BMS was lost
+    fn reset(&mut self);
+}
@@ -0,0 +1,118 @@
+use embassy_time::Instant;
+use nuen_can::Frame;
+
+use super::{BmsKind, BmsProtocol, BmsUpdate};
+use crate::battery::{BatteryStatus, BmsAlarms, CellAssembler, ContactorState, PACK_ADDRESSES};
+
+// frames broadcast by the BMS, the source address in the low byte is the one of the
+// pack (0xF4 for the first pack), the status frame is E2E protected (bytes 0 and 1)
+//  0x1806E5F4: pack voltage (0.1 V), pack current (0.1 A), SOC (0xFF if not available),
+//              contactor state
+//  0x18FF28F4: min/max cell voltage (mV), min/max temperature (°C + 40), SOH, alarms
+//  0x18FF29F4: allowed discharge and charge current (0.1 A)
+// indexed cell frames, byte 0 is the index of the first cell or probe
+//  0x18FF30F4: 3 cell voltages (mV) in bytes 1..7, balancing of these cells in byte 7
+//  0x18FF31F4: 7 temperatures (°C + 40) in bytes 1..8
+const BMS_STATUS: u32 = 0x1806E500;
+const BMS_CELLS: u32 = 0x18FF2800;
+const BMS_LIMITS: u32 = 0x18FF2900;
+const BMS_CELL_VOLTAGES: u32 = 0x18FF3000;
+const BMS_CELL_TEMPERATURES: u32 = 0x18FF3100;
+
+const ADDRESS_MASK: u32 = 0xFF;
+
+const CELLS_PER_FRAME: usize = 3;
+const PROBES_PER_FRAME: usize = 7;
+const TEMPERATURE_OFFSET: i16 = 40;
+
+const STATUS_RECEIVED: u8 = 1 << 0;
+const CELLS_RECEIVED: u8 = 1 << 1;
+const LIMITS_RECEIVED: u8 = 1 << 2;
+const ALL_RECEIVED: u8 = STATUS_RECEIVED | CELLS_RECEIVED | LIMITS_RECEIVED;
+
+pub struct NuenBms {
+    pack: usize,
+    status: BatteryStatus,
+    // frames received since the start, the status is complete with all of them
+    received: u8,
+    cells: CellAssembler,
+}
+
+impl BmsProtocol for NuenBms {
+    fn init(pack: usize) -> Self {
+        NuenBms {
+            pack,
+            status: BatteryStatus::new(),
+            received: 0,
+            cells: CellAssembler::init(),
+        }
+    }
+
+    fn kind(&self) -> BmsKind {
+        BmsKind::Nuen
+    }
+
+    fn decode(&mut self, frame: &Frame) -> Option<BmsUpdate> {
+        let id = frame.id();
+        let data = frame.data();
+        if id & ADDRESS_MASK != PACK_ADDRESSES[self.pack] as u32 || data.len() < 8 {
+            return None;
+        }
+        let word = |at: usize| u16::from_le_bytes([data[at], data[at + 1]]);
+        // raw bytes above 167 are more than 127 °C and do not fit in an i8, clamp them
+        let temperature = |byte: u8| {
+            (byte as i16 - TEMPERATURE_OFFSET).clamp(i8::MIN as i16, i8::MAX as i16) as i8
+        };
+        match id & !ADDRESS_MASK {
+            BMS_STATUS => {
+                self.status.pack_voltage = word(2);
+                self.status.current = word(4) as i16;
+                self.status.bms_soc = (data[6] <= 100).then_some(data[6]);
+                self.status.contactor = ContactorState::from_bits(data[7]);
+                self.received |= STATUS_RECEIVED;
+            }
+            BMS_CELLS => {
+                self.status.cell_min = word(0);
+                self.status.cell_max = word(2);
+                self.status.temperature_min = temperature(data[4]);
+                self.status.temperature_max = temperature(data[5]);
+                self.status.soh = data[6].min(100);
+                self.status.alarms = BmsAlarms(data[7]);
+                self.received |= CELLS_RECEIVED;
+            }
+            BMS_LIMITS => {
+                self.status.discharge_limit = word(0);
+                self.status.charge_limit = word(2);
+                self.received |= LIMITS_RECEIVED;
+            }
+            BMS_CELL_VOLTAGES => {
+                let mut voltages = [0; CELLS_PER_FRAME];
+                for (voltage, bytes) in voltages.iter_mut().zip(data[1..7].chunks(2)) {
+                    *voltage = u16::from_le_bytes([bytes[0], bytes[1]]);
+                }
+                self.cells
+                    .set_voltages(data[0] as usize, &voltages, data[7]);
+                return self.cells.complete().map(BmsUpdate::Cells);
+            }
+            BMS_CELL_TEMPERATURES => {
+                let mut temperatures = [0; PROBES_PER_FRAME];
+                for (probe, byte) in temperatures.iter_mut().zip(data[1..8].iter()) {
+                    *probe = temperature(*byte);
+                }
+                self.cells.set_temperatures(data[0] as usize, &temperatures);
+                return self.cells.complete().map(BmsUpdate::Cells);
+            }
+            _ => return None,
+        }
+        (self.received == ALL_RECEIVED).then_some(BmsUpdate::Status(self.status))
+    }
+
+    // the frames are broadcast without request
+    fn request(&mut self, _now: Instant) -> Option<Frame> {
+        None
+    }
+
+    fn reset(&mut self) {
+        *self = NuenBms::init(self.pack);
+    }
+}
@@ -0,0 +1,135 @@
+use core::cell::RefCell;
+
+use cortex_m::interrupt::Mutex;
+
+use super::soc::PACK_CAPACITY;
+use crate::{
+    config,
+    power::{self, RideMode},
+    println,
+};
+
+// remaining range from the energy left in the pack and the consumption of each ride mode
+
+// consumption until a ride mode has a history, in Wh/km
+const DEFAULT_CONSUMPTION: f32 = 30.0;
+// the average is updated per segment and follows the last rides over this distance
+const SEGMENT: f32 = 1000.0;
+const WINDOW: f32 = 20_000.0;
+// downhill rides do not make the range endless
+const MIN_CONSUMPTION: f32 = 5.0;
+// smaller changes of the average are not worth a flash record, in 0.1 Wh/km
+const SAVE_STEP: u16 = 5;
+
+struct RangeEstimator {
+    // average consumption of each ride mode, in Wh/km
+    consumption: [f32; RideMode::ALL.len()],
+    // energy and distance (m) of the segment in progress
+    energy: f32,
+    distance: f32,
+    loaded: bool,
+}
+
+impl RangeEstimator {
+    const fn new() -> Self {
+        RangeEstimator {
+            consumption: [DEFAULT_CONSUMPTION; RideMode::ALL.len()],
+            energy: 0.0,
+            distance: 0.0,
+            loaded: false,
+        }
+    }
+
+    // take the consumption saved before the last power off
+    fn load(&mut self) {
+        if self.loaded {
+            return;
+        }
+        self.loaded = true;
+        for (consumption, saved) in self
+            .consumption
+            .iter_mut()
+            .zip(config::settings().consumption.iter())
+        {
+            if let Some(saved) = saved {
+                *consumption = *saved as f32 / 10.0;
+            }
+        }
+    }
+}
+
+static RANGE: Mutex<RefCell<RangeEstimator>> = Mutex::new(RefCell::new(RangeEstimator::new()));
+
+// energy drawn from the DC bus in Wh, negative while braking
+pub fn add_energy(energy: f32) {
+    cortex_m::interrupt::free(|cs| RANGE.borrow(cs).borrow_mut().energy += energy);
+}
+
+// distance ridden in m, the consumption of the current ride mode is updated per segment
+pub fn add_distance(distance: f32) {
+    let mode = power::ride_mode();
+    let updated = cortex_m::interrupt::free(|cs| {
+        let mut range = RANGE.borrow(cs).borrow_mut();
+        range.load();
+        range.distance += distance;
+        if range.distance < SEGMENT {
+            return None;
+        }
+        let (segment, weight) = (
+            range.energy * 1000.0 / range.distance,
+            range.distance / WINDOW,
+        );
+        let average = &mut range.consumption[mode as usize];
+        *average += (segment - *average) * weight;
+        range.energy = 0.0;
+        range.distance = 0.0;
+        Some(range.consumption)
+    });
+    // written to flash by the config task once the vehicle is parked
+    if let Some(consumption) = updated {
+        let consumption = consumption.map(|consumption| (consumption.max(0.0) * 10.0) as u16);
+        let changed = config::settings()
+            .consumption
+            .iter()
+            .zip(consumption.iter())
+            .any(|(saved, consumption)| {
+                saved.is_none_or(|saved| saved.abs_diff(*consumption) >= SAVE_STEP)
+            });
+        if changed {
+            config::update_pending(|settings| {
+                settings.consumption = consumption.map(Some);
+            });
+        }
+    }
+}
+
+pub fn consumption(mode: RideMode) -> f32 {
+    cortex_m::interrupt::free(|cs| {
+        let mut range = RANGE.borrow(cs).borrow_mut();
+        range.load();
+        range.consumption[mode as usize]
+    })
+}
+
+// remaining range in km with the current ride mode, unknown without BMS data
+pub fn remaining() -> Option<u16> {
+    let status = super::status()?;
+    // the capacity fades with the state of health
+    let soh = if status.soh > 0 { status.soh } else { 100 };
+    let capacity = PACK_CAPACITY * status.packs as u32;
+    let energy = status.soc as f32 / 100.0 * soh as f32 / 100.0 * capacity as f32 / 1000.0
+        * status.pack_voltage as f32
+        / 10.0;
+    let consumption = consumption(power::ride_mode()).max(MIN_CONSUMPTION);
+    Some((energy / consumption) as u16)
+}
+
+pub fn print_range() {
+    match remaining() {
+        Some(km) => println!("range: {} km in {} mode", km, power::ride_mode().name()),
+        None => println!("range: unknown, no data from the BMS"),
+    }
+    for mode in RideMode::ALL.iter() {
+        println!("{}: {} Wh/km", mode.name(), consumption(*mode) as u16);
+    }
+}
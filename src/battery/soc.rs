@@ -0,0 +1,143 @@
+use embassy_time::{Duration, Instant};
+use log::warn;
+
+use super::BatteryStatus;
+use crate::{
+    config,
+    fault::{self, FaultCode},
+};
+
+// SOC estimated by the VCU from the pack current, corrected from the cell voltage at rest
+
+// open circuit voltage of a cell (mV) and its SOC (%), adjust to the cells of the pack
+pub const OCV_TABLE: [(u16, u8); 11] = [
+    (3000, 0),
+    (3450, 10),
+    (3550, 20),
+    (3610, 30),
+    (3660, 40),
+    (3710, 50),
+    (3770, 60),
+    (3850, 70),
+    (3930, 80),
+    (4030, 90),
+    (4180, 100),
+];
+// nominal capacity of one pack in mAh
+pub const PACK_CAPACITY: u32 = 30_000;
+
+// below this current (0.1 A) the pack rests, the cell voltage is the OCV after a while
+const REST_CURRENT: u16 = 10;
+const REST_TIME: Duration = Duration::from_secs(600);
+// the current is not integrated over a gap in the BMS data
+const MAX_STEP: Duration = Duration::from_secs(1);
+// difference to the BMS SOC (%) which raises and clears the plausibility fault
+const DIVERGENCE_RAISE: f32 = 15.0;
+const DIVERGENCE_CLEAR: f32 = 10.0;
+const DIVERGENCE_TIME: Duration = Duration::from_secs(10);
+// the estimate is kept for the next start each time it changed by this much (%),
+// it is written to flash once the vehicle is parked
+const SAVE_STEP: f32 = 1.0;
+
+// SOC of the OCV table for the given cell voltage, interpolated linearly
+fn ocv_soc(cell: u16) -> f32 {
+    let (first, last) = (OCV_TABLE[0], OCV_TABLE[OCV_TABLE.len() - 1]);
+    if cell <= first.0 {
+        return first.1 as f32;
+    }
+    if cell >= last.0 {
+        return last.1 as f32;
+    }
+    OCV_TABLE
+        .windows(2)
+        .find(|points| cell < points[1].0)
+        .map_or(last.1 as f32, |points| {
+            let ((low_cell, low_soc), (high_cell, high_soc)) = (points[0], points[1]);
+            low_soc as f32
+                + (high_soc - low_soc) as f32 * (cell - low_cell) as f32
+                    / (high_cell - low_cell) as f32
+        })
+}
+
+pub struct SocEstimator {
+    // in %, unknown until the first BMS status without a saved value
+    soc: Option<f32>,
+    saved: Option<f32>,
+    last_update: Option<Instant>,
+    rest_since: Option<Instant>,
+    divergent_since: Option<Instant>,
+}
+
+impl SocEstimator {
+    // continue from the SOC saved before the last power off
+    pub fn init() -> Self {
+        let saved = config::settings().soc.map(|soc| soc as f32 / 10.0);
+        SocEstimator {
+            soc: saved,
+            saved,
+            last_update: None,
+            rest_since: None,
+            divergent_since: None,
+        }
+    }
+
+    // update the estimate with a new BMS status and return it in %
+    pub fn update(&mut self, status: &BatteryStatus, now: Instant) -> u8 {
+        let cell = ((status.cell_min as u32 + status.cell_max as u32) / 2) as u16;
+        let mut soc = match (self.soc, self.last_update) {
+            (None, _) => ocv_soc(cell),
+            (Some(soc), Some(last)) if now.duration_since(last) <= MAX_STEP => {
+                // current in 0.1 A and time in ms, 1 mAh is 3600 A ms
+                let charge = status.current as f32 * now.duration_since(last).as_millis() as f32
+                    / 3600.0
+                    / 10.0;
+                soc - charge * 100.0 / (PACK_CAPACITY * status.packs as u32) as f32
+            }
+            (Some(soc), _) => soc,
+        };
+        self.last_update = Some(now);
+
+        if status.current.unsigned_abs() < REST_CURRENT {
+            let rest_since = *self.rest_since.get_or_insert(now);
+            if now.duration_since(rest_since) >= REST_TIME {
+                soc = ocv_soc(cell);
+            }
+        } else {
+            self.rest_since = None;
+        }
+        let soc = soc.clamp(0.0, 100.0);
+        self.soc = Some(soc);
+
+        if let Some(bms_soc) = status.bms_soc {
+            self.check_plausibility(soc, bms_soc, now);
+        }
+        self.save(soc);
+        soc as u8
+    }
+
+    fn check_plausibility(&mut self, soc: f32, bms_soc: u8, now: Instant) {
+        let divergence = (soc - bms_soc as f32).abs();
+        if divergence < DIVERGENCE_CLEAR {
+            self.divergent_since = None;
+            fault::clear(FaultCode::SocPlausibility);
+        } else if divergence > DIVERGENCE_RAISE {
+            let since = *self.divergent_since.get_or_insert(now);
+            if now.duration_since(since) >= DIVERGENCE_TIME
+                && fault::raise(FaultCode::SocPlausibility)
+            {
+                warn!("SOC estimate {}% but BMS reports {}%", soc as u8, bms_soc);
+            }
+        }
+    }
+
+    fn save(&mut self, soc: f32) {
+        if self
+            .saved
+            .is_some_and(|saved| (soc - saved).abs() < SAVE_STEP)
+        {
+            return;
+        }
+        self.saved = Some(soc);
+        config::update_pending(|settings| settings.soc = Some((soc * 10.0) as u16));
+    }
+}
@@ -0,0 +1,50 @@
+use embassy_stm32::can::{filter::Mask32, Can, Fifo};
+use embassy_time::{with_timeout, Duration};
+use log::info;
+
+use crate::config::SUPPORTED_BITRATES;
+
+// Automatic bitrate detection: the controller listens in silent mode with each
+// candidate bitrate until valid frames are received, nothing is sent meanwhile
+
+// time to listen with one bitrate, longer than the period of the slowest node
+const LISTEN_TIME: Duration = Duration::from_millis(300);
+// frames received in a row without an error
+const VALID_FRAMES: u8 = 2;
+// the start-up is delayed by at most ROUNDS * candidates * LISTEN_TIME
+const ROUNDS: u8 = 3;
+
+// try the preferred bitrate first, the caller leaves the silent mode
+pub async fn detect(can: &mut Can<'static>, preferred: u32) -> Option<u32> {
+    // frames are only received through an enabled filter bank
+    can.modify_filters()
+        .enable_bank(0, Fifo::Fifo0, Mask32::accept_all());
+    let candidates = core::iter::once(preferred).chain(
+        SUPPORTED_BITRATES
+            .iter()
+            .copied()
+            .filter(|bitrate| *bitrate != preferred),
+    );
+    for _ in 0..ROUNDS {
+        for bitrate in candidates.clone() {
+            can.modify_config().set_bitrate(bitrate).set_silent(true);
+            can.enable().await;
+            if with_timeout(LISTEN_TIME, valid_frames(can)).await.is_ok() {
+                info!("CAN bitrate {} kbit/s detected", bitrate / 1000);
+                return Some(bitrate);
+            }
+        }
+    }
+    None
+}
+
+async fn valid_frames(can: &mut Can<'static>) {
+    let mut valid = 0;
+    while valid < VALID_FRAMES {
+        match can.read().await {
+            Ok(_) => valid += 1,
+            // a wrong bitrate shows up as stuff, form and CRC errors
+            Err(_) => valid = 0,
+        }
+    }
+}
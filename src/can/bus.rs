@@ -0,0 +1,78 @@
+use embassy_stm32::can::{self, CanRx, CanTx, Id, Mailbox};
+use nuen_can::{
+    bus::{BusError, CanReceiver, CanTransmitter, Transmitted},
+    Frame,
+};
+
+// bxCAN driver for the CAN tasks, the frames are converted at the driver so the
+// rest of the firmware uses the frame of `nuen_can`
+
+pub struct BxCanTx(pub CanTx<'static>);
+
+pub struct BxCanRx(pub CanRx<'static>);
+
+fn to_bxcan(frame: &Frame) -> can::Frame {
+    if frame.is_extended() {
+        can::Frame::new_extended(frame.id(), frame.data()).unwrap()
+    } else {
+        can::Frame::new_standard(frame.id() as u16, frame.data()).unwrap()
+    }
+}
+
+fn from_bxcan(frame: &can::Frame) -> Frame {
+    match frame.id() {
+        Id::Standard(id) => Frame::new_standard(id.as_raw(), frame.data()),
+        Id::Extended(id) => Frame::new_extended(id.as_raw(), frame.data()),
+    }
+    .unwrap()
+}
+
+fn mailbox(slot: usize) -> Mailbox {
+    match slot {
+        0 => Mailbox::Mailbox0,
+        1 => Mailbox::Mailbox1,
+        _ => Mailbox::Mailbox2,
+    }
+}
+
+impl CanTransmitter for BxCanTx {
+    fn try_transmit(&mut self, frame: &Frame) -> Option<Transmitted> {
+        let status = self.0.try_write(&to_bxcan(frame)).ok()?;
+        Some(Transmitted {
+            slot: status.mailbox() as usize,
+            dequeued: status.dequeued_frame().map(from_bxcan),
+        })
+    }
+
+    fn abort(&mut self, slot: usize) -> bool {
+        self.0.abort(mailbox(slot))
+    }
+
+    fn is_idle(&self, slot: usize) -> bool {
+        self.0.is_idle(mailbox(slot))
+    }
+
+    async fn wait_slot(&mut self) {
+        self.0.flush_any().await;
+    }
+}
+
+impl CanReceiver for BxCanRx {
+    async fn receive(&mut self) -> Result<Frame, BusError> {
+        match self.0.read().await {
+            Ok(envelope) => Ok(from_bxcan(&envelope.frame)),
+            Err(e) => Err(match e {
+                can::BusError::Stuff => BusError::Stuff,
+                can::BusError::Form => BusError::Form,
+                can::BusError::Acknowledge => BusError::Acknowledge,
+                can::BusError::BitRecessive => BusError::BitRecessive,
+                can::BusError::BitDominant => BusError::BitDominant,
+                can::BusError::Crc => BusError::Crc,
+                can::BusError::Software => BusError::Software,
+                can::BusError::BusOff => BusError::BusOff,
+                can::BusError::BusPassive => BusError::BusPassive,
+                can::BusError::BusWarning => BusError::BusWarning,
+            }),
+        }
+    }
+}
@@ -0,0 +1,426 @@
+use defmt::Format;
+use embassy_time::{Duration, Instant};
+use heapless::{Deque, Vec};
+use log::warn;
+use nuen_can::Frame;
+
+use super::supervision::CanNode;
+
+mod sdo;
+
+use sdo::SdoClient;
+pub use sdo::{SdoError, SdoResult, SDO_MAX_REQUESTS};
+
+// CANopen master (CiA 301) for devices with the predefined connection set
+
+// function codes of the predefined connection set, the node ID is added to them
+pub const COB_NMT: u16 = 0x000;
+pub const COB_EMCY: u16 = 0x080;
+pub const COB_TPDO1: u16 = 0x180;
+pub const COB_RPDO1: u16 = 0x200;
+pub const COB_SDO_TX: u16 = 0x580;
+pub const COB_SDO_RX: u16 = 0x600;
+pub const COB_HEARTBEAT: u16 = 0x700;
+
+// object dictionary entries used to configure a node
+pub const OD_HEARTBEAT_PRODUCER: u16 = 0x1017;
+const OD_RPDO_COMMUNICATION: u16 = 0x1400;
+const OD_RPDO_MAPPING: u16 = 0x1600;
+const OD_TPDO_COMMUNICATION: u16 = 0x1800;
+const OD_TPDO_MAPPING: u16 = 0x1A00;
+
+// a PDO with this COB-ID bit set is disabled
+const PDO_DISABLED: u32 = 0x8000_0000;
+// event-driven PDO, sent on change or by the event timer
+const TRANSMISSION_ASYNC: u8 = 0xFF;
+
+const MAX_NODES: usize = 4;
+const OUTBOX_SIZE: usize = 16;
+
+pub struct CanOpenConfig {
+    pub node_id: u8,
+    // handler task which receives the frames of the node
+    pub owner: CanNode,
+    pub heartbeat_timeout: Duration,
+}
+
+// adjust the node IDs to the devices used on the harness
+pub const CANOPEN_CONFIG: [CanOpenConfig; 1] = [CanOpenConfig {
+    node_id: 0x01,
+    owner: CanNode::Motor,
+    heartbeat_timeout: Duration::from_millis(300),
+}];
+
+// COB-ID of a function code of the configured node handled by `owner`
+pub const fn cob_id(function: u16, owner: CanNode) -> u32 {
+    let mut index = 0;
+    while index < CANOPEN_CONFIG.len() {
+        if CANOPEN_CONFIG[index].owner as u8 == owner as u8 {
+            return (function + CANOPEN_CONFIG[index].node_id as u16) as u32;
+        }
+        index += 1;
+    }
+    panic!("no CANopen node for the handler");
+}
+
+// return the handler of a frame sent by a configured CANopen node
+pub fn owner_of(frame: &Frame) -> Option<CanNode> {
+    if frame.is_extended() {
+        return None;
+    }
+    let id = frame.id() as u16;
+    let node_id = (id & 0x7F) as u8;
+    let function = id & 0x780;
+    let from_node = function == COB_EMCY
+        || (COB_TPDO1..COB_SDO_TX).contains(&function) && function & 0x80 != 0
+        || function == COB_SDO_TX
+        || function == COB_HEARTBEAT;
+    if !from_node {
+        return None;
+    }
+    CANOPEN_CONFIG
+        .iter()
+        .find(|config| config.node_id == node_id)
+        .map(|config| config.owner)
+}
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NmtCommand {
+    Start = 0x01,
+    Stop = 0x02,
+    ResetCommunication = 0x82,
+}
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeState {
+    BootUp,
+    Stopped,
+    Operational,
+    PreOperational,
+}
+
+impl NodeState {
+    fn from_raw(state: u8) -> Option<Self> {
+        match state & 0x7F {
+            0x00 => Some(NodeState::BootUp),
+            0x04 => Some(NodeState::Stopped),
+            0x05 => Some(NodeState::Operational),
+            0x7F => Some(NodeState::PreOperational),
+            _ => None,
+        }
+    }
+}
+
+pub struct PdoEntry {
+    pub index: u16,
+    pub subindex: u8,
+    pub bits: u8,
+}
+
+// content of a PDO, the entries are packed in order starting with the least significant bit
+pub struct PdoMap {
+    // PDO number 1..=4
+    pub number: u8,
+    pub entries: &'static [PdoEntry],
+}
+
+impl PdoMap {
+    // extract the value of an object from the PDO data
+    pub fn value(&self, data: &[u8], index: u16, subindex: u8) -> Option<u32> {
+        let mut raw = [0x00; 8];
+        let len = data.len().min(8);
+        raw[..len].copy_from_slice(&data[..len]);
+        let raw = u64::from_le_bytes(raw);
+        let mut offset = 0;
+        for entry in self.entries.iter() {
+            if offset + entry.bits as usize > len * 8 {
+                return None;
+            }
+            if entry.index == index && entry.subindex == subindex {
+                return Some(
+                    (raw.checked_shr(offset as u32).unwrap_or(0) & mask(entry.bits)) as u32,
+                );
+            }
+            offset += entry.bits as usize;
+        }
+        None
+    }
+
+    // pack one value per entry into the PDO data, the entries must fit in 8 bytes
+    pub fn encode(&self, values: &[u32]) -> Result<Vec<u8, 8>, SdoError> {
+        if self.bits() > 64 {
+            return Err(SdoError::InvalidLength);
+        }
+        let mut raw = 0u64;
+        let mut offset = 0;
+        for (entry, value) in self.entries.iter().zip(values.iter()) {
+            raw |= (*value as u64 & mask(entry.bits))
+                .checked_shl(offset)
+                .unwrap_or(0);
+            offset += entry.bits as u32;
+        }
+        Vec::from_slice(&raw.to_le_bytes()[..offset.div_ceil(8) as usize])
+            .map_err(|_| SdoError::InvalidLength)
+    }
+
+    // SDO requests queued by `CanOpen::map_tpdo` and `CanOpen::map_rpdo`
+    pub const fn tpdo_requests(&self) -> usize {
+        self.entries.len() + 6
+    }
+
+    pub const fn rpdo_requests(&self) -> usize {
+        self.entries.len() + 5
+    }
+
+    fn offset(&self) -> u16 {
+        (self.number.clamp(1, 4) as u16 - 1) * 0x100
+    }
+
+    fn bits(&self) -> u32 {
+        self.entries.iter().map(|entry| entry.bits as u32).sum()
+    }
+}
+
+fn mask(bits: u8) -> u64 {
+    if bits >= 64 {
+        u64::MAX
+    } else {
+        (1u64 << bits) - 1
+    }
+}
+
+pub enum CanOpenEvent {
+    // the node started or was reset, it has to be configured again
+    BootUp(u8),
+    StateChanged(u8, NodeState),
+    HeartbeatLost(u8),
+    Emergency {
+        node: u8,
+        code: u16,
+        register: u8,
+    },
+    Sdo(SdoResult),
+    // PDO sent by the node, `number` is the TPDO number on the node
+    Pdo {
+        node: u8,
+        number: u8,
+        data: Vec<u8, 8>,
+    },
+}
+
+// frames produced by the CANopen layer, waiting to be sent
+pub struct Outbox {
+    frames: Deque<Frame, OUTBOX_SIZE>,
+}
+
+impl Outbox {
+    fn push(&mut self, cob_id: u16, data: &[u8]) {
+        if let Some(frame) = Frame::new_standard(cob_id, data) {
+            if self.frames.push_back(frame).is_err() {
+                warn!("CANopen outbox is full, drop COB-ID {:#x}", cob_id);
+            }
+        }
+    }
+}
+
+// heartbeat consumer of a node
+struct RemoteNode {
+    node_id: u8,
+    timeout: Duration,
+    // None until the first heartbeat and after a timeout
+    state: Option<NodeState>,
+    last_seen: Instant,
+}
+
+pub struct CanOpen {
+    nodes: Vec<RemoteNode, MAX_NODES>,
+    sdo: SdoClient,
+    outbox: Outbox,
+}
+
+impl CanOpen {
+    pub fn init() -> Self {
+        CanOpen {
+            nodes: Vec::new(),
+            sdo: SdoClient::init(),
+            outbox: Outbox {
+                frames: Deque::new(),
+            },
+        }
+    }
+
+    // consume the heartbeat of a node, frames of unknown nodes are ignored
+    pub fn add_node(&mut self, node_id: u8, heartbeat_timeout: Duration) {
+        let node = RemoteNode {
+            node_id,
+            timeout: heartbeat_timeout,
+            state: None,
+            last_seen: Instant::now(),
+        };
+        if self.nodes.push(node).is_err() {
+            warn!("CANopen node list is full");
+        }
+    }
+
+    pub fn node_state(&self, node_id: u8) -> Option<NodeState> {
+        self.node(node_id)?.state
+    }
+
+    pub fn next_frame(&mut self) -> Option<Frame> {
+        self.outbox.frames.pop_front()
+    }
+
+    pub fn nmt(&mut self, command: NmtCommand, node_id: u8) {
+        self.outbox.push(COB_NMT, &[command as u8, node_id]);
+    }
+
+    // the transfers are done in order, the result is reported by a `CanOpenEvent::Sdo`
+    pub fn sdo_read(&mut self, node_id: u8, index: u16, subindex: u8) -> Result<(), SdoError> {
+        self.sdo.read(node_id, index, subindex)
+    }
+
+    pub fn sdo_write(
+        &mut self,
+        node_id: u8,
+        index: u16,
+        subindex: u8,
+        data: &[u8],
+    ) -> Result<(), SdoError> {
+        self.sdo.write(node_id, index, subindex, data)
+    }
+
+    pub fn sdo_idle(&self) -> bool {
+        self.sdo.is_idle()
+    }
+
+    // configure a PDO sent by the node, with the event timer in ms (0 to disable)
+    pub fn map_tpdo(
+        &mut self,
+        node_id: u8,
+        map: &PdoMap,
+        event_timer: u16,
+    ) -> Result<(), SdoError> {
+        let cob_id = (COB_TPDO1 + map.offset() + node_id as u16) as u32;
+        let communication = OD_TPDO_COMMUNICATION + map.number as u16 - 1;
+        self.map_pdo(
+            node_id,
+            communication,
+            OD_TPDO_MAPPING + map.number as u16 - 1,
+            cob_id,
+            map,
+        )?;
+        self.sdo
+            .write(node_id, communication, 5, &event_timer.to_le_bytes())?;
+        self.sdo
+            .write(node_id, communication, 1, &cob_id.to_le_bytes())
+    }
+
+    // configure a PDO received by the node
+    pub fn map_rpdo(&mut self, node_id: u8, map: &PdoMap) -> Result<(), SdoError> {
+        let cob_id = (COB_RPDO1 + map.offset() + node_id as u16) as u32;
+        let communication = OD_RPDO_COMMUNICATION + map.number as u16 - 1;
+        self.map_pdo(
+            node_id,
+            communication,
+            OD_RPDO_MAPPING + map.number as u16 - 1,
+            cob_id,
+            map,
+        )?;
+        self.sdo
+            .write(node_id, communication, 1, &cob_id.to_le_bytes())
+    }
+
+    // send a PDO to the node, with one value per entry of the map
+    pub fn send_rpdo(&mut self, node_id: u8, map: &PdoMap, values: &[u32]) -> Result<(), SdoError> {
+        let data = map.encode(values)?;
+        self.outbox
+            .push(COB_RPDO1 + map.offset() + node_id as u16, &data);
+        Ok(())
+    }
+
+    pub fn receive(&mut self, frame: &Frame, now: Instant) -> Option<CanOpenEvent> {
+        if frame.is_extended() {
+            return None;
+        }
+        let id = frame.id() as u16;
+        let node_id = (id & 0x7F) as u8;
+        let data = frame.data();
+        self.node(node_id)?;
+        match id & 0x780 {
+            COB_HEARTBEAT if !data.is_empty() => self.on_heartbeat(node_id, data[0], now),
+            COB_SDO_TX => self
+                .sdo
+                .on_response(node_id, data, now, &mut self.outbox)
+                .map(CanOpenEvent::Sdo),
+            COB_EMCY if data.len() >= 3 => Some(CanOpenEvent::Emergency {
+                node: node_id,
+                code: u16::from_le_bytes([data[0], data[1]]),
+                register: data[2],
+            }),
+            function if (COB_TPDO1..COB_SDO_TX).contains(&function) && function & 0x80 != 0 => {
+                Some(CanOpenEvent::Pdo {
+                    node: node_id,
+                    number: ((function - COB_TPDO1) / 0x100 + 1) as u8,
+                    data: Vec::from_slice(data).unwrap(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    // check the heartbeat and SDO timers, must be called periodically until it returns None
+    pub fn poll(&mut self, now: Instant) -> Option<CanOpenEvent> {
+        for node in self.nodes.iter_mut() {
+            if node.state.is_some() && now.duration_since(node.last_seen) > node.timeout {
+                node.state = None;
+                return Some(CanOpenEvent::HeartbeatLost(node.node_id));
+            }
+        }
+        self.sdo.poll(now, &mut self.outbox).map(CanOpenEvent::Sdo)
+    }
+
+    fn node(&self, node_id: u8) -> Option<&RemoteNode> {
+        self.nodes.iter().find(|node| node.node_id == node_id)
+    }
+
+    fn on_heartbeat(&mut self, node_id: u8, state: u8, now: Instant) -> Option<CanOpenEvent> {
+        let node = self.nodes.iter_mut().find(|node| node.node_id == node_id)?;
+        let state = NodeState::from_raw(state)?;
+        node.last_seen = now;
+        let previous = node.state.replace(state);
+        match state {
+            NodeState::BootUp => Some(CanOpenEvent::BootUp(node_id)),
+            _ if previous != Some(state) => Some(CanOpenEvent::StateChanged(node_id, state)),
+            _ => None,
+        }
+    }
+
+    // disable the PDO, write the mapping and enable the mapping again,
+    // the PDO itself is enabled by the caller after the other parameters
+    fn map_pdo(
+        &mut self,
+        node_id: u8,
+        communication: u16,
+        mapping: u16,
+        cob_id: u32,
+        map: &PdoMap,
+    ) -> Result<(), SdoError> {
+        if map.bits() > 64 {
+            return Err(SdoError::InvalidLength);
+        }
+        let disabled = cob_id | PDO_DISABLED;
+        self.sdo
+            .write(node_id, communication, 1, &disabled.to_le_bytes())?;
+        self.sdo.write(node_id, mapping, 0, &[0])?;
+        for (subindex, entry) in map.entries.iter().enumerate() {
+            let object =
+                (entry.index as u32) << 16 | (entry.subindex as u32) << 8 | entry.bits as u32;
+            self.sdo
+                .write(node_id, mapping, subindex as u8 + 1, &object.to_le_bytes())?;
+        }
+        self.sdo
+            .write(node_id, mapping, 0, &[map.entries.len() as u8])?;
+        self.sdo
+            .write(node_id, communication, 2, &[TRANSMISSION_ASYNC])
+    }
+}
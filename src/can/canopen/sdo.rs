@@ -0,0 +1,337 @@
+use defmt::Format;
+use embassy_time::{Duration, Instant};
+use heapless::{Deque, Vec};
+
+use super::{Outbox, COB_SDO_RX};
+
+// SDO client (CiA 301), expedited and segmented transfers, one transfer at a time
+pub const SDO_MAX_LEN: usize = 64;
+
+// requests queued at a time, enough for the whole configuration of a node
+pub const SDO_MAX_REQUESTS: usize = 48;
+// time to wait for each response of the server
+const SDO_TIMEOUT: Duration = Duration::from_millis(500);
+
+// client command specifiers
+const CCS_DOWNLOAD_SEGMENT: u8 = 0;
+const CCS_INITIATE_DOWNLOAD: u8 = 1;
+const CCS_INITIATE_UPLOAD: u8 = 2;
+const CCS_UPLOAD_SEGMENT: u8 = 3;
+const CS_ABORT: u8 = 4;
+// server command specifiers
+const SCS_UPLOAD_SEGMENT: u8 = 0;
+const SCS_DOWNLOAD_SEGMENT: u8 = 1;
+const SCS_INITIATE_UPLOAD: u8 = 2;
+const SCS_INITIATE_DOWNLOAD: u8 = 3;
+
+// abort codes sent by the client
+const ABORT_TOGGLE: u32 = 0x0503_0000;
+const ABORT_TIMEOUT: u32 = 0x0504_0000;
+const ABORT_COMMAND: u32 = 0x0504_0001;
+const ABORT_MEMORY: u32 = 0x0504_0005;
+
+pub type SdoData = Vec<u8, SDO_MAX_LEN>;
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SdoError {
+    // the request queue is full
+    Busy,
+    InvalidLength,
+    Timeout,
+    // abort code sent by the server
+    Aborted(u32),
+    Toggle,
+    InvalidResponse,
+    Overflow,
+}
+
+#[derive(Clone)]
+enum SdoAccess {
+    Read,
+    Write(SdoData),
+}
+
+#[derive(Clone)]
+struct SdoRequest {
+    node: u8,
+    index: u16,
+    subindex: u8,
+    access: SdoAccess,
+}
+
+impl SdoRequest {
+    // multiplexer of the initiate frames: index and subindex
+    fn mux(&self) -> [u8; 3] {
+        let index = self.index.to_le_bytes();
+        [index[0], index[1], self.subindex]
+    }
+}
+
+pub struct SdoResult {
+    pub node: u8,
+    pub index: u16,
+    pub subindex: u8,
+    // the data read from the server, empty for a write
+    pub result: Result<SdoData, SdoError>,
+}
+
+#[derive(Clone, Copy)]
+enum Phase {
+    InitiateDownload,
+    // end offset of the segment waiting for its confirmation
+    DownloadSegment { end: usize, toggle: bool },
+    InitiateUpload,
+    UploadSegment { toggle: bool },
+}
+
+struct Active {
+    request: SdoRequest,
+    phase: Phase,
+    received: SdoData,
+    deadline: Instant,
+}
+
+enum Step {
+    Continue(Phase),
+    Done(SdoData),
+    // the error is reported and the abort code is sent to the server
+    Fail(SdoError, u32),
+}
+
+pub struct SdoClient {
+    requests: Deque<SdoRequest, SDO_MAX_REQUESTS>,
+    active: Option<Active>,
+}
+
+impl SdoClient {
+    pub fn init() -> Self {
+        SdoClient {
+            requests: Deque::new(),
+            active: None,
+        }
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.active.is_none() && self.requests.is_empty()
+    }
+
+    pub fn read(&mut self, node: u8, index: u16, subindex: u8) -> Result<(), SdoError> {
+        self.queue(SdoRequest {
+            node,
+            index,
+            subindex,
+            access: SdoAccess::Read,
+        })
+    }
+
+    pub fn write(
+        &mut self,
+        node: u8,
+        index: u16,
+        subindex: u8,
+        data: &[u8],
+    ) -> Result<(), SdoError> {
+        if data.is_empty() {
+            return Err(SdoError::InvalidLength);
+        }
+        let data = Vec::from_slice(data).map_err(|_| SdoError::InvalidLength)?;
+        self.queue(SdoRequest {
+            node,
+            index,
+            subindex,
+            access: SdoAccess::Write(data),
+        })
+    }
+
+    fn queue(&mut self, request: SdoRequest) -> Result<(), SdoError> {
+        self.requests.push_back(request).map_err(|_| SdoError::Busy)
+    }
+
+    // handle a response of the server, return the result of a finished transfer
+    pub fn on_response(
+        &mut self,
+        node: u8,
+        data: &[u8],
+        now: Instant,
+        outbox: &mut Outbox,
+    ) -> Option<SdoResult> {
+        let active = self.active.as_mut()?;
+        if active.request.node != node || data.len() < 8 {
+            return None;
+        }
+        let command = data[0];
+        if command >> 5 == CS_ABORT {
+            let code = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+            return self.finish(Err(SdoError::Aborted(code)), now, outbox);
+        }
+        let mux_ok = data[1..4] == active.request.mux();
+
+        let step = match (active.phase, command >> 5) {
+            (Phase::InitiateDownload, SCS_INITIATE_DOWNLOAD) if mux_ok => {
+                match &active.request.access {
+                    SdoAccess::Write(write) if write.len() > 4 => {
+                        let end = send_segment(node, write, 0, false, outbox);
+                        Step::Continue(Phase::DownloadSegment { end, toggle: false })
+                    }
+                    _ => Step::Done(Vec::new()),
+                }
+            }
+            (Phase::DownloadSegment { end, toggle }, SCS_DOWNLOAD_SEGMENT) => {
+                match &active.request.access {
+                    _ if (command & 0x10 != 0) != toggle => {
+                        Step::Fail(SdoError::Toggle, ABORT_TOGGLE)
+                    }
+                    SdoAccess::Write(write) if end < write.len() => {
+                        let end = send_segment(node, write, end, !toggle, outbox);
+                        Step::Continue(Phase::DownloadSegment {
+                            end,
+                            toggle: !toggle,
+                        })
+                    }
+                    _ => Step::Done(Vec::new()),
+                }
+            }
+            (Phase::InitiateUpload, SCS_INITIATE_UPLOAD) if mux_ok => {
+                let size_indicated = command & 0x01 != 0;
+                if command & 0x02 != 0 {
+                    // expedited, the data is in the response
+                    let len = if size_indicated {
+                        4 - ((command >> 2) & 0x03) as usize
+                    } else {
+                        4
+                    };
+                    Step::Done(Vec::from_slice(&data[4..4 + len]).unwrap())
+                } else if size_indicated
+                    && u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize
+                        > SDO_MAX_LEN
+                {
+                    Step::Fail(SdoError::Overflow, ABORT_MEMORY)
+                } else {
+                    request_segment(node, false, outbox);
+                    Step::Continue(Phase::UploadSegment { toggle: false })
+                }
+            }
+            (Phase::UploadSegment { toggle }, SCS_UPLOAD_SEGMENT) => {
+                let len = 7 - ((command >> 1) & 0x07) as usize;
+                if (command & 0x10 != 0) != toggle {
+                    Step::Fail(SdoError::Toggle, ABORT_TOGGLE)
+                } else if active
+                    .received
+                    .extend_from_slice(&data[1..1 + len])
+                    .is_err()
+                {
+                    Step::Fail(SdoError::Overflow, ABORT_MEMORY)
+                } else if command & 0x01 != 0 {
+                    Step::Done(active.received.clone())
+                } else {
+                    request_segment(node, !toggle, outbox);
+                    Step::Continue(Phase::UploadSegment { toggle: !toggle })
+                }
+            }
+            _ => Step::Fail(SdoError::InvalidResponse, ABORT_COMMAND),
+        };
+
+        match step {
+            Step::Continue(phase) => {
+                active.phase = phase;
+                active.deadline = now + SDO_TIMEOUT;
+                None
+            }
+            Step::Done(data) => self.finish(Ok(data), now, outbox),
+            Step::Fail(e, code) => {
+                send_abort(&active.request, code, outbox);
+                self.finish(Err(e), now, outbox)
+            }
+        }
+    }
+
+    // check the response timeout and start the next transfer, must be called periodically
+    pub fn poll(&mut self, now: Instant, outbox: &mut Outbox) -> Option<SdoResult> {
+        match &self.active {
+            Some(active) if now > active.deadline => {
+                send_abort(&active.request, ABORT_TIMEOUT, outbox);
+                self.finish(Err(SdoError::Timeout), now, outbox)
+            }
+            Some(_) => None,
+            None => {
+                self.start_next(now, outbox);
+                None
+            }
+        }
+    }
+
+    fn finish(
+        &mut self,
+        result: Result<SdoData, SdoError>,
+        now: Instant,
+        outbox: &mut Outbox,
+    ) -> Option<SdoResult> {
+        let active = self.active.take()?;
+        self.start_next(now, outbox);
+        Some(SdoResult {
+            node: active.request.node,
+            index: active.request.index,
+            subindex: active.request.subindex,
+            result,
+        })
+    }
+
+    fn start_next(&mut self, now: Instant, outbox: &mut Outbox) {
+        let Some(request) = self.requests.pop_front() else {
+            return;
+        };
+        let mux = request.mux();
+        let mut payload = [0x00; 8];
+        payload[1..4].copy_from_slice(&mux);
+        let phase = match &request.access {
+            SdoAccess::Read => {
+                payload[0] = CCS_INITIATE_UPLOAD << 5;
+                Phase::InitiateUpload
+            }
+            SdoAccess::Write(data) if data.len() <= 4 => {
+                // expedited with the size indicated
+                payload[0] = CCS_INITIATE_DOWNLOAD << 5 | ((4 - data.len()) as u8) << 2 | 0x03;
+                payload[4..4 + data.len()].copy_from_slice(data);
+                Phase::InitiateDownload
+            }
+            SdoAccess::Write(data) => {
+                payload[0] = CCS_INITIATE_DOWNLOAD << 5 | 0x01;
+                payload[4..].copy_from_slice(&(data.len() as u32).to_le_bytes());
+                Phase::InitiateDownload
+            }
+        };
+        outbox.push(COB_SDO_RX + request.node as u16, &payload);
+        self.active = Some(Active {
+            request,
+            phase,
+            received: Vec::new(),
+            deadline: now + SDO_TIMEOUT,
+        });
+    }
+}
+
+// send the segment starting at the offset, return its end offset
+fn send_segment(node: u8, data: &[u8], start: usize, toggle: bool, outbox: &mut Outbox) -> usize {
+    let end = (start + 7).min(data.len());
+    let unused = (7 - (end - start)) as u8;
+    let mut payload = [0x00; 8];
+    payload[0] =
+        CCS_DOWNLOAD_SEGMENT << 5 | (toggle as u8) << 4 | unused << 1 | (end == data.len()) as u8;
+    payload[1..1 + end - start].copy_from_slice(&data[start..end]);
+    outbox.push(COB_SDO_RX + node as u16, &payload);
+    end
+}
+
+fn request_segment(node: u8, toggle: bool, outbox: &mut Outbox) {
+    let mut payload = [0x00; 8];
+    payload[0] = CCS_UPLOAD_SEGMENT << 5 | (toggle as u8) << 4;
+    outbox.push(COB_SDO_RX + node as u16, &payload);
+}
+
+fn send_abort(request: &SdoRequest, code: u32, outbox: &mut Outbox) {
+    let mut payload = [0x00; 8];
+    payload[0] = CS_ABORT << 5;
+    payload[1..4].copy_from_slice(&request.mux());
+    payload[4..].copy_from_slice(&code.to_le_bytes());
+    outbox.push(COB_SDO_RX + request.node as u16, &payload);
+}
@@ -0,0 +1,62 @@
+use nuen_can::Frame;
+
+use crate::{
+    battery::{PACK_ADDRESSES, PACK_COUNT},
+    contactor::CONTACTOR_COMMAND,
+};
+
+// E2E protected messages of the VCU, the protection itself is in `nuen_can::e2e`
+pub use nuen_can::e2e::{E2eConfig, E2eEvent};
+
+// status frame of the BMS, the low byte is the address of the pack
+const BMS_STATUS: u32 = 0x1806E500;
+
+// one frame per pack in `PACK_ADDRESSES`, the address is shifted into the ID and the
+// data ID counts up from the one of the first pack
+const fn per_pack(
+    id: u32,
+    shift: u32,
+    data_id: u16,
+    max_delta_counter: u8,
+) -> [E2eConfig; PACK_COUNT] {
+    let mut configs = [E2eConfig {
+        id,
+        data_id,
+        max_delta_counter,
+    }; PACK_COUNT];
+    let mut pack = 0;
+    while pack < PACK_COUNT {
+        configs[pack].id = id | (PACK_ADDRESSES[pack] as u32) << shift;
+        configs[pack].data_id = data_id + pack as u16;
+        pack += 1;
+    }
+    configs
+}
+
+// safety relevant frames sent by the VCU: the contactor command of each pack, the
+// CANopen motor controller does not check a counter or CRC
+pub const E2E_TX: [E2eConfig; PACK_COUNT] = per_pack(CONTACTOR_COMMAND, 8, 0x0103, 1);
+
+// safety relevant frames received from the BMS: the status of each pack
+pub const E2E_RX: [E2eConfig; PACK_COUNT] = per_pack(BMS_STATUS, 0, 0x0201, 2);
+
+// frames sent by this node are protected before they are queued
+pub struct E2eProtector(nuen_can::e2e::E2eProtector<{ E2E_TX.len() }>);
+
+impl E2eProtector {
+    pub fn init() -> Self {
+        E2eProtector(nuen_can::e2e::E2eProtector::init(&E2E_TX))
+    }
+
+    // write counter and CRC into the frame if it is configured for protection
+    pub fn protect(&mut self, frame: Frame) -> Frame {
+        let mut data = [0x00; 8];
+        data[..frame.data().len()].copy_from_slice(frame.data());
+        if !self.0.protect(frame.id(), &mut data) {
+            return frame;
+        }
+        Frame::new(frame.id(), frame.is_extended(), &data).unwrap_or(frame)
+    }
+}
+
+pub type E2eChecker = nuen_can::e2e::E2eChecker<{ E2E_RX.len() }>;
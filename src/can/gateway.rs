@@ -0,0 +1,86 @@
+use defmt::Format;
+use nuen_can::Frame;
+
+// first filter bank of CAN2, the banks below it belong to CAN1
+pub const CAN2_FIRST_BANK: u8 = 14;
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CanBus {
+    // CAN1: BMS, motor controller and OBC
+    Powertrain,
+    // CAN2: display and body electronics
+    Body,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum GatewayAction {
+    Forward,
+    // replace the identifier bits selected by the mask, keep the others
+    Rewrite(u32),
+    Block,
+}
+
+pub struct GatewayRule {
+    // bus the frame was seen on, it is forwarded to the other one
+    pub from: CanBus,
+    pub id: u32,
+    pub mask: u32,
+    pub action: GatewayAction,
+}
+
+const EXACT: u32 = 0x1FFF_FFFF;
+// priority bits and source address of a J1939 identifier are ignored
+const PGN_MASK: u32 = 0x03FF_FF00;
+
+// the first matching rule decides, frames without a rule stay on their bus
+pub const GATEWAY_RULES: [GatewayRule; 5] = [
+    // the torque and drive enable commands never leave the powertrain bus
+    GatewayRule {
+        from: CanBus::Powertrain,
+        id: 0x0C0005EF,
+        mask: 0x1FF0_FFFF,
+        action: GatewayAction::Block,
+    },
+    // segment LCD frames (PGN 0xF810)
+    GatewayRule {
+        from: CanBus::Powertrain,
+        id: 0x00F8_1000,
+        mask: PGN_MASK,
+        action: GatewayAction::Forward,
+    },
+    // messages to the display address 0xF9
+    GatewayRule {
+        from: CanBus::Powertrain,
+        id: 0x0000_F900,
+        mask: PGN_MASK,
+        action: GatewayAction::Forward,
+    },
+    // BMS status, the display expects it from the VCU address 0x27
+    GatewayRule {
+        from: CanBus::Powertrain,
+        id: 0x1806_E5F4,
+        mask: EXACT,
+        action: GatewayAction::Rewrite(0x1806_E527),
+    },
+    // display status for the nodes on the powertrain bus
+    GatewayRule {
+        from: CanBus::Body,
+        id: 0x18FF_0A28,
+        mask: EXACT,
+        action: GatewayAction::Forward,
+    },
+];
+
+// return the frame to send on the other bus, if any
+pub fn route(from: CanBus, frame: &Frame) -> Option<Frame> {
+    let id = frame.id();
+    let rule = GATEWAY_RULES
+        .iter()
+        .find(|rule| rule.from == from && id & rule.mask == rule.id & rule.mask)?;
+    let id = match rule.action {
+        GatewayAction::Forward => return Some(*frame),
+        GatewayAction::Rewrite(new_id) => (new_id & rule.mask) | (id & !rule.mask),
+        GatewayAction::Block => return None,
+    };
+    Frame::new(id, frame.is_extended(), frame.data())
+}
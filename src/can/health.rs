@@ -0,0 +1,175 @@
+use core::cell::RefCell;
+
+use cortex_m::interrupt::Mutex;
+use defmt::Format;
+use embassy_stm32::pac;
+use nuen_can::bus::BusError;
+
+use crate::println;
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorState {
+    Active,
+    Warning,
+    Passive,
+    BusOff,
+}
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LastError {
+    None,
+    Stuff,
+    Form,
+    Acknowledge,
+    BitRecessive,
+    BitDominant,
+    Crc,
+    Software,
+}
+
+impl From<u8> for LastError {
+    fn from(lec: u8) -> Self {
+        match lec {
+            1 => LastError::Stuff,
+            2 => LastError::Form,
+            3 => LastError::Acknowledge,
+            4 => LastError::BitRecessive,
+            5 => LastError::BitDominant,
+            6 => LastError::Crc,
+            7 => LastError::Software,
+            _ => LastError::None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct CanStats {
+    pub state: ErrorState,
+    pub tec: u8,
+    pub rec: u8,
+    pub last_error: LastError,
+    pub warning_count: u32,
+    pub passive_count: u32,
+    pub bus_off_count: u32,
+    pub recovery_count: u32,
+    pub rx_error_count: u32,
+}
+
+impl CanStats {
+    const fn new() -> Self {
+        CanStats {
+            state: ErrorState::Active,
+            tec: 0,
+            rec: 0,
+            last_error: LastError::None,
+            warning_count: 0,
+            passive_count: 0,
+            bus_off_count: 0,
+            recovery_count: 0,
+            rx_error_count: 0,
+        }
+    }
+}
+
+static CAN_STATS: Mutex<RefCell<CanStats>> = Mutex::new(RefCell::new(CanStats::new()));
+
+pub fn stats() -> CanStats {
+    cortex_m::interrupt::free(|cs| *CAN_STATS.borrow(cs).borrow())
+}
+
+// called from the RX path when the driver reports an error instead of a frame
+pub fn record_bus_error(error: BusError) {
+    cortex_m::interrupt::free(|cs| {
+        let mut stats = CAN_STATS.borrow(cs).borrow_mut();
+        stats.rx_error_count = stats.rx_error_count.wrapping_add(1);
+        if let Some(last_error) = match error {
+            BusError::Stuff => Some(LastError::Stuff),
+            BusError::Form => Some(LastError::Form),
+            BusError::Acknowledge => Some(LastError::Acknowledge),
+            BusError::BitRecessive => Some(LastError::BitRecessive),
+            BusError::BitDominant => Some(LastError::BitDominant),
+            BusError::Crc => Some(LastError::Crc),
+            BusError::Software => Some(LastError::Software),
+            _ => None,
+        } {
+            stats.last_error = last_error;
+        }
+    });
+}
+
+pub fn print_stats() {
+    let stats = stats();
+    println!("CAN state: {:?}", stats.state);
+    println!("\tTEC: {} \t REC: {}", stats.tec, stats.rec);
+    println!("\tlast error: {:?}", stats.last_error);
+    println!("\terror warning: {}", stats.warning_count);
+    println!("\terror passive: {}", stats.passive_count);
+    println!("\tbus off: {}", stats.bus_off_count);
+    println!("\trecovered: {}", stats.recovery_count);
+    println!("\trx errors: {}", stats.rx_error_count);
+}
+
+pub enum HealthEvent {
+    StateChanged(ErrorState, ErrorState),
+}
+
+pub struct CanHealth {
+    regs: pac::can::Can,
+    state: ErrorState,
+}
+
+impl CanHealth {
+    pub fn init(regs: pac::can::Can) -> Self {
+        // the hardware leaves bus-off by itself after 128 occurrences of 11
+        // recessive bits, which is the backoff of the standard, so recovery
+        // never depends on this task being scheduled
+        regs.mcr().modify(|w| w.set_abom(true));
+        CanHealth {
+            regs,
+            state: ErrorState::Active,
+        }
+    }
+
+    // sample the error status register and return what the monitor task has to do
+    pub fn update(&mut self) -> Option<HealthEvent> {
+        let esr = self.regs.esr().read();
+        let state = if esr.boff() {
+            ErrorState::BusOff
+        } else if esr.epvf() {
+            ErrorState::Passive
+        } else if esr.ewgf() {
+            ErrorState::Warning
+        } else {
+            ErrorState::Active
+        };
+
+        let previous = self.state;
+        cortex_m::interrupt::free(|cs| {
+            let mut stats = CAN_STATS.borrow(cs).borrow_mut();
+            stats.tec = esr.tec();
+            stats.rec = esr.rec();
+            let lec = LastError::from(esr.lec().to_bits());
+            if lec != LastError::None {
+                stats.last_error = lec;
+            }
+            if state != previous {
+                match state {
+                    ErrorState::Active => {}
+                    ErrorState::Warning => stats.warning_count += 1,
+                    ErrorState::Passive => stats.passive_count += 1,
+                    ErrorState::BusOff => stats.bus_off_count += 1,
+                }
+                if previous == ErrorState::BusOff {
+                    stats.recovery_count += 1;
+                }
+            }
+            stats.state = state;
+        });
+
+        if state != previous {
+            self.state = state;
+            return Some(HealthEvent::StateChanged(previous, state));
+        }
+        None
+    }
+}
@@ -0,0 +1,90 @@
+use core::cell::RefCell;
+
+use cortex_m::interrupt::Mutex;
+use embassy_time::{Duration, Instant};
+use heapless::{Deque, Vec};
+use nuen_can::Frame;
+
+use crate::println;
+
+// frames requested from the command line, see `can send` and `can inject`
+const MAX_JOBS: usize = 8;
+const INJECT_QUEUE: usize = 8;
+// periodic frames are sent from a task with a 10ms cycle
+pub const MIN_PERIOD: Duration = Duration::from_millis(10);
+
+#[derive(Clone, Copy)]
+struct TxJob {
+    frame: Frame,
+    // None for a frame which is sent once
+    period: Option<Duration>,
+    next_at: Instant,
+}
+
+static JOBS: Mutex<RefCell<Vec<TxJob, MAX_JOBS>>> = Mutex::new(RefCell::new(Vec::new()));
+static INJECTED: Mutex<RefCell<Deque<Frame, INJECT_QUEUE>>> =
+    Mutex::new(RefCell::new(Deque::new()));
+
+// queue a frame for transmission, a periodic frame replaces an older job with the same ID
+pub fn send(frame: Frame, period: Option<Duration>) -> bool {
+    let id = frame.id();
+    cortex_m::interrupt::free(|cs| {
+        let mut jobs = JOBS.borrow(cs).borrow_mut();
+        if period.is_some() {
+            jobs.retain(|job| job.period.is_none() || job.frame.id() != id);
+        }
+        jobs.push(TxJob {
+            frame,
+            period: period.map(|period| period.max(MIN_PERIOD)),
+            next_at: Instant::now(),
+        })
+        .is_ok()
+    })
+}
+
+// stop the periodic frames with the given ID, or all of them
+pub fn stop(id: Option<u32>) {
+    cortex_m::interrupt::free(|cs| {
+        JOBS.borrow(cs)
+            .borrow_mut()
+            .retain(|job| id.is_some_and(|id| job.frame.id() != id));
+    });
+}
+
+// queue a frame for the RX path as if it was received from the bus
+pub fn inject(frame: Frame) -> bool {
+    cortex_m::interrupt::free(|cs| INJECTED.borrow(cs).borrow_mut().push_back(frame).is_ok())
+}
+
+// return the next frame which has to be transmitted now
+pub fn due_frame(now: Instant) -> Option<Frame> {
+    cortex_m::interrupt::free(|cs| {
+        let mut jobs = JOBS.borrow(cs).borrow_mut();
+        let index = jobs.iter().position(|job| now >= job.next_at)?;
+        let frame = jobs[index].frame;
+        match jobs[index].period {
+            Some(period) => jobs[index].next_at = now + period,
+            None => {
+                jobs.remove(index);
+            }
+        }
+        Some(frame)
+    })
+}
+
+pub fn injected_frame() -> Option<Frame> {
+    cortex_m::interrupt::free(|cs| INJECTED.borrow(cs).borrow_mut().pop_front())
+}
+
+pub fn print_jobs() {
+    let jobs = cortex_m::interrupt::free(|cs| JOBS.borrow(cs).borrow().clone());
+    println!("ID \t\t period ms \t data");
+    for job in jobs.iter().filter(|job| job.period.is_some()) {
+        println!(
+            "{:X} \t {} \t\t {:02X?}",
+            job.frame.id(),
+            job.period.map_or(0, |period| period.as_millis()),
+            job.frame.data()
+        );
+    }
+}
@@ -0,0 +1,45 @@
+// ISO 15765-2 channels of the VCU, the transport itself is in `nuen_can::isotp`
+pub use nuen_can::isotp::{IsoTp, IsoTpConfig};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum IsoTpUser {
+    Diagnostic,
+    Bms,
+}
+
+pub struct IsoTpChannel {
+    pub user: IsoTpUser,
+    pub config: IsoTpConfig,
+}
+
+pub const ISOTP_CONFIG: [IsoTpChannel; 2] = [
+    // diagnostic requests from the tester (physical addressing)
+    IsoTpChannel {
+        user: IsoTpUser::Diagnostic,
+        config: IsoTpConfig {
+            rx_id: 0x7E0,
+            tx_id: 0x7E8,
+            extended: false,
+            block_size: 0,
+            st_min: 0,
+        },
+    },
+    // multi-frame data from the BMS
+    IsoTpChannel {
+        user: IsoTpUser::Bms,
+        config: IsoTpConfig {
+            rx_id: 0x18DAF1F4,
+            tx_id: 0x18DAF4F1,
+            extended: true,
+            block_size: 8,
+            st_min: 1,
+        },
+    },
+];
+
+// return the channel which receives the given ID
+pub fn channel_of(id: u32) -> Option<usize> {
+    ISOTP_CONFIG
+        .iter()
+        .position(|channel| channel.config.rx_id == id)
+}
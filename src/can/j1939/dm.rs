@@ -0,0 +1,269 @@
+use core::cell::RefCell;
+
+use cortex_m::interrupt::Mutex;
+use defmt::Format;
+use embassy_time::{Duration, Instant};
+use heapless::{Deque, Vec};
+use log::warn;
+
+use super::{J1939Id, TransportError, ADDRESS_GLOBAL, J1939};
+use crate::fault::{self, FaultCode};
+
+// J1939-73 diagnostic messages: active (DM1) and previously active (DM2) DTCs
+pub const PGN_DM1: u32 = 0xFECA;
+pub const PGN_DM2: u32 = 0xFECB;
+
+const DM_PRIORITY: u8 = 6;
+const DM1_PERIOD: Duration = Duration::from_secs(1);
+
+const MAX_EXTERNAL: usize = 16;
+const MAX_DTCS: usize = FaultCode::ALL.len() + MAX_EXTERNAL;
+const MAX_REQUESTS: usize = 4;
+// occurrence count is 7 bits, 127 means not available
+const MAX_OCCURRENCE: u8 = 126;
+
+// manufacturer specific SPNs (520192..=524287)
+const SPN_BMS_TIMEOUT: u32 = 520192;
+const SPN_MOTOR_TIMEOUT: u32 = 520193;
+const SPN_E2E: u32 = 520194;
+const SPN_CONTACTOR: u32 = 520195;
+const SPN_SOC: u32 = 520196;
+// J1939 network #1
+const SPN_NETWORK: u32 = 639;
+// CANopen emergency of the motor controller, the error code class is added
+pub const SPN_MOTOR_EMERGENCY: u32 = 520448;
+// alarm flags of the BMS, the bit number is added
+pub const SPN_BMS_ALARM: u32 = 520704;
+
+pub const FMI_ERRATIC: u8 = 2;
+pub const FMI_NOT_RESPONDING: u8 = 7;
+pub const FMI_ABNORMAL_UPDATE: u8 = 9;
+pub const FMI_BAD_DEVICE: u8 = 12;
+pub const FMI_NETWORK_DATA: u8 = 19;
+pub const FMI_CONDITION_EXISTS: u8 = 31;
+
+// lamps used by the VCU, the MIL and protect lamp stay off
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lamp {
+    RedStop,
+    AmberWarning,
+}
+
+impl Lamp {
+    // bit position in the lamp status byte
+    fn shift(&self) -> u8 {
+        match self {
+            Lamp::RedStop => 4,
+            Lamp::AmberWarning => 2,
+        }
+    }
+}
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dtc {
+    pub spn: u32,
+    pub fmi: u8,
+    pub lamp: Lamp,
+}
+
+impl Dtc {
+    // SPN conversion method 4, the CM bit is 0
+    fn encode(&self, occurrence: u8) -> [u8; 4] {
+        [
+            self.spn as u8,
+            (self.spn >> 8) as u8,
+            ((self.spn >> 11) as u8 & 0xE0) | (self.fmi & 0x1F),
+            occurrence.min(MAX_OCCURRENCE),
+        ]
+    }
+}
+
+// DTC of a fault of the VCU fault list
+pub fn dtc_of(code: FaultCode) -> Dtc {
+    let lamp = if code.is_critical() {
+        Lamp::RedStop
+    } else {
+        Lamp::AmberWarning
+    };
+    let (spn, fmi) = match code {
+        FaultCode::CanErrorPassive => (SPN_NETWORK, FMI_NETWORK_DATA),
+        FaultCode::CanBusOff => (SPN_NETWORK, FMI_BAD_DEVICE),
+        FaultCode::BmsTimeout => (SPN_BMS_TIMEOUT, FMI_ABNORMAL_UPDATE),
+        FaultCode::MotorTimeout => (SPN_MOTOR_TIMEOUT, FMI_ABNORMAL_UPDATE),
+        FaultCode::E2eError => (SPN_E2E, FMI_NETWORK_DATA),
+        FaultCode::Contactor => (SPN_CONTACTOR, FMI_NOT_RESPONDING),
+        FaultCode::SocPlausibility => (SPN_SOC, FMI_ERRATIC),
+    };
+    Dtc { spn, fmi, lamp }
+}
+
+// ECUs which report their faults to the VCU
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DtcSource {
+    Bms,
+    Motor,
+}
+
+#[derive(Clone, Copy)]
+struct ExternalDtc {
+    source: DtcSource,
+    dtc: Dtc,
+    active: bool,
+    occurrence: u8,
+}
+
+static EXTERNAL: Mutex<RefCell<Vec<ExternalDtc, MAX_EXTERNAL>>> =
+    Mutex::new(RefCell::new(Vec::new()));
+// DM1 and DM2 requests, see `on_request`
+static REQUESTS: Mutex<RefCell<Deque<(u32, u8), MAX_REQUESTS>>> =
+    Mutex::new(RefCell::new(Deque::new()));
+
+// set a fault reported by another ECU active or previously active
+pub fn report(source: DtcSource, dtc: Dtc, active: bool) {
+    cortex_m::interrupt::free(|cs| {
+        let mut external = EXTERNAL.borrow(cs).borrow_mut();
+        let entry = external.iter_mut().find(|entry| {
+            entry.source == source && entry.dtc.spn == dtc.spn && entry.dtc.fmi == dtc.fmi
+        });
+        match entry {
+            Some(entry) => {
+                if active && !entry.active {
+                    entry.occurrence = entry.occurrence.saturating_add(1);
+                }
+                entry.active = active;
+                entry.dtc.lamp = dtc.lamp;
+            }
+            None if active => {
+                let entry = ExternalDtc {
+                    source,
+                    dtc,
+                    active,
+                    occurrence: 1,
+                };
+                if external.push(entry).is_err() {
+                    warn!("DTC list is full, drop SPN {} FMI {}", dtc.spn, dtc.fmi);
+                }
+            }
+            None => {}
+        }
+    });
+}
+
+// the ECU reported that all its faults are gone
+pub fn clear_source(source: DtcSource) {
+    cortex_m::interrupt::free(|cs| {
+        for entry in EXTERNAL.borrow(cs).borrow_mut().iter_mut() {
+            if entry.source == source {
+                entry.active = false;
+            }
+        }
+    });
+}
+
+// J1939 handler for PGN_REQUEST
+pub fn on_request(id: &J1939Id, data: &[u8]) {
+    if data.len() < 3 {
+        return;
+    }
+    let pgn = data[0] as u32 | (data[1] as u32) << 8 | (data[2] as u32) << 16;
+    if pgn != PGN_DM1 && pgn != PGN_DM2 {
+        return;
+    }
+    // answer a global request globally, otherwise to the requester
+    let destination = match id.destination {
+        Some(ADDRESS_GLOBAL) | None => ADDRESS_GLOBAL,
+        Some(_) => id.source,
+    };
+    cortex_m::interrupt::free(|cs| {
+        if REQUESTS
+            .borrow(cs)
+            .borrow_mut()
+            .push_back((pgn, destination))
+            .is_err()
+        {
+            warn!("Drop DM request, queue is full");
+        }
+    });
+}
+
+// lamp status, flash status and the DTCs, or the "no DTC" content of J1939-73
+fn encode(previously_active: bool) -> Vec<u8, { 2 + 4 * MAX_DTCS }> {
+    let mut lamps = 0u8;
+    let mut dtcs: Vec<[u8; 4], MAX_DTCS> = Vec::new();
+    for code in FaultCode::ALL.iter() {
+        let entry = fault::entry(*code);
+        let dtc = dtc_of(*code);
+        if entry.active {
+            lamps |= 1 << dtc.lamp.shift();
+        }
+        if entry.active != previously_active && (entry.active || entry.previously_active) {
+            dtcs.push(dtc.encode(entry.occurrence)).ok();
+        }
+    }
+    let external = cortex_m::interrupt::free(|cs| EXTERNAL.borrow(cs).borrow().clone());
+    for entry in external.iter() {
+        if entry.active {
+            lamps |= 1 << entry.dtc.lamp.shift();
+        }
+        // external entries are only kept once they were active
+        if entry.active != previously_active {
+            dtcs.push(entry.dtc.encode(entry.occurrence)).ok();
+        }
+    }
+
+    let mut data = Vec::new();
+    // no flashing lamps
+    data.extend_from_slice(&[lamps, 0xFF]).ok();
+    if dtcs.is_empty() {
+        data.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF])
+            .ok();
+    }
+    for dtc in dtcs.iter() {
+        data.extend_from_slice(dtc).ok();
+    }
+    // a single frame message is always 8 bytes
+    while data.len() < 8 {
+        data.push(0xFF).ok();
+    }
+    data
+}
+
+// broadcast of DM1 and answers to the DM requests
+pub struct DiagnosticMessages {
+    next_dm1: Instant,
+    last_dm1: Vec<u8, { 2 + 4 * MAX_DTCS }>,
+}
+
+impl DiagnosticMessages {
+    pub fn init() -> Self {
+        DiagnosticMessages {
+            next_dm1: Instant::now(),
+            last_dm1: Vec::new(),
+        }
+    }
+
+    // send DM1 every second and when the active DTCs change, must be called periodically
+    pub fn poll(&mut self, j1939: &mut J1939, now: Instant) {
+        let dm1 = encode(false);
+        if (now >= self.next_dm1 || dm1 != self.last_dm1)
+            && j1939
+                .send(DM_PRIORITY, PGN_DM1, ADDRESS_GLOBAL, &dm1, now)
+                .is_ok()
+        {
+            self.next_dm1 = now + DM1_PERIOD;
+            self.last_dm1 = dm1;
+        }
+
+        let request = cortex_m::interrupt::free(|cs| REQUESTS.borrow(cs).borrow().front().copied());
+        if let Some((pgn, destination)) = request {
+            let data = encode(pgn == PGN_DM2);
+            match j1939.send(DM_PRIORITY, pgn, destination, &data, now) {
+                // retried on the next call
+                Err(TransportError::Busy) => return,
+                Err(e) => warn!("DM request for PGN {:#x} failed: {:?}", pgn, e),
+                Ok(()) => {}
+            }
+            cortex_m::interrupt::free(|cs| REQUESTS.borrow(cs).borrow_mut().pop_front());
+        }
+    }
+}
@@ -0,0 +1,344 @@
+use defmt::Format;
+use embassy_time::{Duration, Instant};
+use heapless::{Deque, Vec};
+use log::{info, warn};
+use nuen_can::Frame;
+
+pub mod dm;
+mod transport;
+
+use transport::Transport;
+pub use transport::TransportError;
+
+pub const PGN_REQUEST: u32 = 0xEA00;
+pub const PGN_ADDRESS_CLAIMED: u32 = 0xEE00;
+pub const PGN_TP_CM: u32 = 0xEC00;
+pub const PGN_TP_DT: u32 = 0xEB00;
+
+pub const ADDRESS_GLOBAL: u8 = 0xFF;
+pub const ADDRESS_NULL: u8 = 0xFE;
+
+// range used by self-configurable ECUs when the preferred address is taken
+const DYNAMIC_ADDRESS_FIRST: u8 = 128;
+const DYNAMIC_ADDRESS_LAST: u8 = 247;
+// no traffic is sent after an address claim until this time passed
+const CLAIM_TIMEOUT: Duration = Duration::from_millis(250);
+
+const MAX_SUBSCRIPTIONS: usize = 16;
+const OUTBOX_SIZE: usize = 16;
+
+// J1939 NAME of the VCU (J1939-81)
+pub const VCU_NAME: Name = Name {
+    arbitrary_address: true,
+    industry_group: 0,
+    vehicle_system_instance: 0,
+    vehicle_system: 0,
+    function: 0x86, // vehicle control unit
+    function_instance: 0,
+    ecu_instance: 0,
+    manufacturer_code: 0x7FF,
+    identity_number: 0x00001,
+};
+pub const VCU_ADDRESS: u8 = 0x27;
+
+// 29-bit identifier split into its J1939 fields
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct J1939Id {
+    pub priority: u8,
+    pub pgn: u32,
+    pub source: u8,
+    // None for PDU2 (broadcast) messages
+    pub destination: Option<u8>,
+}
+
+impl J1939Id {
+    pub const fn from_raw(id: u32) -> Self {
+        let pdu_format = (id >> 16) & 0xFF;
+        let pdu_specific = ((id >> 8) & 0xFF) as u8;
+        // extended data page and data page
+        let page = (id >> 24) & 0x03;
+        let (pgn, destination) = if pdu_format < 240 {
+            ((page << 16) | (pdu_format << 8), Some(pdu_specific))
+        } else {
+            ((page << 16) | (pdu_format << 8) | pdu_specific as u32, None)
+        };
+        J1939Id {
+            priority: ((id >> 26) & 0x07) as u8,
+            pgn,
+            source: id as u8,
+            destination,
+        }
+    }
+
+    pub const fn to_raw(self) -> u32 {
+        let pdu_specific = match self.destination {
+            Some(destination) if (self.pgn >> 8) & 0xFF < 240 => destination as u32,
+            _ => self.pgn & 0xFF,
+        };
+        ((self.priority as u32 & 0x07) << 26)
+            | ((self.pgn & 0x3FF00) << 8)
+            | (pdu_specific << 8)
+            | self.source as u32
+    }
+
+    pub fn from_frame(frame: &Frame) -> Option<Self> {
+        frame.is_extended().then(|| J1939Id::from_raw(frame.id()))
+    }
+
+    // true if the message is broadcast or sent to the given address
+    pub fn is_for(&self, address: u8) -> bool {
+        match self.destination {
+            None | Some(ADDRESS_GLOBAL) => true,
+            Some(destination) => destination == address,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Name {
+    pub arbitrary_address: bool,
+    pub industry_group: u8,
+    pub vehicle_system_instance: u8,
+    pub vehicle_system: u8,
+    pub function: u8,
+    pub function_instance: u8,
+    pub ecu_instance: u8,
+    pub manufacturer_code: u16,
+    pub identity_number: u32,
+}
+
+impl Name {
+    pub const fn to_raw(self) -> u64 {
+        (self.arbitrary_address as u64) << 63
+            | (self.industry_group as u64 & 0x07) << 60
+            | (self.vehicle_system_instance as u64 & 0x0F) << 56
+            | (self.vehicle_system as u64 & 0x7F) << 49
+            | (self.function as u64) << 40
+            | (self.function_instance as u64 & 0x1F) << 35
+            | (self.ecu_instance as u64 & 0x07) << 32
+            | (self.manufacturer_code as u64 & 0x7FF) << 21
+            | (self.identity_number as u64 & 0x1F_FFFF)
+    }
+}
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClaimState {
+    Claiming,
+    Claimed,
+    CannotClaim,
+}
+
+// frames produced by the J1939 layer, waiting to be sent
+pub struct Outbox {
+    frames: Deque<Frame, OUTBOX_SIZE>,
+}
+
+impl Outbox {
+    fn push(&mut self, priority: u8, pgn: u32, destination: Option<u8>, source: u8, data: &[u8]) {
+        let id = J1939Id {
+            priority,
+            pgn,
+            source,
+            destination,
+        };
+        if let Some(frame) = Frame::new_extended(id.to_raw(), data) {
+            if self.frames.push_back(frame).is_err() {
+                warn!("J1939 outbox is full, drop PGN {:#x}", pgn);
+            }
+        }
+    }
+}
+
+pub type J1939Handler = fn(&J1939Id, &[u8]);
+
+struct Subscription {
+    pgn: u32,
+    handler: J1939Handler,
+}
+
+pub struct J1939 {
+    name: u64,
+    address: u8,
+    claim: ClaimState,
+    claimed_at: Instant,
+    subscriptions: Vec<Subscription, MAX_SUBSCRIPTIONS>,
+    transport: Transport,
+    outbox: Outbox,
+}
+
+impl J1939 {
+    pub fn init(name: Name, address: u8) -> Self {
+        J1939 {
+            name: name.to_raw(),
+            address,
+            claim: ClaimState::Claiming,
+            claimed_at: Instant::now(),
+            subscriptions: Vec::new(),
+            transport: Transport::init(),
+            outbox: Outbox {
+                frames: Deque::new(),
+            },
+        }
+    }
+
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    pub fn claim_state(&self) -> ClaimState {
+        self.claim
+    }
+
+    // call the handler for every message of the PGN, including transported ones
+    pub fn subscribe(&mut self, pgn: u32, handler: J1939Handler) {
+        if self
+            .subscriptions
+            .push(Subscription { pgn, handler })
+            .is_err()
+        {
+            warn!("J1939 subscription list is full");
+        }
+    }
+
+    // announce our address, must be called once at start-up
+    pub fn start(&mut self, now: Instant) {
+        self.send_address_claim(now);
+    }
+
+    pub fn next_frame(&mut self) -> Option<Frame> {
+        self.outbox.frames.pop_front()
+    }
+
+    // send a PGN, messages longer than 8 bytes use the transport protocol
+    pub fn send(
+        &mut self,
+        priority: u8,
+        pgn: u32,
+        destination: u8,
+        data: &[u8],
+        now: Instant,
+    ) -> Result<(), TransportError> {
+        if self.claim != ClaimState::Claimed {
+            return Err(TransportError::Busy);
+        }
+        if data.len() <= 8 {
+            self.outbox
+                .push(priority, pgn, Some(destination), self.address, data);
+            Ok(())
+        } else {
+            self.transport
+                .send(pgn, destination, data, self.address, now, &mut self.outbox)
+        }
+    }
+
+    pub fn receive(&mut self, frame: &Frame, now: Instant) {
+        let Some(id) = J1939Id::from_frame(frame) else {
+            return;
+        };
+        let data = frame.data();
+        match id.pgn {
+            PGN_ADDRESS_CLAIMED => self.on_address_claim(&id, data, now),
+            PGN_REQUEST if id.is_for(self.address) && data.len() >= 3 => {
+                let pgn = data[0] as u32 | (data[1] as u32) << 8 | (data[2] as u32) << 16;
+                if pgn == PGN_ADDRESS_CLAIMED {
+                    self.send_address_claim(now);
+                } else {
+                    self.dispatch(&id, data);
+                }
+            }
+            PGN_TP_CM if id.is_for(self.address) => {
+                match self
+                    .transport
+                    .on_connection(&id, data, self.address, now, &mut self.outbox)
+                {
+                    Some(Ok(pgn)) => info!("J1939 transfer of PGN {:#x} done", pgn),
+                    Some(Err(e)) => warn!("J1939 transfer failed: {:?}", e),
+                    None => {}
+                }
+            }
+            PGN_TP_DT if id.is_for(self.address) => {
+                if let Some((id, data)) =
+                    self.transport
+                        .on_data(&id, data, self.address, now, &mut self.outbox)
+                {
+                    for subscription in self.subscriptions.iter() {
+                        if subscription.pgn == id.pgn {
+                            (subscription.handler)(&id, data);
+                        }
+                    }
+                }
+            }
+            _ if id.is_for(self.address) => self.dispatch(&id, data),
+            _ => {}
+        }
+    }
+
+    // check the claim and transport timers, must be called periodically
+    pub fn poll(&mut self, now: Instant) {
+        if self.claim == ClaimState::Claiming && now.duration_since(self.claimed_at) > CLAIM_TIMEOUT
+        {
+            info!("J1939 address {:#x} claimed", self.address);
+            self.claim = ClaimState::Claimed;
+        }
+        if let Some(e) = self.transport.poll(self.address, now, &mut self.outbox) {
+            warn!("J1939 transfer failed: {:?}", e);
+        }
+    }
+
+    fn dispatch(&self, id: &J1939Id, data: &[u8]) {
+        for subscription in self.subscriptions.iter() {
+            if subscription.pgn == id.pgn {
+                (subscription.handler)(id, data);
+            }
+        }
+    }
+
+    fn send_address_claim(&mut self, now: Instant) {
+        let source = if self.claim == ClaimState::CannotClaim {
+            ADDRESS_NULL
+        } else {
+            self.claimed_at = now;
+            self.address
+        };
+        self.outbox.push(
+            6,
+            PGN_ADDRESS_CLAIMED,
+            Some(ADDRESS_GLOBAL),
+            source,
+            &self.name.to_le_bytes(),
+        );
+    }
+
+    fn on_address_claim(&mut self, id: &J1939Id, data: &[u8], now: Instant) {
+        if id.source != self.address || data.len() < 8 || self.claim == ClaimState::CannotClaim {
+            return;
+        }
+        let mut name = [0x00; 8];
+        name.copy_from_slice(&data[..8]);
+        let other = u64::from_le_bytes(name);
+        if self.name < other {
+            // the lower NAME has priority, defend our address
+            self.outbox.push(
+                6,
+                PGN_ADDRESS_CLAIMED,
+                Some(ADDRESS_GLOBAL),
+                self.address,
+                &self.name.to_le_bytes(),
+            );
+            return;
+        }
+
+        warn!("J1939 address {:#x} lost", self.address);
+        if self.name >> 63 == 1 && self.address < DYNAMIC_ADDRESS_LAST {
+            self.address = if self.address < DYNAMIC_ADDRESS_FIRST {
+                DYNAMIC_ADDRESS_FIRST
+            } else {
+                self.address + 1
+            };
+            self.claim = ClaimState::Claiming;
+        } else {
+            self.claim = ClaimState::CannotClaim;
+        }
+        self.send_address_claim(now);
+    }
+}
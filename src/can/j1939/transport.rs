@@ -0,0 +1,417 @@
+use defmt::Format;
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+use super::{J1939Id, Outbox, ADDRESS_GLOBAL, PGN_TP_CM, PGN_TP_DT};
+
+// J1939-21 transport protocol for messages of 9..=1785 bytes
+pub const TP_MAX_LEN: usize = 1785;
+
+const CM_RTS: u8 = 16;
+const CM_CTS: u8 = 17;
+const CM_EOM_ACK: u8 = 19;
+const CM_BAM: u8 = 32;
+const CM_ABORT: u8 = 255;
+
+const ABORT_BUSY: u8 = 1;
+const ABORT_TIMEOUT: u8 = 3;
+const ABORT_UNEXPECTED_DATA: u8 = 6;
+const ABORT_TOO_LONG: u8 = 9;
+// any other reason, e.g. a packet count which does not match the size
+const ABORT_OTHER: u8 = 250;
+
+// time between two data packets of a BAM, and the J1939-21 timeouts
+const BAM_PACKET_GAP: Duration = Duration::from_millis(50);
+const T1: Duration = Duration::from_millis(750);
+const T2: Duration = Duration::from_millis(1250);
+const T3: Duration = Duration::from_millis(1250);
+
+const RX_SESSIONS: usize = 3;
+// packets we accept per CTS
+const CTS_PACKETS: u8 = 16;
+const TP_PRIORITY: u8 = 7;
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportError {
+    Busy,
+    TooLong,
+    Aborted,
+    Timeout,
+}
+
+fn pgn_bytes(pgn: u32) -> [u8; 3] {
+    [pgn as u8, (pgn >> 8) as u8, (pgn >> 16) as u8]
+}
+
+fn packets_of(size: usize) -> u8 {
+    size.div_ceil(7) as u8
+}
+
+struct RxSession {
+    source: u8,
+    destination: u8,
+    pgn: u32,
+    size: usize,
+    packets: u8,
+    next_seq: u8,
+    // last packet of the current CTS block, unused for BAM
+    block_end: u8,
+    deadline: Instant,
+    buffer: Vec<u8, TP_MAX_LEN>,
+}
+
+enum TxState {
+    Idle,
+    Bam { next_seq: u8, next_at: Instant },
+    WaitCts { deadline: Instant },
+    Sending { next_seq: u8, block_end: u8 },
+    WaitAck { deadline: Instant },
+}
+
+pub struct Transport {
+    rx: Vec<RxSession, RX_SESSIONS>,
+    tx_state: TxState,
+    tx_destination: u8,
+    tx_pgn: u32,
+    tx_data: Vec<u8, TP_MAX_LEN>,
+}
+
+impl Transport {
+    pub fn init() -> Self {
+        Transport {
+            rx: Vec::new(),
+            tx_state: TxState::Idle,
+            tx_destination: ADDRESS_GLOBAL,
+            tx_pgn: 0,
+            tx_data: Vec::new(),
+        }
+    }
+
+    pub fn is_busy(&self) -> bool {
+        !matches!(self.tx_state, TxState::Idle)
+    }
+
+    // start a BAM for the global address or a CMDT connection for a specific destination
+    pub fn send(
+        &mut self,
+        pgn: u32,
+        destination: u8,
+        data: &[u8],
+        source: u8,
+        now: Instant,
+        outbox: &mut Outbox,
+    ) -> Result<(), TransportError> {
+        if self.is_busy() {
+            return Err(TransportError::Busy);
+        }
+        self.tx_data.clear();
+        self.tx_data
+            .extend_from_slice(data)
+            .map_err(|_| TransportError::TooLong)?;
+        self.tx_pgn = pgn;
+        self.tx_destination = destination;
+        let size = data.len() as u16;
+        let pgn = pgn_bytes(pgn);
+        let mut cm = [0xFF; 8];
+        cm[1..3].copy_from_slice(&size.to_le_bytes());
+        cm[3] = packets_of(data.len());
+        cm[5..8].copy_from_slice(&pgn);
+        if destination == ADDRESS_GLOBAL {
+            cm[0] = CM_BAM;
+            self.tx_state = TxState::Bam {
+                next_seq: 1,
+                next_at: now + BAM_PACKET_GAP,
+            };
+        } else {
+            cm[0] = CM_RTS;
+            self.tx_state = TxState::WaitCts { deadline: now + T3 };
+        }
+        outbox.push_cm(destination, source, cm);
+        Ok(())
+    }
+
+    // handle a TP.CM frame, return the PGN of a finished transmission
+    pub fn on_connection(
+        &mut self,
+        id: &J1939Id,
+        data: &[u8],
+        address: u8,
+        now: Instant,
+        outbox: &mut Outbox,
+    ) -> Option<Result<u32, TransportError>> {
+        if data.len() < 8 {
+            return None;
+        }
+        let size = u16::from_le_bytes([data[1], data[2]]) as usize;
+        let pgn = data[5] as u32 | (data[6] as u32) << 8 | (data[7] as u32) << 16;
+        let destination = id.destination.unwrap_or(ADDRESS_GLOBAL);
+        match data[0] {
+            CM_BAM | CM_RTS => {
+                // a new announcement from the same source replaces the old session
+                self.rx.retain(|session| session.source != id.source);
+                let broadcast = data[0] == CM_BAM;
+                let packets = data[3];
+                let reason = if size > TP_MAX_LEN {
+                    Some(ABORT_TOO_LONG)
+                } else if size < 9 || packets as usize != size.div_ceil(7) {
+                    Some(ABORT_OTHER)
+                } else if self.rx.is_full() {
+                    Some(ABORT_BUSY)
+                } else {
+                    None
+                };
+                if let Some(reason) = reason {
+                    // a broadcast is dropped silently
+                    if !broadcast {
+                        outbox.push_cm(id.source, address, abort(reason, pgn));
+                    }
+                    return None;
+                }
+                let block_end = packets.min(data[4]).min(CTS_PACKETS);
+                self.rx
+                    .push(RxSession {
+                        source: id.source,
+                        destination,
+                        pgn,
+                        size,
+                        packets,
+                        next_seq: 1,
+                        block_end,
+                        deadline: now + if broadcast { T1 } else { T2 },
+                        buffer: Vec::new(),
+                    })
+                    .ok();
+                if !broadcast {
+                    outbox.push_cm(id.source, address, cts(block_end, 1, pgn));
+                }
+                None
+            }
+            CM_CTS if id.source == self.tx_destination => {
+                if let TxState::WaitCts { .. } | TxState::Sending { .. } = self.tx_state {
+                    let count = data[1];
+                    let next_seq = data[2];
+                    let packets = packets_of(self.tx_data.len());
+                    if count == 0 {
+                        // receiver asks us to hold the connection open
+                        self.tx_state = TxState::WaitCts { deadline: now + T3 };
+                    } else if next_seq == 0 || next_seq > packets {
+                        // we cannot send packets which do not exist
+                        outbox.push_cm(id.source, address, abort(ABORT_OTHER, self.tx_pgn));
+                        self.tx_state = TxState::Idle;
+                        return Some(Err(TransportError::Aborted));
+                    } else {
+                        self.tx_state = TxState::Sending {
+                            next_seq,
+                            block_end: next_seq.saturating_add(count - 1).min(packets),
+                        };
+                    }
+                }
+                None
+            }
+            CM_EOM_ACK if id.source == self.tx_destination => {
+                if let TxState::WaitAck { .. } = self.tx_state {
+                    self.tx_state = TxState::Idle;
+                    return Some(Ok(self.tx_pgn));
+                }
+                None
+            }
+            CM_ABORT => {
+                self.rx.retain(|session| session.source != id.source);
+                if id.source == self.tx_destination && self.is_busy() {
+                    self.tx_state = TxState::Idle;
+                    return Some(Err(TransportError::Aborted));
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    // handle a TP.DT frame, return the reassembled message once complete
+    pub fn on_data(
+        &mut self,
+        id: &J1939Id,
+        data: &[u8],
+        address: u8,
+        now: Instant,
+        outbox: &mut Outbox,
+    ) -> Option<(J1939Id, &[u8])> {
+        let index = self
+            .rx
+            .iter()
+            .position(|session| session.source == id.source)?;
+        let session = &mut self.rx[index];
+        // a data packet always has 8 bytes, so the packets fill the announced size exactly
+        if data.len() < 8 || data[0] != session.next_seq {
+            return None;
+        }
+        let count = (session.size - session.buffer.len())
+            .min(7)
+            .min(data.len() - 1);
+        session.buffer.extend_from_slice(&data[1..=count]).ok();
+        let broadcast = session.destination == ADDRESS_GLOBAL;
+        session.deadline = now + if broadcast { T1 } else { T2 };
+
+        if session.buffer.len() >= session.size {
+            if !broadcast {
+                let mut ack = [0xFF; 8];
+                ack[0] = CM_EOM_ACK;
+                ack[1..3].copy_from_slice(&(session.size as u16).to_le_bytes());
+                ack[3] = session.packets;
+                ack[5..8].copy_from_slice(&pgn_bytes(session.pgn));
+                outbox.push_cm(session.source, address, ack);
+            }
+            // the session is dropped on the next poll, after the message was handled
+            session.next_seq = 0;
+            let message = J1939Id {
+                priority: id.priority,
+                pgn: session.pgn,
+                source: session.source,
+                destination: if broadcast {
+                    None
+                } else {
+                    Some(session.destination)
+                },
+            };
+            return Some((message, &session.buffer));
+        }
+
+        // packets left after this one, the sender must not send more than announced
+        let left = session
+            .packets
+            .checked_sub(session.next_seq)
+            .filter(|left| *left > 0);
+        let (Some(left), Some(next_seq)) = (left, session.next_seq.checked_add(1)) else {
+            if !broadcast {
+                outbox.push_cm(
+                    session.source,
+                    address,
+                    abort(ABORT_UNEXPECTED_DATA, session.pgn),
+                );
+            }
+            // dropped on the next poll
+            session.next_seq = 0;
+            return None;
+        };
+        if !broadcast && session.next_seq == session.block_end {
+            session.block_end = session.next_seq.saturating_add(left.min(CTS_PACKETS));
+            outbox.push_cm(
+                session.source,
+                address,
+                cts(left.min(CTS_PACKETS), next_seq, session.pgn),
+            );
+        }
+        session.next_seq = next_seq;
+        None
+    }
+
+    // send pending data packets and check the timeouts, must be called periodically
+    pub fn poll(
+        &mut self,
+        address: u8,
+        now: Instant,
+        outbox: &mut Outbox,
+    ) -> Option<TransportError> {
+        // finished sessions are marked with sequence 0 and dropped here
+        self.rx.retain(|session| session.next_seq != 0);
+        let mut aborted = Vec::<(u8, u32), RX_SESSIONS>::new();
+        self.rx.retain(|session| {
+            if now > session.deadline {
+                if session.destination != ADDRESS_GLOBAL {
+                    aborted.push((session.source, session.pgn)).ok();
+                }
+                return false;
+            }
+            true
+        });
+        for (source, pgn) in aborted {
+            outbox.push_cm(source, address, abort(ABORT_TIMEOUT, pgn));
+        }
+
+        match self.tx_state {
+            TxState::Idle => None,
+            TxState::Bam { next_seq, next_at } => {
+                if now >= next_at {
+                    outbox.push_dt(ADDRESS_GLOBAL, address, self.packet(next_seq));
+                    self.tx_state = if next_seq >= packets_of(self.tx_data.len()) {
+                        TxState::Idle
+                    } else {
+                        TxState::Bam {
+                            next_seq: next_seq + 1,
+                            next_at: now + BAM_PACKET_GAP,
+                        }
+                    };
+                }
+                None
+            }
+            TxState::WaitCts { deadline } | TxState::WaitAck { deadline } => {
+                if now > deadline {
+                    outbox.push_cm(
+                        self.tx_destination,
+                        address,
+                        abort(ABORT_TIMEOUT, self.tx_pgn),
+                    );
+                    self.tx_state = TxState::Idle;
+                    return Some(TransportError::Timeout);
+                }
+                None
+            }
+            TxState::Sending {
+                next_seq,
+                block_end,
+            } => {
+                outbox.push_dt(self.tx_destination, address, self.packet(next_seq));
+                let packets = packets_of(self.tx_data.len());
+                self.tx_state = if next_seq >= packets {
+                    TxState::WaitAck { deadline: now + T3 }
+                } else if next_seq >= block_end {
+                    TxState::WaitCts { deadline: now + T3 }
+                } else {
+                    TxState::Sending {
+                        next_seq: next_seq + 1,
+                        block_end,
+                    }
+                };
+                None
+            }
+        }
+    }
+
+    fn packet(&self, seq: u8) -> [u8; 8] {
+        let mut packet = [0xFF; 8];
+        packet[0] = seq;
+        let start = seq.saturating_sub(1) as usize * 7;
+        let end = (start + 7).min(self.tx_data.len());
+        if start < end {
+            packet[1..=end - start].copy_from_slice(&self.tx_data[start..end]);
+        }
+        packet
+    }
+}
+
+fn cts(count: u8, next_seq: u8, pgn: u32) -> [u8; 8] {
+    let mut cts = [0xFF; 8];
+    cts[0] = CM_CTS;
+    cts[1] = count;
+    cts[2] = next_seq;
+    cts[5..8].copy_from_slice(&pgn_bytes(pgn));
+    cts
+}
+
+fn abort(reason: u8, pgn: u32) -> [u8; 8] {
+    let mut abort = [0xFF; 8];
+    abort[0] = CM_ABORT;
+    abort[1] = reason;
+    abort[5..8].copy_from_slice(&pgn_bytes(pgn));
+    abort
+}
+
+impl Outbox {
+    fn push_cm(&mut self, destination: u8, source: u8, data: [u8; 8]) {
+        self.push(TP_PRIORITY, PGN_TP_CM, Some(destination), source, &data);
+    }
+
+    fn push_dt(&mut self, destination: u8, source: u8, data: [u8; 8]) {
+        self.push(TP_PRIORITY, PGN_TP_DT, Some(destination), source, &data);
+    }
+}
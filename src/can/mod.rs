@@ -0,0 +1,24 @@
+use defmt::Format;
+
+pub mod baud;
+pub mod bus;
+pub mod canopen;
+pub mod e2e;
+pub mod gateway;
+pub mod health;
+pub mod inject;
+pub mod isotp;
+pub mod j1939;
+pub mod supervision;
+pub mod trace;
+pub mod traffic;
+pub mod tx_queue;
+
+// default bitrate of the vehicle bus, see `config::Settings`
+pub const CAN_BITRATE: u32 = 500_000;
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Rx,
+    Tx,
+}
@@ -0,0 +1,165 @@
+use core::cell::RefCell;
+
+use cortex_m::interrupt::Mutex;
+use defmt::Format;
+use embassy_time::{Duration, Instant};
+
+use super::canopen::{cob_id, COB_HEARTBEAT, COB_TPDO1};
+use crate::println;
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CanNode {
+    Bms,
+    Motor,
+    Obc,
+    Display,
+}
+
+impl CanNode {
+    pub const ALL: [CanNode; 4] = [CanNode::Bms, CanNode::Motor, CanNode::Obc, CanNode::Display];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CanNode::Bms => "BMS",
+            CanNode::Motor => "MOTOR",
+            CanNode::Obc => "OBC",
+            CanNode::Display => "DISPLAY",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeStatus {
+    Unknown,
+    Alive,
+    Timeout,
+    Recovered,
+}
+
+impl NodeStatus {
+    pub fn is_alive(&self) -> bool {
+        matches!(self, NodeStatus::Alive | NodeStatus::Recovered)
+    }
+}
+
+pub struct NodeConfig {
+    pub node: CanNode,
+    // messages the node is expected to transmit periodically
+    pub ids: &'static [u32],
+    pub timeout: Duration,
+}
+
+// adjust the IDs to the devices used on the harness, the BMS IDs of every protocol
+// are listed so the frames of the configured one reach the BMS task
+pub const NODE_CONFIG: [NodeConfig; 4] = [
+    NodeConfig {
+        node: CanNode::Bms,
+        ids: &[
+            // nuen, pack 1 and 2
+            0x1806E5F4, 0x18FF28F4, 0x18FF29F4, 0x18FF30F4, 0x18FF31F4, 0x1806E5F5, 0x18FF28F5,
+            0x18FF29F5, 0x18FF30F5, 0x18FF31F5, // daly responses, pack 1 and 2
+            0x18904001, 0x18914001, 0x18924001, 0x18934001, 0x18954001, 0x18964001, 0x18974001,
+            0x18984001, 0x18904002, 0x18914002, 0x18924002, 0x18934002, 0x18954002, 0x18964002,
+            0x18974002, 0x18984002,
+        ],
+        timeout: Duration::from_millis(500),
+    },
+    NodeConfig {
+        node: CanNode::Motor,
+        // CANopen node, see `canopen::CANOPEN_CONFIG`: TPDO1 and TPDO2 every 50 ms,
+        // TPDO3 every 500 ms and the heartbeat
+        ids: &[
+            cob_id(COB_TPDO1, CanNode::Motor),
+            cob_id(COB_TPDO1 + 0x100, CanNode::Motor),
+            cob_id(COB_TPDO1 + 0x200, CanNode::Motor),
+            cob_id(COB_HEARTBEAT, CanNode::Motor),
+        ],
+        timeout: Duration::from_millis(200),
+    },
+    NodeConfig {
+        node: CanNode::Obc,
+        ids: &[0x18FF50E5],
+        timeout: Duration::from_millis(2000),
+    },
+    NodeConfig {
+        node: CanNode::Display,
+        ids: &[0x18FF0A28],
+        timeout: Duration::from_millis(1000),
+    },
+];
+
+// return the node which is expected to send the given ID
+pub fn node_of(id: u32) -> Option<CanNode> {
+    NODE_CONFIG
+        .iter()
+        .find(|config| config.ids.contains(&id))
+        .map(|config| config.node)
+}
+
+static LAST_SEEN: Mutex<RefCell<[Option<Instant>; 4]>> = Mutex::new(RefCell::new([None; 4]));
+static NODE_STATUS: Mutex<RefCell<[NodeStatus; 4]>> =
+    Mutex::new(RefCell::new([NodeStatus::Unknown; 4]));
+
+// called from the RX path for every received frame
+pub fn frame_received(id: u32) {
+    if let Some(node) = node_of(id) {
+        cortex_m::interrupt::free(|cs| {
+            LAST_SEEN.borrow(cs).borrow_mut()[node.index()] = Some(Instant::now());
+        });
+    }
+}
+
+pub fn node_status(node: CanNode) -> NodeStatus {
+    cortex_m::interrupt::free(|cs| NODE_STATUS.borrow(cs).borrow()[node.index()])
+}
+
+pub fn print_nodes() {
+    println!("Node \t status");
+    for node in CanNode::ALL.iter() {
+        println!("{} \t {:?}", node.name(), node_status(*node));
+    }
+}
+
+pub struct NodeSupervisor {
+    status: [NodeStatus; 4],
+}
+
+impl NodeSupervisor {
+    pub fn init() -> Self {
+        NodeSupervisor {
+            status: [NodeStatus::Unknown; 4],
+        }
+    }
+
+    // check the next node whose status changed since the last call
+    pub fn check(&mut self) -> Option<(CanNode, NodeStatus)> {
+        let now = Instant::now();
+        let last_seen = cortex_m::interrupt::free(|cs| *LAST_SEEN.borrow(cs).borrow());
+        for config in NODE_CONFIG.iter() {
+            let index = config.node.index();
+            let current = self.status[index];
+            let status = match last_seen[index] {
+                // a node that never showed up stays unknown until it times out
+                None if now.as_millis() > config.timeout.as_millis() => NodeStatus::Timeout,
+                None => NodeStatus::Unknown,
+                Some(seen) if now.duration_since(seen) > config.timeout => NodeStatus::Timeout,
+                Some(_) => match current {
+                    NodeStatus::Timeout | NodeStatus::Recovered => NodeStatus::Recovered,
+                    _ => NodeStatus::Alive,
+                },
+            };
+            if status != current {
+                self.status[index] = status;
+                cortex_m::interrupt::free(|cs| {
+                    NODE_STATUS.borrow(cs).borrow_mut()[index] = status;
+                });
+                return Some((config.node, status));
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,242 @@
+use core::{cell::RefCell, fmt::Write};
+
+use cortex_m::interrupt::Mutex;
+use defmt::Format;
+use embassy_time::Instant;
+use heapless::{Deque, String};
+use log::info;
+use nuen_can::Frame;
+
+use super::Direction;
+use crate::{print, println};
+
+// about 24 bytes per frame, a few seconds of a busy bus
+const TRACE_SIZE: usize = 1024;
+// frames still recorded after the trigger, the rest of the buffer keeps the history before it
+const POST_TRIGGER: usize = TRACE_SIZE / 4;
+// interface name used in the candump output
+const INTERFACE: &str = "can0";
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    // any new fault, see `fault::raise`
+    Fault,
+    // reception or transmission of the given identifier
+    Id(u32),
+    // only the `can trace trigger` command
+    Manual,
+}
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceState {
+    Recording,
+    Triggered,
+    Stopped,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    Candump,
+    Asc,
+}
+
+#[derive(Clone, Copy)]
+struct TraceEntry {
+    // time since start-up in us
+    timestamp: u64,
+    id: u32,
+    extended: bool,
+    direction: Direction,
+    len: u8,
+    data: [u8; 8],
+}
+
+struct Trace {
+    entries: Deque<TraceEntry, TRACE_SIZE>,
+    trigger: Trigger,
+    state: TraceState,
+    post_left: usize,
+    triggered_at: Option<u64>,
+}
+
+impl Trace {
+    const fn new() -> Self {
+        Trace {
+            entries: Deque::new(),
+            trigger: Trigger::Fault,
+            state: TraceState::Recording,
+            post_left: 0,
+            triggered_at: None,
+        }
+    }
+
+    fn start_trigger(&mut self) {
+        if self.state == TraceState::Recording {
+            self.state = TraceState::Triggered;
+            self.post_left = POST_TRIGGER;
+            self.triggered_at = Some(Instant::now().as_micros());
+        }
+    }
+}
+
+static TRACE: Mutex<RefCell<Trace>> = Mutex::new(RefCell::new(Trace::new()));
+
+// called from the RX and TX path for every frame on the bus
+pub fn record(frame: &Frame, direction: Direction) {
+    let mut entry = TraceEntry {
+        timestamp: Instant::now().as_micros(),
+        id: frame.id(),
+        extended: frame.is_extended(),
+        direction,
+        len: frame.data().len() as u8,
+        data: [0x00; 8],
+    };
+    entry.data[..frame.data().len()].copy_from_slice(frame.data());
+
+    let stopped = cortex_m::interrupt::free(|cs| {
+        let mut trace = TRACE.borrow(cs).borrow_mut();
+        if trace.state == TraceState::Stopped {
+            return false;
+        }
+        if trace.entries.is_full() {
+            trace.entries.pop_front();
+        }
+        trace.entries.push_back(entry).ok();
+        match trace.state {
+            TraceState::Recording if trace.trigger == Trigger::Id(entry.id) => {
+                trace.start_trigger();
+                false
+            }
+            TraceState::Triggered => {
+                trace.post_left -= 1;
+                if trace.post_left == 0 {
+                    trace.state = TraceState::Stopped;
+                    return true;
+                }
+                false
+            }
+            _ => false,
+        }
+    });
+    if stopped {
+        info!("CAN trace stopped, dump it with: can trace dump");
+    }
+}
+
+// called when a new fault is raised
+pub fn on_fault() {
+    cortex_m::interrupt::free(|cs| {
+        let mut trace = TRACE.borrow(cs).borrow_mut();
+        if trace.trigger == Trigger::Fault {
+            trace.start_trigger();
+        }
+    });
+}
+
+// manual trigger, works with every trigger configuration
+pub fn trigger() {
+    cortex_m::interrupt::free(|cs| TRACE.borrow(cs).borrow_mut().start_trigger());
+}
+
+// clear the buffer and start recording until the trigger fires
+pub fn arm(trigger: Trigger) {
+    cortex_m::interrupt::free(|cs| {
+        let mut trace = TRACE.borrow(cs).borrow_mut();
+        trace.entries.clear();
+        trace.trigger = trigger;
+        trace.state = TraceState::Recording;
+        trace.triggered_at = None;
+    });
+}
+
+pub fn print_status() {
+    let (state, trigger, count, triggered_at) = cortex_m::interrupt::free(|cs| {
+        let trace = TRACE.borrow(cs).borrow();
+        (
+            trace.state,
+            trace.trigger,
+            trace.entries.len(),
+            trace.triggered_at,
+        )
+    });
+    println!("CAN trace: {:?}", state);
+    println!("\ttrigger: {:?}", trigger);
+    println!("\tframes: {} / {}", count, TRACE_SIZE);
+    if let Some(timestamp) = triggered_at {
+        println!(
+            "\ttriggered at: {}.{:06}",
+            timestamp / 1_000_000,
+            timestamp % 1_000_000
+        );
+    }
+}
+
+// print the trace on the console, the recording is stopped first
+// so the buffer does not change while it is printed
+pub fn dump(format: TraceFormat) {
+    let count = cortex_m::interrupt::free(|cs| {
+        let mut trace = TRACE.borrow(cs).borrow_mut();
+        trace.state = TraceState::Stopped;
+        trace.entries.len()
+    });
+
+    if format == TraceFormat::Asc {
+        // there is no real time clock, timestamps are relative to start-up
+        println!("date Thu Jan 1 12:00:00.000 am 1970");
+        println!("base hex  timestamps absolute");
+        println!("no internal events logged");
+        println!("Begin Triggerblock Thu Jan 1 12:00:00.000 am 1970");
+        println!("   0.000000 Start of measurement");
+    }
+    for index in 0..count {
+        let entry = cortex_m::interrupt::free(|cs| {
+            let trace = TRACE.borrow(cs).borrow();
+            let (front, back) = trace.entries.as_slices();
+            if index < front.len() {
+                front[index]
+            } else {
+                back[index - front.len()]
+            }
+        });
+        let seconds = entry.timestamp / 1_000_000;
+        let micros = entry.timestamp % 1_000_000;
+        let data = &entry.data[..entry.len as usize];
+        match format {
+            TraceFormat::Candump => {
+                print!("({}.{:06}) {} ", seconds, micros, INTERFACE);
+                if entry.extended {
+                    print!("{:08X}#", entry.id);
+                } else {
+                    print!("{:03X}#", entry.id);
+                }
+                for byte in data {
+                    print!("{:02X}", byte);
+                }
+                println!();
+            }
+            TraceFormat::Asc => {
+                let mut id = String::<16>::new();
+                if entry.extended {
+                    write!(id, "{:X}x", entry.id).ok();
+                } else {
+                    write!(id, "{:X}", entry.id).ok();
+                }
+                let direction = match entry.direction {
+                    Direction::Rx => "Rx",
+                    Direction::Tx => "Tx",
+                };
+                print!(
+                    "{:>4}.{:06} 1  {:<15} {}   d {}",
+                    seconds, micros, id, direction, entry.len
+                );
+                for byte in data {
+                    print!(" {:02X}", byte);
+                }
+                println!();
+            }
+        }
+    }
+    if format == TraceFormat::Asc {
+        println!("End TriggerBlock");
+    }
+}
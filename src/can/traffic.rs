@@ -0,0 +1,213 @@
+use core::cell::RefCell;
+
+use cortex_m::interrupt::Mutex;
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+use nuen_can::Frame;
+
+use super::{Direction, CAN_BITRATE};
+use crate::{print, println};
+
+// number of IDs tracked, frames of further IDs only count for the bus load
+const MAX_IDS: usize = 48;
+// bus load and frame rates are computed over this window
+const LOAD_WINDOW: Duration = Duration::from_millis(1000);
+// weight of a new sample in the period and jitter averages, as a shift
+const AVERAGE_SHIFT: u32 = 3;
+
+#[derive(Clone, Copy)]
+struct IdStats {
+    id: u32,
+    extended: bool,
+    direction: Direction,
+    count: u32,
+    last_seen: Instant,
+    // averaged interval between two frames and its mean deviation, in us
+    period: u32,
+    jitter: u32,
+    // frames per second in the last complete window
+    rate: u32,
+    window_count: u32,
+}
+
+impl IdStats {
+    fn new(id: u32, extended: bool, direction: Direction, now: Instant) -> Self {
+        IdStats {
+            id,
+            extended,
+            direction,
+            count: 1,
+            last_seen: now,
+            period: 0,
+            jitter: 0,
+            rate: 0,
+            window_count: 1,
+        }
+    }
+
+    fn update(&mut self, now: Instant) {
+        let interval = now.duration_since(self.last_seen).as_micros() as u32;
+        if self.count == 1 {
+            self.period = interval;
+        } else {
+            let deviation = interval.abs_diff(self.period);
+            self.jitter = average(self.jitter, deviation);
+            self.period = average(self.period, interval);
+        }
+        self.count = self.count.wrapping_add(1);
+        self.window_count += 1;
+        self.last_seen = now;
+    }
+}
+
+fn average(average: u32, sample: u32) -> u32 {
+    let average = average as i64;
+    (average + ((sample as i64 - average) >> AVERAGE_SHIFT)) as u32
+}
+
+// bits of a frame on the wire including the worst case stuff bits,
+// the 3 bit interframe space is counted as part of the frame
+pub fn frame_bits(frame: &Frame) -> u32 {
+    // bits covered by bit stuffing: SOF to the end of the CRC
+    let stuffed = if frame.is_extended() { 54 } else { 34 } + frame.data().len() as u32 * 8;
+    // CRC delimiter, ACK, EOF and interframe space
+    stuffed + (stuffed - 1) / 4 + 13
+}
+
+struct Traffic {
+    ids: Vec<IdStats, MAX_IDS>,
+    untracked: u32,
+    window_start: Instant,
+    window_bits: u32,
+    // bus load of the last complete window and the highest seen, in 0.1%
+    load: u16,
+    peak_load: u16,
+    bitrate: u32,
+}
+
+impl Traffic {
+    const fn new() -> Self {
+        Traffic {
+            ids: Vec::new(),
+            untracked: 0,
+            window_start: Instant::from_ticks(0),
+            window_bits: 0,
+            load: 0,
+            peak_load: 0,
+            bitrate: CAN_BITRATE,
+        }
+    }
+}
+
+static TRAFFIC: Mutex<RefCell<Traffic>> = Mutex::new(RefCell::new(Traffic::new()));
+
+// bitrate the bus load is computed for
+pub fn set_bitrate(bitrate: u32) {
+    cortex_m::interrupt::free(|cs| TRAFFIC.borrow(cs).borrow_mut().bitrate = bitrate);
+}
+
+// called from the RX and TX path for every frame on the bus
+pub fn record(frame: &Frame, direction: Direction) {
+    let id = frame.id();
+    let extended = frame.is_extended();
+    let bits = frame_bits(frame);
+    let now = Instant::now();
+    cortex_m::interrupt::free(|cs| {
+        let mut traffic = TRAFFIC.borrow(cs).borrow_mut();
+        traffic.window_bits = traffic.window_bits.saturating_add(bits);
+        match traffic
+            .ids
+            .iter_mut()
+            .find(|stats| stats.id == id && stats.direction == direction)
+        {
+            Some(stats) => stats.update(now),
+            None => {
+                if traffic
+                    .ids
+                    .push(IdStats::new(id, extended, direction, now))
+                    .is_err()
+                {
+                    traffic.untracked = traffic.untracked.wrapping_add(1);
+                }
+            }
+        }
+    });
+}
+
+// close the measurement window once it elapsed, must be called periodically
+pub fn update() {
+    let now = Instant::now();
+    cortex_m::interrupt::free(|cs| {
+        let mut traffic = TRAFFIC.borrow(cs).borrow_mut();
+        let elapsed = now.duration_since(traffic.window_start);
+        if elapsed < LOAD_WINDOW {
+            return;
+        }
+        let elapsed_us = elapsed.as_micros().max(1);
+        let capacity = traffic.bitrate as u64 * elapsed_us / 1_000_000;
+        let load = (traffic.window_bits as u64 * 1000 / capacity.max(1)).min(1000) as u16;
+        traffic.load = load;
+        traffic.peak_load = traffic.peak_load.max(load);
+        traffic.window_bits = 0;
+        traffic.window_start = now;
+        for stats in traffic.ids.iter_mut() {
+            stats.rate = (stats.window_count as u64 * 1_000_000 / elapsed_us) as u32;
+            stats.window_count = 0;
+        }
+    });
+}
+
+pub fn reset() {
+    cortex_m::interrupt::free(|cs| {
+        let mut traffic = TRAFFIC.borrow(cs).borrow_mut();
+        traffic.ids.clear();
+        traffic.untracked = 0;
+        traffic.peak_load = 0;
+    });
+}
+
+// list the IDs with the highest frame rate first
+pub fn print_top() {
+    let (mut ids, untracked, load, peak_load, bitrate) = cortex_m::interrupt::free(|cs| {
+        let traffic = TRAFFIC.borrow(cs).borrow();
+        (
+            traffic.ids.clone(),
+            traffic.untracked,
+            traffic.load,
+            traffic.peak_load,
+            traffic.bitrate,
+        )
+    });
+    ids.sort_unstable_by(|a, b| b.rate.cmp(&a.rate).then(a.id.cmp(&b.id)));
+    let now = Instant::now();
+    println!(
+        "bus load: {}.{}% \t peak: {}.{}% \t @ {} kbit/s",
+        load / 10,
+        load % 10,
+        peak_load / 10,
+        peak_load % 10,
+        bitrate / 1000
+    );
+    println!("ID \t\t dir \t count \t rate/s \t period ms \t jitter ms \t age ms");
+    for stats in ids.iter() {
+        if stats.extended {
+            print!("{:08X}", stats.id);
+        } else {
+            print!("{:03X}     ", stats.id);
+        }
+        println!(
+            " \t {:?} \t {} \t {} \t\t {}.{} \t\t {}.{} \t\t {}",
+            stats.direction,
+            stats.count,
+            stats.rate,
+            stats.period / 1000,
+            stats.period % 1000 / 100,
+            stats.jitter / 1000,
+            stats.jitter % 1000 / 100,
+            now.duration_since(stats.last_seen).as_millis()
+        );
+    }
+    if untracked > 0 {
+        println!("{} frames of untracked IDs", untracked);
+    }
+}
@@ -0,0 +1,88 @@
+use core::cell::RefCell;
+
+use cortex_m::interrupt::Mutex;
+use nuen_can::tx_queue::{TxClass, TxClassRule, TxStats, TX_CLASSES};
+
+use crate::println;
+
+// classes of the frames sent on CAN1, the queue itself is in `nuen_can::tx_queue`
+
+const EXACT: u32 = 0x1FFF_FFFF;
+// priority bits and source address of a J1939 identifier are ignored
+const PGN_MASK: u32 = 0x03FF_FF00;
+
+// the first matching rule decides, other frames are of the control class
+pub const TX_CLASS_RULES: [TxClassRule; 8] = [
+    // torque command and drive enable
+    TxClassRule {
+        id: 0x0C00_05EF,
+        mask: 0x1FF0_FFFF,
+        class: TxClass::Safety,
+    },
+    // contactor command to the BMS of each pack
+    TxClassRule {
+        id: 0x1803_00EF,
+        mask: 0x1FFF_00FF,
+        class: TxClass::Safety,
+    },
+    // UDS responses and ISO-TP frames to the BMS
+    TxClassRule {
+        id: 0x7E8,
+        mask: EXACT,
+        class: TxClass::Diagnostic,
+    },
+    TxClassRule {
+        id: 0x18DA_F4F1,
+        mask: EXACT,
+        class: TxClass::Diagnostic,
+    },
+    // J1939 transport protocol, TP.CM and TP.DT
+    TxClassRule {
+        id: 0x00EC_0000,
+        mask: 0x03FF_0000,
+        class: TxClass::Diagnostic,
+    },
+    TxClassRule {
+        id: 0x00EB_0000,
+        mask: 0x03FF_0000,
+        class: TxClass::Diagnostic,
+    },
+    // segment LCD frames and messages to the display address 0xF9
+    TxClassRule {
+        id: 0x00F8_1000,
+        mask: PGN_MASK,
+        class: TxClass::Display,
+    },
+    TxClassRule {
+        id: 0x0000_F900,
+        mask: PGN_MASK,
+        class: TxClass::Display,
+    },
+];
+
+// copy of the statistics of the CanTx task for the command line
+static TX_STATS: Mutex<RefCell<[TxStats; TX_CLASSES]>> =
+    Mutex::new(RefCell::new([TxStats::new(); TX_CLASSES]));
+
+pub fn record_stats(stats: &[TxStats; TX_CLASSES]) {
+    cortex_m::interrupt::free(|cs| *TX_STATS.borrow(cs).borrow_mut() = *stats);
+}
+
+pub fn print_stats() {
+    let stats = cortex_m::interrupt::free(|cs| *TX_STATS.borrow(cs).borrow());
+    println!("class \t\t queued \t sent \t overflow \t stale \t aborted \t preempted \t max depth");
+    for class in TxClass::ALL.iter() {
+        let stats = &stats[class.index()];
+        println!(
+            "{:?} \t {} \t\t {} \t {} \t\t {} \t {} \t\t {} \t\t {}",
+            class,
+            stats.queued,
+            stats.sent,
+            stats.overflow,
+            stats.stale,
+            stats.aborted,
+            stats.preempted,
+            stats.max_depth
+        );
+    }
+}
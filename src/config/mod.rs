@@ -0,0 +1,318 @@
+use core::cell::RefCell;
+
+use cortex_m::interrupt::Mutex;
+use defmt::Format;
+use embassy_stm32::flash::{Blocking, Flash};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use log::{info, warn};
+
+use crate::{battery::protocol::BmsKind, can::CAN_BITRATE, power::RideMode, println};
+
+// Settings persisted in the last flash sector. Every save appends a record,
+// the sector is only erased when it is full, the last valid record wins.
+
+// sector 11 of the STM32F412RG (1 MB), memory.x ends the FLASH region before it
+const CONFIG_OFFSET: u32 = 0x000E_0000;
+const CONFIG_SIZE: u32 = 0x0002_0000;
+
+const RECORD_SIZE: usize = 64;
+const RECORD_MAGIC: u32 = 0x4E55_454E;
+// magic, length, payload and the CRC at the end
+const HEADER_SIZE: usize = 6;
+const PAYLOAD_MAX: usize = RECORD_SIZE - HEADER_SIZE - 4;
+
+// an optional value which is not set
+const NO_VALUE: u16 = 0xFFFF;
+
+pub const SUPPORTED_BITRATES: [u32; 4] = [125_000, 250_000, 500_000, 1_000_000];
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    NotInitialized,
+    Flash,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    pub can1_bitrate: u32,
+    pub can2_bitrate: u32,
+    // detect the CAN1 bitrate at start-up, see `can::baud`
+    pub auto_baud: bool,
+    // SOC estimated by the VCU in 0.1 %, see `battery::SocEstimator`
+    pub soc: Option<u16>,
+    // consumption of each ride mode in 0.1 Wh/km, see `battery::range`
+    pub consumption: [Option<u16>; RideMode::ALL.len()],
+    // driver of the BMS frames, see `battery::protocol`
+    pub bms: BmsKind,
+    // motor turns per wheel turn in 0.01, see `motor::vehicle_speed`
+    pub gear_ratio: u16,
+    // distance per wheel turn in mm
+    pub wheel_circumference: u16,
+}
+
+impl Settings {
+    const fn new() -> Self {
+        Settings {
+            can1_bitrate: CAN_BITRATE,
+            can2_bitrate: CAN_BITRATE,
+            auto_baud: false,
+            soc: None,
+            consumption: [None; RideMode::ALL.len()],
+            bms: BmsKind::Nuen,
+            gear_ratio: 100,
+            wheel_circumference: 1350,
+        }
+    }
+
+    // new fields are appended, an older record keeps their default value
+    fn encode(&self, payload: &mut [u8; PAYLOAD_MAX]) -> usize {
+        payload[0..4].copy_from_slice(&self.can1_bitrate.to_le_bytes());
+        payload[4..8].copy_from_slice(&self.can2_bitrate.to_le_bytes());
+        payload[8] = self.auto_baud as u8;
+        payload[9..11].copy_from_slice(&self.soc.unwrap_or(NO_VALUE).to_le_bytes());
+        for (index, consumption) in self.consumption.iter().enumerate() {
+            let at = 11 + 2 * index;
+            payload[at..at + 2].copy_from_slice(&consumption.unwrap_or(NO_VALUE).to_le_bytes());
+        }
+        let at = 11 + 2 * self.consumption.len();
+        payload[at] = self.bms as u8;
+        payload[at + 1..at + 3].copy_from_slice(&self.gear_ratio.to_le_bytes());
+        payload[at + 3..at + 5].copy_from_slice(&self.wheel_circumference.to_le_bytes());
+        at + 5
+    }
+
+    fn decode(payload: &[u8]) -> Self {
+        let mut settings = Settings::new();
+        let word = |at: usize| {
+            payload
+                .get(at..at + 4)
+                .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        };
+        if let Some(bitrate) = word(0).filter(|bitrate| SUPPORTED_BITRATES.contains(bitrate)) {
+            settings.can1_bitrate = bitrate;
+        }
+        if let Some(bitrate) = word(4).filter(|bitrate| SUPPORTED_BITRATES.contains(bitrate)) {
+            settings.can2_bitrate = bitrate;
+        }
+        if let Some(auto_baud) = payload.get(8) {
+            settings.auto_baud = *auto_baud == 1;
+        }
+        if let Some(soc) = payload.get(9..11) {
+            settings.soc = Some(u16::from_le_bytes([soc[0], soc[1]])).filter(|soc| *soc <= 1000);
+        }
+        for (index, consumption) in settings.consumption.iter_mut().enumerate() {
+            let at = 11 + 2 * index;
+            if let Some(value) = payload.get(at..at + 2) {
+                *consumption = Some(u16::from_le_bytes([value[0], value[1]]))
+                    .filter(|value| *value != NO_VALUE);
+            }
+        }
+        let at = 11 + 2 * settings.consumption.len();
+        if let Some(bms) = payload.get(at).and_then(|bms| BmsKind::from_u8(*bms)) {
+            settings.bms = bms;
+        }
+        let half = |at: usize| {
+            payload
+                .get(at..at + 2)
+                .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+                .filter(|value| *value != 0 && *value != NO_VALUE)
+        };
+        if let Some(gear_ratio) = half(at + 1) {
+            settings.gear_ratio = gear_ratio;
+        }
+        if let Some(wheel_circumference) = half(at + 3) {
+            settings.wheel_circumference = wheel_circumference;
+        }
+        settings
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct ConfigStore {
+    flash: Option<Flash<'static, Blocking>>,
+    settings: Settings,
+    // offset of the first erased record in the sector
+    next_record: u32,
+    // changed by `update_pending` since the last save
+    pending: bool,
+}
+
+static CONFIG: Mutex<RefCell<ConfigStore>> = Mutex::new(RefCell::new(ConfigStore {
+    flash: None,
+    settings: Settings::new(),
+    next_record: 0,
+    pending: false,
+}));
+
+// wakes the config task to write the pending changes, see `request_save`
+static SAVE_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFF;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// load the last saved settings, the defaults are used without a valid record
+pub fn init(mut flash: Flash<'static, Blocking>) {
+    let mut settings = None;
+    let mut next_record = CONFIG_SIZE;
+    let mut record = [0x00; RECORD_SIZE];
+    for offset in (0..CONFIG_SIZE).step_by(RECORD_SIZE) {
+        if flash
+            .blocking_read(CONFIG_OFFSET + offset, &mut record)
+            .is_err()
+        {
+            break;
+        }
+        if record.iter().all(|byte| *byte == 0xFF) {
+            next_record = offset;
+            break;
+        }
+        let len = u16::from_le_bytes([record[4], record[5]]) as usize;
+        let crc_at = RECORD_SIZE - 4;
+        let crc = u32::from_le_bytes([
+            record[crc_at],
+            record[crc_at + 1],
+            record[crc_at + 2],
+            record[crc_at + 3],
+        ]);
+        let magic = u32::from_le_bytes([record[0], record[1], record[2], record[3]]);
+        if magic == RECORD_MAGIC && len <= PAYLOAD_MAX && crc == crc32(&record[..crc_at]) {
+            settings = Some(Settings::decode(&record[HEADER_SIZE..HEADER_SIZE + len]));
+        }
+    }
+    match settings {
+        Some(_) => info!("Config loaded"),
+        None => warn!("No saved config, use the defaults"),
+    }
+    cortex_m::interrupt::free(|cs| {
+        let mut store = CONFIG.borrow(cs).borrow_mut();
+        store.settings = settings.unwrap_or_default();
+        store.next_record = next_record;
+        store.flash = Some(flash);
+    });
+}
+
+pub fn settings() -> Settings {
+    cortex_m::interrupt::free(|cs| CONFIG.borrow(cs).borrow().settings)
+}
+
+// change the settings in RAM, see `save`
+pub fn update(change: impl FnOnce(&mut Settings)) {
+    cortex_m::interrupt::free(|cs| change(&mut CONFIG.borrow(cs).borrow_mut().settings));
+}
+
+// change values which are updated while riding, they are only written to flash
+// once the vehicle is parked, see `save_pending`
+pub fn update_pending(change: impl FnOnce(&mut Settings)) {
+    cortex_m::interrupt::free(|cs| {
+        let mut store = CONFIG.borrow(cs).borrow_mut();
+        change(&mut store.settings);
+        store.pending = true;
+    });
+}
+
+// called by the state machine while the vehicle stands, the flash is
+// written by the config task on the low priority executor
+pub fn request_save() {
+    if cortex_m::interrupt::free(|cs| CONFIG.borrow(cs).borrow().pending) {
+        SAVE_REQUEST.signal(());
+    }
+}
+
+pub async fn wait_save_request() {
+    SAVE_REQUEST.wait().await
+}
+
+// save the settings if `update_pending` changed them, never call it while riding
+pub fn save_pending() -> Result<(), ConfigError> {
+    if !cortex_m::interrupt::free(|cs| CONFIG.borrow(cs).borrow().pending) {
+        return Ok(());
+    }
+    save()
+}
+
+// write the settings to flash, the CPU stalls while the sector is erased
+pub fn save() -> Result<(), ConfigError> {
+    // the flash is taken out of the store so the interrupts stay enabled while writing
+    let (mut flash, settings, next_record) = cortex_m::interrupt::free(|cs| {
+        let mut store = CONFIG.borrow(cs).borrow_mut();
+        let flash = store.flash.take().ok_or(ConfigError::NotInitialized)?;
+        // a change while writing is saved the next time, a failed save only
+        // with the next change
+        store.pending = false;
+        Ok((flash, store.settings, store.next_record))
+    })?;
+    let result = write_record(&mut flash, &settings, next_record);
+    cortex_m::interrupt::free(|cs| {
+        let mut store = CONFIG.borrow(cs).borrow_mut();
+        store.flash = Some(flash);
+        // a record which failed half way can't be written again, erase on the next save
+        store.next_record = *result.as_ref().unwrap_or(&CONFIG_SIZE);
+    });
+    result.map(|_| ())
+}
+
+// append a record, return the offset of the next one
+fn write_record(
+    flash: &mut Flash<'static, Blocking>,
+    settings: &Settings,
+    mut offset: u32,
+) -> Result<u32, ConfigError> {
+    if offset >= CONFIG_SIZE {
+        flash
+            .blocking_erase(CONFIG_OFFSET, CONFIG_OFFSET + CONFIG_SIZE)
+            .map_err(|_| ConfigError::Flash)?;
+        offset = 0;
+    }
+
+    let mut payload = [0x00; PAYLOAD_MAX];
+    let len = settings.encode(&mut payload);
+    let mut record = [0xFF; RECORD_SIZE];
+    record[0..4].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+    record[4..6].copy_from_slice(&(len as u16).to_le_bytes());
+    record[HEADER_SIZE..HEADER_SIZE + len].copy_from_slice(&payload[..len]);
+    let crc_at = RECORD_SIZE - 4;
+    let crc = crc32(&record[..crc_at]);
+    record[crc_at..].copy_from_slice(&crc.to_le_bytes());
+
+    flash
+        .blocking_write(CONFIG_OFFSET + offset, &record)
+        .map_err(|_| ConfigError::Flash)?;
+    Ok(offset + RECORD_SIZE as u32)
+}
+
+pub fn print_settings() {
+    let settings = settings();
+    println!("can1 bitrate: {} kbit/s", settings.can1_bitrate / 1000);
+    println!("can2 bitrate: {} kbit/s", settings.can2_bitrate / 1000);
+    println!(
+        "auto baud: {}",
+        if settings.auto_baud { "on" } else { "off" }
+    );
+    println!("bms: {}", settings.bms.name());
+    println!(
+        "gear ratio: {}.{:02}, wheel: {} mm",
+        settings.gear_ratio / 100,
+        settings.gear_ratio % 100,
+        settings.wheel_circumference
+    );
+    if let Some(soc) = settings.soc {
+        println!("SOC estimate: {}.{}%", soc / 10, soc % 10);
+    }
+}
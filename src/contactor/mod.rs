@@ -0,0 +1,193 @@
+use core::cell::RefCell;
+
+use cortex_m::interrupt::Mutex;
+use defmt::Format;
+use embassy_time::{Duration, Instant};
+use log::{info, warn};
+use nuen_can::Frame;
+
+use crate::battery::{self, ContactorState, PACK_ADDRESSES, PACK_COUNT};
+
+// contactor command to the BMS of each pack, the address of the pack in bits 8..16
+// (0x1803F4EF for the first pack), E2E protected (bytes 0 and 1), the command in byte 2
+pub const CONTACTOR_COMMAND: u32 = 0x180300EF;
+
+// the main contactor is closed once the DC bus reached this part of the pack voltage
+const PRECHARGE_PERCENT: u32 = 95;
+const PRECHARGE_TIMEOUT: Duration = Duration::from_secs(3);
+// time for the BMS to report the main contactor closed
+const CLOSE_TIMEOUT: Duration = Duration::from_millis(500);
+// the contactors are opened without load, or after this timeout
+const OPEN_CURRENT: i16 = 20;
+const OPEN_TIMEOUT: Duration = Duration::from_secs(1);
+// a DC bus voltage older than this is not used
+const DC_BUS_TIMEOUT: Duration = Duration::from_millis(300);
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContactorCommand {
+    Open = 0,
+    // precharge relay and negative contactor closed
+    Precharge = 1,
+    // main and negative contactor closed, precharge relay open
+    Close = 2,
+}
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContactorPhase {
+    Open,
+    Precharge,
+    Closing,
+    Closed,
+    // waiting for the current to drop before opening
+    Opening,
+}
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContactorEvent {
+    Closed,
+    Opened,
+    Failed,
+}
+
+// latest phase, readable from other tasks
+static PHASE: Mutex<RefCell<ContactorPhase>> = Mutex::new(RefCell::new(ContactorPhase::Open));
+// DC bus voltage measured by the motor controller, in 0.1 V
+static DC_BUS: Mutex<RefCell<Option<(u16, Instant)>>> = Mutex::new(RefCell::new(None));
+
+pub fn phase() -> ContactorPhase {
+    cortex_m::interrupt::free(|cs| *PHASE.borrow(cs).borrow())
+}
+
+pub fn is_closed() -> bool {
+    phase() == ContactorPhase::Closed
+}
+
+pub fn report_dc_bus(voltage: u16) {
+    cortex_m::interrupt::free(|cs| {
+        DC_BUS.borrow(cs).replace(Some((voltage, Instant::now())));
+    });
+}
+
+fn dc_bus(now: Instant) -> Option<u16> {
+    let dc_bus = cortex_m::interrupt::free(|cs| *DC_BUS.borrow(cs).borrow());
+    dc_bus
+        .filter(|(_, at)| now.duration_since(*at) <= DC_BUS_TIMEOUT)
+        .map(|(voltage, _)| voltage)
+}
+
+// the DC bus is charged close enough to the pack voltage
+fn precharged(now: Instant) -> bool {
+    match (battery::status(), dc_bus(now)) {
+        (Some(status), Some(voltage)) if status.pack_voltage > 0 => {
+            voltage as u32 * 100 >= status.pack_voltage as u32 * PRECHARGE_PERCENT
+        }
+        _ => false,
+    }
+}
+
+// precharge and main contactor sequence, the BMS drives the relays
+pub struct Contactor {
+    phase: ContactorPhase,
+    since: Instant,
+}
+
+impl Contactor {
+    pub fn init() -> Self {
+        Contactor {
+            phase: ContactorPhase::Open,
+            since: Instant::now(),
+        }
+    }
+
+    // step the sequence towards closed or open, must be called periodically
+    pub fn update(&mut self, now: Instant, close: bool) -> Option<ContactorEvent> {
+        let elapsed = now.duration_since(self.since);
+        let status = battery::status();
+        let (phase, event) = match self.phase {
+            ContactorPhase::Open if close && status.is_some() => (ContactorPhase::Precharge, None),
+            ContactorPhase::Open => return None,
+            // no current flows before the main contactor is closed
+            ContactorPhase::Precharge | ContactorPhase::Closing if !close => {
+                (ContactorPhase::Open, Some(ContactorEvent::Opened))
+            }
+            ContactorPhase::Precharge if precharged(now) => (ContactorPhase::Closing, None),
+            ContactorPhase::Precharge if elapsed > PRECHARGE_TIMEOUT => {
+                warn!("Precharge failed, DC bus {:?}", dc_bus(now));
+                (ContactorPhase::Open, Some(ContactorEvent::Failed))
+            }
+            ContactorPhase::Closing
+                if status.is_some_and(|status| status.contactor == ContactorState::Closed) =>
+            {
+                (ContactorPhase::Closed, Some(ContactorEvent::Closed))
+            }
+            ContactorPhase::Closing if elapsed > CLOSE_TIMEOUT => {
+                warn!("Main contactor not closed");
+                (ContactorPhase::Open, Some(ContactorEvent::Failed))
+            }
+            ContactorPhase::Closed if !close => (ContactorPhase::Opening, None),
+            // opened by the BMS, or the BMS is gone
+            ContactorPhase::Closed
+                if !status.is_some_and(|status| status.contactor == ContactorState::Closed) =>
+            {
+                warn!("Main contactor opened by the BMS");
+                (ContactorPhase::Open, Some(ContactorEvent::Failed))
+            }
+            ContactorPhase::Opening
+                if elapsed > OPEN_TIMEOUT
+                    || status.is_none_or(|status| status.current.abs() < OPEN_CURRENT) =>
+            {
+                (ContactorPhase::Open, Some(ContactorEvent::Opened))
+            }
+            _ => return None,
+        };
+        info!("Contactor {:?} -> {:?}", self.phase, phase);
+        self.phase = phase;
+        self.since = now;
+        cortex_m::interrupt::free(|cs| PHASE.borrow(cs).replace(phase));
+        event
+    }
+
+    pub fn phase(&self) -> ContactorPhase {
+        self.phase
+    }
+
+    pub fn command(&self) -> ContactorCommand {
+        match self.phase {
+            ContactorPhase::Open => ContactorCommand::Open,
+            ContactorPhase::Precharge => ContactorCommand::Precharge,
+            // stay closed until the current dropped
+            ContactorPhase::Closing | ContactorPhase::Closed | ContactorPhase::Opening => {
+                ContactorCommand::Close
+            }
+        }
+    }
+
+    // command of each pack, only the packs chosen by the arbitration are connected
+    pub fn pack_command(&self, pack: usize) -> ContactorCommand {
+        if battery::pack_active(pack) {
+            self.command()
+        } else {
+            ContactorCommand::Open
+        }
+    }
+
+    // command frame of each pack, the BMS opens the contactors when it is not received
+    pub fn command_frames(&self) -> [Frame; PACK_COUNT] {
+        core::array::from_fn(|pack| self.command_frame(pack))
+    }
+
+    fn command_frame(&self, pack: usize) -> Frame {
+        let data = [
+            0x00,
+            0x00,
+            self.pack_command(pack) as u8,
+            0xFF,
+            0xFF,
+            0xFF,
+            0xFF,
+            0xFF,
+        ];
+        let id = CONTACTOR_COMMAND | (PACK_ADDRESSES[pack] as u32) << 8;
+        Frame::new_extended(id, &data).unwrap()
+    }
+}
@@ -0,0 +1,143 @@
+use core::cell::RefCell;
+
+use cortex_m::interrupt::Mutex;
+use defmt::Format;
+use log::{info, warn};
+
+use crate::can::trace;
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultCode {
+    CanErrorPassive,
+    CanBusOff,
+    BmsTimeout,
+    MotorTimeout,
+    E2eError,
+    Contactor,
+    SocPlausibility,
+}
+
+impl FaultCode {
+    pub const ALL: [FaultCode; 7] = [
+        FaultCode::CanErrorPassive,
+        FaultCode::CanBusOff,
+        FaultCode::BmsTimeout,
+        FaultCode::MotorTimeout,
+        FaultCode::E2eError,
+        FaultCode::Contactor,
+        FaultCode::SocPlausibility,
+    ];
+
+    // critical faults are not allowed while the vehicle is riding
+    pub fn is_critical(&self) -> bool {
+        match self {
+            FaultCode::CanErrorPassive => false,
+            FaultCode::CanBusOff => true,
+            FaultCode::BmsTimeout => true,
+            FaultCode::MotorTimeout => true,
+            FaultCode::E2eError => true,
+            FaultCode::Contactor => true,
+            FaultCode::SocPlausibility => false,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FaultCode::CanErrorPassive => "CAN error passive",
+            FaultCode::CanBusOff => "CAN bus off",
+            FaultCode::BmsTimeout => "BMS timeout",
+            FaultCode::MotorTimeout => "Motor timeout",
+            FaultCode::E2eError => "E2E protection",
+            FaultCode::Contactor => "HV contactor",
+            FaultCode::SocPlausibility => "SOC plausibility",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct FaultEntry {
+    pub active: bool,
+    pub previously_active: bool,
+    pub occurrence: u8,
+}
+
+impl FaultEntry {
+    const fn new() -> Self {
+        FaultEntry {
+            active: false,
+            previously_active: false,
+            occurrence: 0,
+        }
+    }
+}
+
+pub struct FaultManager {
+    entries: [FaultEntry; FaultCode::ALL.len()],
+}
+
+impl FaultManager {
+    const fn new() -> Self {
+        FaultManager {
+            entries: [FaultEntry::new(); FaultCode::ALL.len()],
+        }
+    }
+
+    // return true if the fault was not active before
+    fn raise(&mut self, code: FaultCode) -> bool {
+        let entry = &mut self.entries[code.index()];
+        if entry.active {
+            return false;
+        }
+        entry.active = true;
+        entry.occurrence = entry.occurrence.saturating_add(1);
+        true
+    }
+
+    // return true if the fault was active before
+    fn clear(&mut self, code: FaultCode) -> bool {
+        let entry = &mut self.entries[code.index()];
+        if !entry.active {
+            return false;
+        }
+        entry.active = false;
+        entry.previously_active = true;
+        true
+    }
+}
+
+static FAULTS: Mutex<RefCell<FaultManager>> = Mutex::new(RefCell::new(FaultManager::new()));
+
+pub fn raise(code: FaultCode) -> bool {
+    let raised = cortex_m::interrupt::free(|cs| FAULTS.borrow(cs).borrow_mut().raise(code));
+    if raised {
+        warn!("Fault raised: {}", code.name());
+        trace::on_fault();
+    }
+    raised
+}
+
+pub fn clear(code: FaultCode) -> bool {
+    let cleared = cortex_m::interrupt::free(|cs| FAULTS.borrow(cs).borrow_mut().clear(code));
+    if cleared {
+        info!("Fault cleared: {}", code.name());
+    }
+    cleared
+}
+
+pub fn entry(code: FaultCode) -> FaultEntry {
+    cortex_m::interrupt::free(|cs| FAULTS.borrow(cs).borrow().entries[code.index()])
+}
+
+pub fn is_active(code: FaultCode) -> bool {
+    entry(code).active
+}
+
+pub fn has_critical() -> bool {
+    FaultCode::ALL
+        .iter()
+        .any(|code| code.is_critical() && is_active(*code))
+}
@@ -6,33 +6,46 @@ use core::cell::RefCell;
 use cortex_m::interrupt::Mutex;
 use cortex_m_rt::entry;
 use embassy_executor::{Executor, InterruptExecutor};
+use embassy_futures::block_on;
 use embassy_stm32::{
     bind_interrupts,
-    can::{
-        Can, Frame, Rx0InterruptHandler, Rx1InterruptHandler, SceInterruptHandler,
-        TxInterruptHandler,
-    },
+    can::{Can, Rx0InterruptHandler, Rx1InterruptHandler, SceInterruptHandler, TxInterruptHandler},
+    flash::Flash,
     gpio::{Input, Level, Output, Pull, Speed},
     interrupt,
     interrupt::{InterruptExt, Priority},
     mode::Async,
-    peripherals::{CAN1, USART1},
+    peripherals::{CAN1, CAN2, USART1},
     usart::{Config, InterruptHandler, Uart, UartTx},
 };
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
 use logger::init_logger;
 use panic_probe as _;
 use static_cell::StaticCell;
+mod battery;
+mod can;
 mod cmd;
-mod display;
+mod config;
+mod contactor;
+mod fault;
 mod io;
 mod logger;
+mod motor;
+mod power;
 mod state_machine;
 mod tasks;
+mod uds;
+use can::{
+    baud,
+    supervision::{CanNode, NodeStatus},
+    traffic,
+};
+use fault::FaultCode;
 use io::{BikeOutput, SwitchGearInput};
 
-use log::info;
+use log::{info, warn};
 use logger::Printer;
+use nuen_can::{display::ScreenRequest, Frame};
 
 static UART: Mutex<RefCell<Option<UartTx<'static, Async>>>> = Mutex::new(RefCell::new(None));
 
@@ -54,20 +67,16 @@ pub fn uart_flush() {
     });
 }
 
+pub fn system_reset() -> ! {
+    uart_flush();
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
 pub enum SimulinkType {
     KeyFob(u8),
     Can(Frame),
-}
-
-pub enum ScreenRequest {
-    Power(bool),
-    LeftIndicator,
-    RightIndicator,
-    Ready,
-    Speed(u8),
-    Soc(u8),
-    Abs(bool),
-    HeadLight(bool),
+    Fault(FaultCode),
+    NodeStatus(CanNode, NodeStatus),
 }
 
 type ScreenBox = Channel<CriticalSectionRawMutex, ScreenRequest, 16>;
@@ -75,12 +84,21 @@ type SimulinkBox = Channel<CriticalSectionRawMutex, SimulinkType, 16>;
 type CanObcBox = Channel<CriticalSectionRawMutex, Frame, 16>;
 type CanBmsBox = Channel<CriticalSectionRawMutex, Frame, 16>;
 type CanMotorBox = Channel<CriticalSectionRawMutex, Frame, 16>;
+type IsoTpBox = Channel<CriticalSectionRawMutex, Frame, 16>;
+type CanTxBox = Channel<CriticalSectionRawMutex, Frame, 16>;
+type J1939Box = Channel<CriticalSectionRawMutex, Frame, 16>;
+type CanInjectBox = Channel<CriticalSectionRawMutex, Frame, 16>;
+type CanGatewayBox = Channel<CriticalSectionRawMutex, Frame, 16>;
 
 bind_interrupts!(struct Irqs {
     CAN1_RX0 => Rx0InterruptHandler<CAN1>;
     CAN1_RX1 => Rx1InterruptHandler<CAN1>;
     CAN1_SCE => SceInterruptHandler<CAN1>;
     CAN1_TX => TxInterruptHandler<CAN1>;
+    CAN2_RX0 => Rx0InterruptHandler<CAN2>;
+    CAN2_RX1 => Rx1InterruptHandler<CAN2>;
+    CAN2_SCE => SceInterruptHandler<CAN2>;
+    CAN2_TX => TxInterruptHandler<CAN2>;
     USART1 => InterruptHandler<USART1>;
 });
 
@@ -149,23 +167,59 @@ fn main() -> ! {
     interrupt::USART3.set_priority(Priority::P6);
     let high_prio_spawner = EXECUTOR_HIGH.start(interrupt::USART3);
 
-    // Init 2 embassy channels to transfer data.
+    // Init embassy channels to transfer data.
     static CHANNEL0: StaticCell<SimulinkBox> = StaticCell::new();
     static CHANNEL1: StaticCell<ScreenBox> = StaticCell::new();
     static CHANNEL2: StaticCell<CanBmsBox> = StaticCell::new();
     static CHANNEL3: StaticCell<CanMotorBox> = StaticCell::new();
     static CHANNEL4: StaticCell<CanObcBox> = StaticCell::new();
+    static CHANNEL5: StaticCell<IsoTpBox> = StaticCell::new();
+    static CHANNEL6: StaticCell<CanTxBox> = StaticCell::new();
+    static CHANNEL7: StaticCell<J1939Box> = StaticCell::new();
+    static CHANNEL8: StaticCell<CanInjectBox> = StaticCell::new();
+    static CHANNEL9: StaticCell<CanGatewayBox> = StaticCell::new();
 
     let channel0 = &*CHANNEL0.init(Channel::new());
     let channel1 = &*CHANNEL1.init(Channel::new());
     let channel2 = &*CHANNEL2.init(Channel::new());
     let channel3 = &*CHANNEL3.init(Channel::new());
     let channel4 = &*CHANNEL4.init(Channel::new());
+    let channel5 = &*CHANNEL5.init(Channel::new());
+    let channel6 = &*CHANNEL6.init(Channel::new());
+    let channel7 = &*CHANNEL7.init(Channel::new());
+    let channel8 = &*CHANNEL8.init(Channel::new());
+    let channel9 = &*CHANNEL9.init(Channel::new());
+
+    // load the persisted settings
+    config::init(Flash::new_blocking(p.FLASH));
+    let settings = config::settings();
 
     // Initialize the CAN bus
     let mut can = Can::new(p.CAN1, p.PA11, p.PA12, Irqs);
-    can.modify_config().set_bitrate(500_000);
+    let mut bitrate = settings.can1_bitrate;
+    if settings.auto_baud {
+        // no task runs yet, the start-up waits for the detection
+        match block_on(baud::detect(&mut can, bitrate)) {
+            Some(detected) => {
+                bitrate = detected;
+                // tried first on the next start, which is then not delayed
+                if detected != settings.can1_bitrate {
+                    config::update(|settings| settings.can1_bitrate = detected);
+                    if let Err(e) = config::save() {
+                        warn!("CAN bitrate not saved: {:?}", e);
+                    }
+                }
+            }
+            None => warn!("CAN bitrate not detected, use {} kbit/s", bitrate / 1000),
+        }
+    }
+    can.modify_config().set_bitrate(bitrate).set_silent(false);
+    traffic::set_bitrate(bitrate);
     let (can_tx, can_rx) = can.split();
+    // CAN2 is the body bus, connected to CAN1 through the gateway
+    let mut can2 = Can::new(p.CAN2, p.PB5, p.PB6, Irqs);
+    can2.modify_config().set_bitrate(settings.can2_bitrate);
+    let (can2_tx, can2_rx) = can2.split();
 
     // spawn state machine task on high priority executor.
     info!("hello everybody. welcome to embassy!\r");
@@ -176,6 +230,7 @@ fn main() -> ! {
             bike_output,
             channel1,
             channel0,
+            channel6,
         ))
         .unwrap();
     high_prio_spawner.spawn(tasks::cmd_task(usart_rx)).unwrap();
@@ -185,15 +240,36 @@ fn main() -> ! {
     let low_prio_spawner = EXECUTOR_LOW.init(Executor::new());
     low_prio_spawner.run(|spawner| {
         spawner
-            .spawn(tasks::can_tx_task(can, can_tx, channel1))
+            .spawn(tasks::can_tx_task(
+                can, can_tx, channel1, channel6, channel9,
+            ))
             .unwrap();
         spawner
             .spawn(tasks::can_rx_task(
-                can_rx, channel0, channel2, channel3, channel4,
+                can_rx, channel0, channel2, channel3, channel4, channel5, channel7, channel8,
+                channel9,
             ))
             .unwrap();
-        spawner.spawn(tasks::bms_task(channel2)).unwrap();
-        spawner.spawn(tasks::motor_task(channel3)).unwrap();
+        spawner.spawn(tasks::bms_task(channel2, channel6)).unwrap();
+        spawner
+            .spawn(tasks::motor_task(channel3, channel6))
+            .unwrap();
         spawner.spawn(tasks::obc_task(channel4)).unwrap();
+        spawner.spawn(tasks::can_monitor_task(channel0)).unwrap();
+        spawner.spawn(tasks::config_task()).unwrap();
+        spawner
+            .spawn(tasks::isotp_task(channel5, channel6))
+            .unwrap();
+        spawner
+            .spawn(tasks::j1939_task(channel7, channel6))
+            .unwrap();
+        spawner
+            .spawn(tasks::can_inject_task(channel6, channel8))
+            .unwrap();
+        spawner
+            .spawn(tasks::can_gateway_task(
+                can2, can2_tx, can2_rx, channel9, channel6,
+            ))
+            .unwrap();
     });
 }
@@ -0,0 +1,134 @@
+use core::cell::RefCell;
+
+use cortex_m::interrupt::Mutex;
+use defmt::Format;
+
+use crate::{config, println};
+
+// fault flags of the motor controller, adjust to the controller
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MotorFaults(pub u16);
+
+impl MotorFaults {
+    pub const OVER_CURRENT: u16 = 1 << 0;
+    pub const OVER_VOLTAGE: u16 = 1 << 1;
+    pub const UNDER_VOLTAGE: u16 = 1 << 2;
+    pub const MOTOR_OVER_TEMPERATURE: u16 = 1 << 3;
+    pub const CONTROLLER_OVER_TEMPERATURE: u16 = 1 << 4;
+    pub const POSITION_SENSOR: u16 = 1 << 5;
+    pub const PHASE_LOSS: u16 = 1 << 6;
+    pub const INTERNAL: u16 = 1 << 7;
+
+    pub const ALL: [(u16, &'static str); 8] = [
+        (Self::OVER_CURRENT, "over current"),
+        (Self::OVER_VOLTAGE, "over voltage"),
+        (Self::UNDER_VOLTAGE, "under voltage"),
+        (Self::MOTOR_OVER_TEMPERATURE, "motor over temperature"),
+        (
+            Self::CONTROLLER_OVER_TEMPERATURE,
+            "controller over temperature",
+        ),
+        (Self::POSITION_SENSOR, "position sensor"),
+        (Self::PHASE_LOSS, "phase loss"),
+        (Self::INTERNAL, "internal"),
+    ];
+
+    pub fn contains(&self, fault: u16) -> bool {
+        self.0 & fault != 0
+    }
+}
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MotorStatus {
+    // CiA 402 statusword
+    pub statusword: u16,
+    // in rpm, negative while reversing
+    pub rpm: i32,
+    // in 0.1 % of the rated torque, negative while braking
+    pub torque: i16,
+    // RMS phase current in 0.1 A
+    pub phase_current: i16,
+    // in 0.1 V
+    pub dc_voltage: u16,
+    // in 0.1 A, positive while driving
+    pub dc_current: i16,
+    // in °C
+    pub motor_temperature: i8,
+    pub controller_temperature: i8,
+    pub faults: MotorFaults,
+}
+
+impl MotorStatus {
+    pub const fn new() -> Self {
+        MotorStatus {
+            statusword: 0,
+            rpm: 0,
+            torque: 0,
+            phase_current: 0,
+            dc_voltage: 0,
+            dc_current: 0,
+            motor_temperature: 0,
+            controller_temperature: 0,
+            faults: MotorFaults(0),
+        }
+    }
+}
+
+// latest status decoded from the motor controller, empty while it is silent
+static MOTOR: Mutex<RefCell<Option<MotorStatus>>> = Mutex::new(RefCell::new(None));
+
+pub fn publish(status: MotorStatus) {
+    cortex_m::interrupt::free(|cs| MOTOR.borrow(cs).replace(Some(status)));
+}
+
+// the motor controller stopped sending, the last values must not be used any more
+pub fn invalidate() {
+    cortex_m::interrupt::free(|cs| MOTOR.borrow(cs).replace(None));
+}
+
+pub fn status() -> Option<MotorStatus> {
+    cortex_m::interrupt::free(|cs| *MOTOR.borrow(cs).borrow())
+}
+
+// vehicle speed in m/s at the given motor speed, see `config gear` and `config wheel`
+pub fn vehicle_speed(rpm: i32) -> f32 {
+    let settings = config::settings();
+    let wheel_rpm = rpm.unsigned_abs() as f32 * 100.0 / settings.gear_ratio as f32;
+    wheel_rpm / 60.0 * settings.wheel_circumference as f32 / 1000.0
+}
+
+// vehicle speed in km/h, unknown without data from the motor controller
+pub fn speed() -> Option<u8> {
+    let status = status()?;
+    Some((vehicle_speed(status.rpm) * 3.6).min(u8::MAX as f32) as u8)
+}
+
+pub fn print_status() {
+    let Some(status) = status() else {
+        println!("no data from the motor controller");
+        return;
+    };
+    println!(
+        "speed: {} km/h, {} rpm, torque {}%",
+        speed().unwrap_or(0),
+        status.rpm,
+        status.torque as f32 / 10.0
+    );
+    println!(
+        "DC bus: {}.{} V, {} A, phase current: {} A",
+        status.dc_voltage / 10,
+        status.dc_voltage % 10,
+        status.dc_current as f32 / 10.0,
+        status.phase_current as f32 / 10.0
+    );
+    println!(
+        "temperature: motor {} C, controller {} C",
+        status.motor_temperature, status.controller_temperature
+    );
+    println!("statusword: {:#06x}", status.statusword);
+    for (fault, name) in MotorFaults::ALL.iter() {
+        if status.faults.contains(*fault) {
+            println!("fault: {}", name);
+        }
+    }
+}
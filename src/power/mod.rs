@@ -0,0 +1,224 @@
+use core::cell::RefCell;
+
+use cortex_m::interrupt::Mutex;
+use defmt::Format;
+use embassy_time::Instant;
+use log::info;
+
+use crate::{battery, contactor, println};
+
+// current limits sent to the motor controller, all currents in 0.1 A
+
+// battery temperature derating: full current up to the start, none above the end
+const DERATE_START: i16 = 45;
+const DERATE_END: i16 = 60;
+// no regenerative braking into a frozen battery
+const CHARGE_MIN_TEMPERATURE: i8 = 0;
+
+// change of the limits per second, a reduction is applied faster than an increase
+const RAMP_UP: u32 = 500;
+const RAMP_DOWN: u32 = 2000;
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RideMode {
+    Eco,
+    Normal,
+    Sport,
+}
+
+impl RideMode {
+    pub const ALL: [RideMode; 3] = [RideMode::Eco, RideMode::Normal, RideMode::Sport];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RideMode::Eco => "eco",
+            RideMode::Normal => "normal",
+            RideMode::Sport => "sport",
+        }
+    }
+
+    fn discharge_limit(&self) -> u16 {
+        match self {
+            RideMode::Eco => 600,
+            RideMode::Normal => 1200,
+            RideMode::Sport => 2000,
+        }
+    }
+}
+
+// the limit which is currently the lowest one
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitReason {
+    // no BMS data, nothing is allowed
+    NoBattery,
+    // the high voltage is not connected
+    Contactor,
+    Bms,
+    Thermal,
+    RideMode,
+}
+
+impl LimitReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LimitReason::NoBattery => "no battery data",
+            LimitReason::Contactor => "contactor open",
+            LimitReason::Bms => "BMS",
+            LimitReason::Thermal => "battery temperature",
+            LimitReason::RideMode => "ride mode",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PowerLimit {
+    pub discharge: u16,
+    pub charge: u16,
+    pub discharge_reason: LimitReason,
+    pub charge_reason: LimitReason,
+}
+
+impl PowerLimit {
+    const fn new() -> Self {
+        PowerLimit {
+            discharge: 0,
+            charge: 0,
+            discharge_reason: LimitReason::NoBattery,
+            charge_reason: LimitReason::NoBattery,
+        }
+    }
+}
+
+static RIDE_MODE: Mutex<RefCell<RideMode>> = Mutex::new(RefCell::new(RideMode::Normal));
+// latest limits sent to the motor controller
+static LIMIT: Mutex<RefCell<PowerLimit>> = Mutex::new(RefCell::new(PowerLimit::new()));
+
+pub fn ride_mode() -> RideMode {
+    cortex_m::interrupt::free(|cs| *RIDE_MODE.borrow(cs).borrow())
+}
+
+pub fn set_ride_mode(mode: RideMode) {
+    info!("Ride mode {}", mode.name());
+    cortex_m::interrupt::free(|cs| RIDE_MODE.borrow(cs).replace(mode));
+}
+
+pub fn limit() -> PowerLimit {
+    cortex_m::interrupt::free(|cs| *LIMIT.borrow(cs).borrow())
+}
+
+// scale a limit down linearly between the derating temperatures
+fn derate(limit: u16, temperature: i8) -> u16 {
+    let temperature = temperature as i16;
+    if temperature <= DERATE_START {
+        limit
+    } else if temperature >= DERATE_END {
+        0
+    } else {
+        (limit as i32 * (DERATE_END - temperature) as i32 / (DERATE_END - DERATE_START) as i32)
+            as u16
+    }
+}
+
+// move from the current value towards the target with the given rate per second
+fn ramp(current: u16, target: u16, elapsed_ms: u64) -> u16 {
+    let rate = if target > current { RAMP_UP } else { RAMP_DOWN };
+    let step = (rate as u64 * elapsed_ms / 1000).max(1) as u16;
+    if target > current {
+        current.saturating_add(step).min(target)
+    } else {
+        current.saturating_sub(step).max(target)
+    }
+}
+
+// the lowest limit wins, the first one on a tie
+fn lowest(limits: &[(u16, LimitReason)]) -> (u16, LimitReason) {
+    limits.iter().copied().fold(
+        limits[0],
+        |low, limit| if limit.0 < low.0 { limit } else { low },
+    )
+}
+
+pub struct PowerLimiter {
+    limit: PowerLimit,
+    last_update: Option<Instant>,
+}
+
+impl PowerLimiter {
+    pub fn init() -> Self {
+        PowerLimiter {
+            limit: PowerLimit::new(),
+            last_update: None,
+        }
+    }
+
+    // compute the limits for now, must be called periodically
+    pub fn update(&mut self, now: Instant) -> PowerLimit {
+        let elapsed_ms = self
+            .last_update
+            .map_or(0, |last| now.duration_since(last).as_millis());
+        self.last_update = Some(now);
+
+        let status = battery::status();
+        // the BMS limits are never exceeded, even while ramping down
+        let bms_limit = status.map_or((0, 0), |status| {
+            (status.discharge_limit, status.charge_limit)
+        });
+        let (discharge, discharge_reason, charge, charge_reason) = match status {
+            // ramp down before the contactors are opened
+            Some(_) if !contactor::is_closed() => {
+                (0, LimitReason::Contactor, 0, LimitReason::Contactor)
+            }
+            Some(status) => {
+                let (discharge, discharge_reason) = lowest(&[
+                    (status.discharge_limit, LimitReason::Bms),
+                    (
+                        derate(status.discharge_limit, status.temperature_max),
+                        LimitReason::Thermal,
+                    ),
+                    (ride_mode().discharge_limit(), LimitReason::RideMode),
+                ]);
+                let thermal_charge = if status.temperature_min < CHARGE_MIN_TEMPERATURE {
+                    0
+                } else {
+                    derate(status.charge_limit, status.temperature_max)
+                };
+                let (charge, charge_reason) = lowest(&[
+                    (status.charge_limit, LimitReason::Bms),
+                    (thermal_charge, LimitReason::Thermal),
+                ]);
+                (discharge, discharge_reason, charge, charge_reason)
+            }
+            None => (0, LimitReason::NoBattery, 0, LimitReason::NoBattery),
+        };
+
+        let limit = PowerLimit {
+            discharge: ramp(self.limit.discharge, discharge, elapsed_ms).min(bms_limit.0),
+            charge: ramp(self.limit.charge, charge, elapsed_ms).min(bms_limit.1),
+            discharge_reason,
+            charge_reason,
+        };
+        if limit.discharge_reason != self.limit.discharge_reason {
+            info!("Discharge current limited by {}", discharge_reason.name());
+        }
+        self.limit = limit;
+        cortex_m::interrupt::free(|cs| LIMIT.borrow(cs).replace(limit));
+        limit
+    }
+}
+
+pub fn print_limit() {
+    let limit = limit();
+    println!("ride mode: {}", ride_mode().name());
+    println!(
+        "discharge: {}.{} A, limited by {}",
+        limit.discharge / 10,
+        limit.discharge % 10,
+        limit.discharge_reason.name()
+    );
+    println!(
+        "charge: {}.{} A, limited by {}",
+        limit.charge / 10,
+        limit.charge % 10,
+        limit.charge_reason.name()
+    );
+}
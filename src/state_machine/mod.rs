@@ -1,8 +1,18 @@
-use crate::io::SwitchGearInput;
+use crate::{
+    battery::{self, PACK_COUNT},
+    can::supervision::{CanNode, NodeStatus},
+    contactor::{Contactor, ContactorEvent, ContactorPhase},
+    fault::{self, FaultCode},
+    io::SwitchGearInput,
+};
+use core::cell::RefCell;
+use cortex_m::interrupt::Mutex;
 use defmt::Format;
+use embassy_time::Instant;
 use log::info;
+use nuen_can::Frame;
 
-#[derive(Format)]
+#[derive(Format, Clone, Copy, PartialEq, Eq)]
 pub enum Vehiclestate {
     Lock,
     Parking,
@@ -10,10 +20,24 @@ pub enum Vehiclestate {
     PreRiding,
     Riding,
     Charging,
+    Fault,
 }
+// latest state, readable from other tasks
+static STATE: Mutex<RefCell<Vehiclestate>> = Mutex::new(RefCell::new(Vehiclestate::Lock));
+
+pub fn state() -> Vehiclestate {
+    cortex_m::interrupt::free(|cs| *STATE.borrow(cs).borrow())
+}
+
+pub fn is_riding() -> bool {
+    matches!(state(), Vehiclestate::PreRiding | Vehiclestate::Riding)
+}
+
 pub struct StateControl {
     state: Vehiclestate,
     input: SwitchGearInput,
+    nodes: [NodeStatus; 4],
+    contactor: Contactor,
 }
 
 impl StateControl {
@@ -21,6 +45,8 @@ impl StateControl {
         StateControl {
             state: Vehiclestate::Lock,
             input,
+            nodes: [NodeStatus::Unknown; 4],
+            contactor: Contactor::init(),
         }
     }
 
@@ -34,9 +60,56 @@ impl StateControl {
             Vehiclestate::PreRiding => self.handle_preriding_state(),
             Vehiclestate::Riding => self.handle_riding_state(),
             Vehiclestate::Charging => self.handle_charging_state(),
+            Vehiclestate::Fault => self.handle_fault_state(),
+        };
+        // the high voltage is only connected to ride
+        let close = matches!(self.state, Vehiclestate::PreRiding | Vehiclestate::Riding);
+        match self.contactor.update(Instant::now(), close) {
+            Some(ContactorEvent::Failed) => {
+                fault::raise(FaultCode::Contactor);
+                self.report_fault(FaultCode::Contactor);
+            }
+            Some(ContactorEvent::Closed) => info!("High voltage connected"),
+            Some(ContactorEvent::Opened) => info!("High voltage disconnected"),
+            None => {}
+        }
+        cortex_m::interrupt::free(|cs| STATE.borrow(cs).replace(self.state));
+        &self.state
+    }
+
+    // contactor commands to send every cycle, one per pack
+    pub fn contactor_frames(&self) -> [Frame; PACK_COUNT] {
+        self.contactor.command_frames()
+    }
+
+    // called when the CAN monitor reports a node status change
+    pub fn update_node(&mut self, node: CanNode, status: NodeStatus) {
+        self.nodes[node as usize] = status;
+    }
+
+    fn node_alive(&self, node: CanNode) -> bool {
+        self.nodes[node as usize].is_alive()
+    }
+
+    // called when another task reports a new fault
+    pub fn report_fault(&mut self, code: FaultCode) {
+        if !code.is_critical() {
+            return;
+        }
+        match self.state {
+            Vehiclestate::PreRiding | Vehiclestate::Riding => {
+                info!("change state to Fault due to {}", code.name());
+                self.state = Vehiclestate::Fault;
+            }
+            _ => {}
         }
     }
 
+    // headlight switched on at the switch gear
+    pub fn headlight_on(&self) -> bool {
+        self.input.pc_power_sw()
+    }
+
     pub fn current_state(&self) -> &Vehiclestate {
         &self.state
     }
@@ -61,6 +134,18 @@ impl StateControl {
 
     fn handle_preriding_state(&mut self) -> &Vehiclestate {
         // check Pin, BMS, MC, OBC, MCU temperature < 50
+        if !self.node_alive(CanNode::Bms) || !self.node_alive(CanNode::Motor) {
+            return &self.state;
+        }
+        // wait for the BMS data, and for the battery to be usable
+        match battery::status() {
+            Some(status) if !status.alarms.is_critical() => {}
+            _ => return &self.state,
+        }
+        // precharge runs from the update, ride once the main contactor is closed
+        if self.contactor.phase() != ContactorPhase::Closed {
+            return &self.state;
+        }
         info!("change state from Preriding to Riding");
         self.state = Vehiclestate::Riding;
         &self.state
@@ -81,4 +166,17 @@ impl StateControl {
         }
         &self.state
     }
+
+    fn handle_fault_state(&mut self) -> &Vehiclestate {
+        // a failed contactor sequence is over once the contactors are open
+        if self.contactor.phase() == ContactorPhase::Open {
+            fault::clear(FaultCode::Contactor);
+        }
+        // stay in Fault until all critical faults are gone
+        if !fault::has_critical() {
+            info!("change state from Fault to Parking");
+            self.state = Vehiclestate::Parking;
+        }
+        &self.state
+    }
 }
@@ -1,20 +1,129 @@
-use embassy_time::{Instant, Timer};
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Instant, Timer};
 use log::{info, warn};
 
-use crate::{tasks::BMS_CYCLE, CanBmsBox};
+use crate::{
+    battery::{
+        self,
+        protocol::{BmsKind, BmsProtocol, BmsUpdate, DalyBms, NuenBms},
+        BmsAlarms, PackArbiter, SocEstimator, PACK_COUNT,
+    },
+    can::j1939::dm::{self, Dtc, DtcSource, Lamp, FMI_CONDITION_EXISTS, SPN_BMS_ALARM},
+    config,
+    tasks::BMS_CYCLE,
+    CanBmsBox, CanTxBox,
+};
+
+// same as the supervision of the BMS node
+const BMS_STATUS_TIMEOUT: Duration = Duration::from_millis(500);
 
 #[embassy_executor::task]
-pub async fn bms_task(channel: &'static CanBmsBox) {
+pub async fn bms_task(channel: &'static CanBmsBox, tx_channel: &'static CanTxBox) {
     info!("Started BMS Task !!!");
+    match config::settings().bms {
+        BmsKind::Nuen => bms::<NuenBms>(channel, tx_channel).await,
+        BmsKind::Daly => bms::<DalyBms>(channel, tx_channel).await,
+    }
+}
+
+async fn bms<P: BmsProtocol>(channel: &CanBmsBox, tx_channel: &CanTxBox) -> ! {
+    // one driver per pack
+    let mut protocols: [P; PACK_COUNT] = core::array::from_fn(P::init);
+    let mut arbiter = PackArbiter::init();
+    let mut estimator = SocEstimator::init();
+    let mut last_status = [None; PACK_COUNT];
+    let mut alarms = BmsAlarms::default();
+    info!("BMS protocol: {}", protocols[0].kind().name());
     loop {
+        // wake up on every frame, and periodically to check the timeout
+        let received = select(channel.receive(), Timer::after_millis(BMS_CYCLE)).await;
         let start = Instant::now();
+        let mut updated = false;
+        if let Either::First(frame) = received {
+            for (pack, protocol) in protocols.iter_mut().enumerate() {
+                match protocol.decode(&frame) {
+                    Some(BmsUpdate::Cells(cells)) => battery::publish_cells(pack, Some(cells)),
+                    Some(BmsUpdate::Status(status)) => {
+                        last_status[pack] = Some(start);
+                        arbiter.update(pack, Some(status));
+                        updated = true;
+                    }
+                    None => {}
+                }
+            }
+        }
+        // also while the frames of the other packs keep the task awake
+        for (pack, protocol) in protocols.iter_mut().enumerate() {
+            let lost = last_status[pack]
+                .is_some_and(|last| start.duration_since(last) > BMS_STATUS_TIMEOUT);
+            if lost {
+                warn!(
+                    "No status from the BMS of pack {} for {}ms",
+                    pack + 1,
+                    BMS_STATUS_TIMEOUT.as_millis()
+                );
+                // the status is complete again once every frame was received
+                protocol.reset();
+                last_status[pack] = None;
+                arbiter.update(pack, None);
+                battery::publish_cells(pack, None);
+                updated = true;
+            }
+        }
+        if updated {
+            // every alarm is reported, also of the packs which are not used
+            if arbiter.alarms() != alarms {
+                report_alarms(alarms, arbiter.alarms());
+                alarms = arbiter.alarms();
+            }
+            battery::publish_packs(arbiter.packs());
+            match arbiter.combine() {
+                Some(mut status) => {
+                    // the estimate is used when the BMS has no SOC
+                    status.soc_estimate = estimator.update(&status, start);
+                    status.soc = status.bms_soc.unwrap_or(status.soc_estimate);
+                    battery::publish(status);
+                }
+                None => battery::invalidate(),
+            }
+        }
+        for protocol in protocols.iter_mut() {
+            if let Some(request) = protocol.request(start) {
+                if tx_channel.try_send(request).is_err() {
+                    warn!("Drop BMS request, CAN TX queue is full");
+                }
+            }
+        }
 
-        let frame =  channel.receive().await;
         let ms = Instant::now().duration_since(start).as_millis();
         if ms > BMS_CYCLE {
             warn!("BMS task done after {ms}ms > {BMS_CYCLE}ms");
+        }
+    }
+}
+
+// set the DTC of every alarm which was raised or cleared
+fn report_alarms(previous: BmsAlarms, current: BmsAlarms) {
+    for (bit, (alarm, name)) in BmsAlarms::ALL.iter().enumerate() {
+        let active = current.contains(*alarm);
+        if active == previous.contains(*alarm) {
+            continue;
+        }
+        if active {
+            warn!("BMS alarm: {}", name);
         } else {
-            Timer::after_millis(BMS_CYCLE - ms).await;
+            info!("BMS alarm cleared: {}", name);
         }
+        let lamp = if BmsAlarms(*alarm).is_critical() {
+            Lamp::RedStop
+        } else {
+            Lamp::AmberWarning
+        };
+        let dtc = Dtc {
+            spn: SPN_BMS_ALARM + bit as u32,
+            fmi: FMI_CONDITION_EXISTS,
+            lamp,
+        };
+        dm::report(DtcSource::Bms, dtc, active);
     }
 }
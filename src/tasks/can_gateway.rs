@@ -0,0 +1,61 @@
+use crate::{
+    can::{
+        bus::{BxCanRx, BxCanTx},
+        gateway::{self, CanBus},
+        supervision,
+    },
+    CanGatewayBox, CanTxBox,
+};
+use embassy_futures::select::{select, Either};
+use embassy_stm32::can::{Can, CanRx, CanTx};
+use log::{info, warn};
+use nuen_can::bus::{CanReceiver, CanTransmitter};
+
+#[embassy_executor::task]
+pub async fn can_gateway_task(
+    mut can: Can<'static>,
+    tx: CanTx<'static>,
+    rx: CanRx<'static>,
+    channel: &'static CanGatewayBox,
+    tx_channel: &'static CanTxBox,
+) {
+    // the CAN2 filter banks are set up together with CAN1 in the CanTx task
+    can.enable().await;
+    can_gateway(BxCanTx(tx), BxCanRx(rx), channel, tx_channel).await
+}
+
+// gateway loop for the body bus, independent of the CAN driver
+pub async fn can_gateway<T: CanTransmitter, R: CanReceiver>(
+    mut tx: T,
+    mut rx: R,
+    channel: &CanGatewayBox,
+    tx_channel: &CanTxBox,
+) -> ! {
+    info!("Started CAN Gateway Task !!!");
+    loop {
+        match select(rx.receive(), channel.receive()).await {
+            Either::First(Ok(frame)) => {
+                let id = frame.id();
+                supervision::frame_received(id);
+                if let Some(frame) = gateway::route(CanBus::Body, &frame) {
+                    if tx_channel.try_send(frame).is_err() {
+                        warn!("Drop gateway frame {:#x}, CAN1 queue is full", id);
+                    }
+                }
+            }
+            Either::First(Err(e)) => {
+                info!("Failed to receive CAN2 Frame: {:?}", e);
+            }
+            // frames from the powertrain bus, dropped instead of blocking
+            // when nothing acknowledges them on the body bus
+            Either::Second(frame) => {
+                if tx.try_transmit(&frame).is_none() {
+                    warn!(
+                        "Drop gateway frame {:#x}, CAN2 mailboxes are full",
+                        frame.id()
+                    );
+                }
+            }
+        }
+    }
+}
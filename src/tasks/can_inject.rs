@@ -0,0 +1,24 @@
+use crate::{can::inject, tasks::CAN_INJECT_CYCLE, CanInjectBox, CanTxBox};
+use embassy_time::{Instant, Timer};
+use log::{info, warn};
+
+#[embassy_executor::task]
+pub async fn can_inject_task(tx_channel: &'static CanTxBox, inject_channel: &'static CanInjectBox) {
+    info!("Started CAN Inject Task !!!");
+    loop {
+        let start = Instant::now();
+        while let Some(frame) = inject::due_frame(start) {
+            tx_channel.send(frame).await;
+        }
+        while let Some(frame) = inject::injected_frame() {
+            inject_channel.send(frame).await;
+        }
+
+        let ms = Instant::now().duration_since(start).as_millis();
+        if ms > CAN_INJECT_CYCLE {
+            warn!("CanInject task done after {ms}ms > {CAN_INJECT_CYCLE}ms");
+        } else {
+            Timer::after_millis(CAN_INJECT_CYCLE - ms).await;
+        }
+    }
+}
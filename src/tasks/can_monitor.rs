@@ -0,0 +1,75 @@
+use crate::{
+    can::{
+        health::{CanHealth, ErrorState, HealthEvent},
+        supervision::{CanNode, NodeStatus, NodeSupervisor},
+        traffic,
+    },
+    fault::{self, FaultCode},
+    tasks::CAN_MON_CYCLE,
+    SimulinkBox, SimulinkType,
+};
+use embassy_stm32::pac;
+use embassy_time::{Instant, Timer};
+use log::{info, warn};
+
+#[embassy_executor::task]
+pub async fn can_monitor_task(channel: &'static SimulinkBox) {
+    let mut health = CanHealth::init(pac::CAN1);
+    let mut supervisor = NodeSupervisor::init();
+    info!("Started CAN Monitor Task !!!");
+    loop {
+        let start = Instant::now();
+        if let Some(HealthEvent::StateChanged(from, to)) = health.update() {
+            warn!("CAN state changed from {:?} to {:?}", from, to);
+            if to == ErrorState::Passive || to == ErrorState::BusOff {
+                if fault::raise(FaultCode::CanErrorPassive) {
+                    channel
+                        .send(SimulinkType::Fault(FaultCode::CanErrorPassive))
+                        .await;
+                }
+            } else {
+                fault::clear(FaultCode::CanErrorPassive);
+            }
+            if to == ErrorState::BusOff {
+                if fault::raise(FaultCode::CanBusOff) {
+                    channel
+                        .send(SimulinkType::Fault(FaultCode::CanBusOff))
+                        .await;
+                }
+            } else {
+                fault::clear(FaultCode::CanBusOff);
+            }
+        }
+
+        traffic::update();
+
+        while let Some((node, status)) = supervisor.check() {
+            info!("{} node status changed to {:?}", node.name(), status);
+            if let Some(code) = timeout_fault(node) {
+                if status == NodeStatus::Timeout {
+                    if fault::raise(code) {
+                        channel.send(SimulinkType::Fault(code)).await;
+                    }
+                } else {
+                    fault::clear(code);
+                }
+            }
+            channel.send(SimulinkType::NodeStatus(node, status)).await;
+        }
+
+        let ms = Instant::now().duration_since(start).as_millis();
+        if ms > CAN_MON_CYCLE {
+            warn!("CanMonitor task done after {ms}ms > {CAN_MON_CYCLE}ms");
+        } else {
+            Timer::after_millis(CAN_MON_CYCLE - ms).await;
+        }
+    }
+}
+
+fn timeout_fault(node: CanNode) -> Option<FaultCode> {
+    match node {
+        CanNode::Bms => Some(FaultCode::BmsTimeout),
+        CanNode::Motor => Some(FaultCode::MotorTimeout),
+        _ => None,
+    }
+}
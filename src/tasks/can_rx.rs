@@ -1,34 +1,123 @@
-use crate::{tasks::CAN_RX_CYCLE, CanBmsBox, CanMotorBox, CanObcBox, SimulinkBox, SimulinkType};
+use crate::{
+    can::{
+        bus::BxCanRx,
+        canopen,
+        e2e::{E2eChecker, E2eEvent, E2E_RX},
+        gateway::{self, CanBus},
+        health, isotp,
+        j1939::J1939Id,
+        supervision::{self, CanNode},
+        trace, traffic, Direction,
+    },
+    fault::{self, FaultCode},
+    tasks::CAN_RX_CYCLE,
+    CanBmsBox, CanGatewayBox, CanInjectBox, CanMotorBox, CanObcBox, IsoTpBox, J1939Box,
+    SimulinkBox, SimulinkType,
+};
 use embassy_stm32::can::CanRx;
-use embassy_time::{Instant, Timer};
-use log::{info, warn};
+use log::warn;
+use nuen_can::{
+    bus::BusError,
+    pipeline::{self, CanRxConfig, RxHooks},
+    Frame,
+};
 
+static CAN_RX_CONFIG: CanRxConfig = CanRxConfig {
+    cycle: CAN_RX_CYCLE,
+};
+
+// the RX task feeds every handler queue
+#[allow(clippy::too_many_arguments)]
 #[embassy_executor::task]
 pub async fn can_rx_task(
-    mut rx: CanRx<'static>,
+    rx: CanRx<'static>,
     channel0: &'static SimulinkBox,
     channel2: &'static CanBmsBox,
     channel3: &'static CanMotorBox,
     channel4: &'static CanObcBox,
+    channel5: &'static IsoTpBox,
+    channel7: &'static J1939Box,
+    channel8: &'static CanInjectBox,
+    channel9: &'static CanGatewayBox,
 ) {
-    info!("Started CANRX Task !!!");
-    loop {
-        let start = Instant::now();
-        match rx.read().await {
-            Ok(evelope) => {
-                info!("Receive CAN Frame {:?}", evelope);
-                channel0.send(SimulinkType::Can(evelope.frame)).await;
-            }
-            Err(e) => {
-                info!("Failed to receive CAN Frame: {:?}", e);
+    let hooks = CanRxHooks {
+        e2e: E2eChecker::init(&E2E_RX),
+        channel0,
+        channel2,
+        channel3,
+        channel4,
+        channel5,
+        channel7,
+        channel9,
+    };
+    pipeline::can_rx(BxCanRx(rx), channel8, &CAN_RX_CONFIG, hooks).await
+}
+
+struct CanRxHooks {
+    e2e: E2eChecker,
+    channel0: &'static SimulinkBox,
+    channel2: &'static CanBmsBox,
+    channel3: &'static CanMotorBox,
+    channel4: &'static CanObcBox,
+    channel5: &'static IsoTpBox,
+    channel7: &'static J1939Box,
+    channel9: &'static CanGatewayBox,
+}
+
+impl RxHooks for CanRxHooks {
+    fn received(&mut self, frame: &Frame) {
+        traffic::record(frame, Direction::Rx);
+        trace::record(frame, Direction::Rx);
+        if let Some(frame) = gateway::route(CanBus::Powertrain, frame) {
+            if self.channel9.try_send(frame).is_err() {
+                warn!("Drop gateway frame, CAN2 queue is full");
             }
         }
+    }
+
+    fn error(&mut self, error: BusError) {
+        health::record_bus_error(error);
+    }
 
-        let ms = Instant::now().duration_since(start).as_millis();
-        if ms > CAN_RX_CYCLE {
-            warn!("CanRx task done after {ms}ms > {CAN_RX_CYCLE}ms");
+    async fn dispatch(&mut self, frame: Frame) {
+        let id = frame.id();
+        supervision::frame_received(id);
+        if let Some(status) = self.e2e.check(id, frame.data()) {
+            match self.e2e.report(id, status) {
+                Some(E2eEvent::Failed) if fault::raise(FaultCode::E2eError) => {
+                    self.channel0
+                        .send(SimulinkType::Fault(FaultCode::E2eError))
+                        .await;
+                }
+                Some(E2eEvent::Recovered) if !self.e2e.any_faulty() => {
+                    fault::clear(FaultCode::E2eError);
+                }
+                _ => {}
+            }
+            // never pass a corrupted or repeated message to the handlers
+            if !status.is_ok() {
+                warn!("Drop CAN Frame {:#x}, E2E check {:?}", id, status);
+                return;
+            }
+        }
+        // route the frames of known nodes to their handler task
+        let routed = if isotp::channel_of(id).is_some() {
+            self.channel5.try_send(frame)
         } else {
-            Timer::after_millis(CAN_RX_CYCLE - ms).await;
+            match supervision::node_of(id).or_else(|| canopen::owner_of(&frame)) {
+                Some(CanNode::Bms) => self.channel2.try_send(frame),
+                Some(CanNode::Motor) => self.channel3.try_send(frame),
+                Some(CanNode::Obc) => self.channel4.try_send(frame),
+                // other extended frames are handled by the J1939 stack
+                _ if J1939Id::from_frame(&frame).is_some() => self.channel7.try_send(frame),
+                _ => {
+                    self.channel0.send(SimulinkType::Can(frame)).await;
+                    Ok(())
+                }
+            }
+        };
+        if routed.is_err() {
+            warn!("Drop CAN Frame {:#x}, handler queue is full", id);
         }
     }
 }
@@ -1,74 +1,75 @@
 use crate::{
-    display::{CanMessage, SegLcd},
-    tasks::CAN_TX_CYCLE,
-    ScreenBox, ScreenRequest,
+    can::{
+        bus::BxCanTx,
+        e2e::E2eProtector,
+        gateway::{self, CanBus, CAN2_FIRST_BANK},
+        trace, traffic,
+        tx_queue::{self, TX_CLASS_RULES},
+        Direction,
+    },
+    tasks::CAN_TX_POLL,
+    CanGatewayBox, CanTxBox, ScreenBox,
+};
+use embassy_stm32::can::{filter::Mask32, Can, CanTx, Fifo};
+use log::warn;
+use nuen_can::{
+    pipeline::{self, CanTxConfig, TxHooks},
+    tx_queue::{TxStats, TX_CLASSES},
+    Frame,
 };
-use embassy_stm32::can::{filter::Mask32, Can, CanTx, Fifo, Frame};
-use embassy_time::{Instant, Timer};
-use log::{info, warn};
 
-impl From<CanMessage> for Frame {
-    fn from(message: CanMessage) -> Self {
-        Frame::new_extended(message.id, &message.data).unwrap()
-    }
-}
+static CAN_TX_CONFIG: CanTxConfig = CanTxConfig {
+    poll: CAN_TX_POLL,
+    rules: &TX_CLASS_RULES,
+};
 
 #[embassy_executor::task]
 pub async fn can_tx_task(
     mut can: Can<'static>,
-    mut tx: CanTx<'static>,
+    tx: CanTx<'static>,
     channel: &'static ScreenBox,
+    frame_channel: &'static CanTxBox,
+    gateway_channel: &'static CanGatewayBox,
 ) {
-    let mut display = SegLcd::init();
-
     can.enable().await;
+    // CAN1 owns the filter banks of both controllers
     can.modify_filters()
-        .enable_bank(0, Fifo::Fifo0, Mask32::accept_all());
-    tx.write(&display.get_status_1().into()).await;
-    tx.write(&display.get_status_2().into()).await;
-    tx.write(&display.get_status_3().into()).await;
-    info!("Started CANTX Task !!!");
-    loop {
-        let start = Instant::now();
-        match channel.receive().await {
-            ScreenRequest::Power(en) => {
-                info!("send LeftIndicator to screen");
-                if en {
-                    tx.write(&display.lcd_on().into()).await;
-                } else {
-                    tx.write(&display.lcd_off().into()).await;
-                }
-            }
-            ScreenRequest::Ready => {
-                tx.write(&display.rdy_on().into()).await;
-            }
-            ScreenRequest::LeftIndicator => {
-                info!("send LeftIndicator to screen");
-                tx.write(&display.left_ind_on().into()).await;
-            }
-            ScreenRequest::RightIndicator => {
-                info!("send LeftIndicator to screen");
-                tx.write(&display.right_ind_on().into()).await;
-            }
-            ScreenRequest::Speed(speed) => {
-                info!("send Speed {} to screen", speed);
-            }
-            ScreenRequest::Soc(soc) => {
-                info!("send SOC {} to screen", soc);
-            }
-            ScreenRequest::Abs(abs) => {
-                info!("send ABS {} to screen", abs);
-            }
-            ScreenRequest::HeadLight(on) => {
-                info!("send HeadLight {} to screen", on);
+        .enable_bank(0, Fifo::Fifo0, Mask32::accept_all())
+        .set_split(CAN2_FIRST_BANK)
+        .slave_filters()
+        .enable_bank(CAN2_FIRST_BANK, Fifo::Fifo0, Mask32::accept_all());
+    let hooks = CanTxHooks {
+        e2e: E2eProtector::init(),
+        gateway: gateway_channel,
+    };
+    pipeline::can_tx(BxCanTx(tx), channel, frame_channel, &CAN_TX_CONFIG, hooks).await
+}
+
+struct CanTxHooks {
+    e2e: E2eProtector,
+    gateway: &'static CanGatewayBox,
+}
+
+impl TxHooks for CanTxHooks {
+    fn prepare(&mut self, frame: Frame) -> Frame {
+        self.e2e.protect(frame)
+    }
+
+    // the display frames are also forwarded to the body bus if the gateway has a rule for them
+    fn built(&mut self, frame: &Frame) {
+        if let Some(frame) = gateway::route(CanBus::Powertrain, frame) {
+            if self.gateway.try_send(frame).is_err() {
+                warn!("Drop gateway frame, CAN2 queue is full");
             }
         }
+    }
 
-        let ms = Instant::now().duration_since(start).as_millis();
-        if ms > CAN_TX_CYCLE {
-            warn!("CanTx task done after {ms}ms > {CAN_TX_CYCLE}ms");
-        } else {
-            Timer::after_millis(CAN_TX_CYCLE - ms).await;
-        }
+    fn written(&mut self, frame: &Frame) {
+        traffic::record(frame, Direction::Tx);
+        trace::record(frame, Direction::Tx);
+    }
+
+    fn stats(&mut self, stats: &[TxStats; TX_CLASSES]) {
+        tx_queue::record_stats(stats);
     }
 }
@@ -1,7 +1,21 @@
-use crate::{cmd::CommandLine, print, println};
+use crate::{
+    battery::{self, protocol::BmsKind},
+    can::{
+        health, inject, supervision,
+        trace::{self, TraceFormat, Trigger},
+        traffic, tx_queue,
+    },
+    cmd::CommandLine,
+    config::{self, SUPPORTED_BITRATES},
+    motor,
+    power::{self, RideMode},
+    print, println, system_reset,
+};
 use embassy_stm32::{mode::Async, usart::UartRx};
+use embassy_time::Duration;
 use heapless::Vec;
 use log::{info, warn};
+use nuen_can::Frame;
 
 #[embassy_executor::task]
 pub async fn cmd_task(mut rx: UartRx<'static, Async>) {
@@ -44,6 +58,7 @@ pub async fn cmd_task(mut rx: UartRx<'static, Async>) {
             print!("\x1b[1;32mnuen-embassy >\x1b[0m ");
         } else if (buffer[0] as char).is_ascii_alphanumeric()
             || (buffer[0] as char).is_ascii_punctuation()
+            || buffer[0] == b' '
         {
             print!("{}", buffer[0] as char);
             // Append valid bytes to command string
@@ -64,9 +79,31 @@ fn init_command_line() -> CommandLine<'static> {
     command_line.add_command("bye", "Say goodbye", say_good_bye);
     command_line.add_command("reset", "reset the board", |_| {
         println!("reset the board immediately !!!");
-        cortex_m::peripheral::SCB::sys_reset();
+        system_reset();
     });
     command_line.add_command("help", "print help", |_| {});
+    command_line.add_command(
+        "can",
+        "CAN bus tools: can stats|nodes|top|tx|trace|send|inject",
+        can_command,
+    );
+    command_line.add_command("bms", "battery status: bms [cells]", bms_command);
+    command_line.add_command("range", "remaining range and consumption", |_| {
+        battery::range::print_range()
+    });
+    command_line.add_command("motor", "motor controller status", |_| {
+        motor::print_status()
+    });
+    command_line.add_command(
+        "power",
+        "current limits: power [mode eco|normal|sport]",
+        power_command,
+    );
+    command_line.add_command(
+        "config",
+        "persisted settings: config [can1|can2 <kbit/s>] [autobaud on|off] [bms <name>] [gear <ratio>] [wheel <mm>] [save]",
+        config_command,
+    );
     command_line
 }
 
@@ -77,3 +114,185 @@ fn say_good_bye(args: &[&str]) {
 fn clear_terminal(_args: &[&str]) {
     print!("\x1b[2J\x1b[H");
 }
+
+fn can_command(args: &[&str]) {
+    match args.first() {
+        Some(&"stats") => health::print_stats(),
+        Some(&"nodes") => supervision::print_nodes(),
+        Some(&"top") => match args.get(1) {
+            Some(&"reset") => traffic::reset(),
+            _ => traffic::print_top(),
+        },
+        Some(&"tx") => tx_queue::print_stats(),
+        Some(&"trace") => trace_command(&args[1..]),
+        Some(&"send") => send_command(&args[1..]),
+        Some(&"inject") => inject_command(&args[1..]),
+        _ => println!("usage: can stats|nodes|top [reset]|tx|trace|send|inject"),
+    }
+}
+
+fn bms_command(args: &[&str]) {
+    match args {
+        [] => battery::print_status(),
+        ["cells"] => battery::print_cells(),
+        _ => println!("usage: bms [cells]"),
+    }
+}
+
+fn power_command(args: &[&str]) {
+    match args {
+        [] => power::print_limit(),
+        ["mode", name] => match RideMode::ALL.iter().find(|mode| mode.name() == *name) {
+            Some(mode) => power::set_ride_mode(*mode),
+            None => println!("unknown ride mode: {}", name),
+        },
+        _ => println!("usage: power [mode eco|normal|sport]"),
+    }
+}
+
+fn config_command(args: &[&str]) {
+    match args {
+        [] => config::print_settings(),
+        [bus @ ("can1" | "can2"), kbit] => {
+            let bitrate = kbit.parse::<u32>().unwrap_or(0) * 1000;
+            if !SUPPORTED_BITRATES.contains(&bitrate) {
+                println!("supported: 125, 250, 500 and 1000 kbit/s");
+                return;
+            }
+            config::update(|settings| match *bus {
+                "can1" => settings.can1_bitrate = bitrate,
+                _ => settings.can2_bitrate = bitrate,
+            });
+            println!("used after save and reset");
+        }
+        ["autobaud", on @ ("on" | "off")] => {
+            config::update(|settings| settings.auto_baud = *on == "on");
+            println!("used after save and reset");
+        }
+        ["bms", name] => match BmsKind::ALL.iter().find(|kind| kind.name() == *name) {
+            Some(kind) => {
+                config::update(|settings| settings.bms = *kind);
+                println!("used after save and reset");
+            }
+            None => println!("supported: nuen, daly"),
+        },
+        ["gear", ratio] => match ratio.parse::<f32>() {
+            Ok(ratio) if ratio > 0.0 && ratio < 100.0 => {
+                config::update(|settings| settings.gear_ratio = (ratio * 100.0) as u16);
+                println!("gear ratio set");
+            }
+            _ => println!("motor turns per wheel turn, 0.01 .. 100"),
+        },
+        ["wheel", mm] => match mm.parse::<u16>() {
+            Ok(mm) if mm > 0 && mm < u16::MAX => {
+                config::update(|settings| settings.wheel_circumference = mm);
+                println!("wheel circumference set");
+            }
+            _ => println!("wheel circumference in mm"),
+        },
+        ["save"] => match config::save() {
+            Ok(()) => println!("config saved"),
+            Err(e) => println!("save failed: {:?}", e),
+        },
+        _ => println!("usage: config [can1|can2 <kbit/s>] [autobaud on|off] [bms <name>] [gear <ratio>] [wheel <mm>] [save]"),
+    }
+}
+
+fn trace_command(args: &[&str]) {
+    match args {
+        [] => trace::print_status(),
+        ["arm"] | ["arm", "fault"] => trace::arm(Trigger::Fault),
+        ["arm", "manual"] => trace::arm(Trigger::Manual),
+        ["arm", "id", id] => match parse_hex(id) {
+            Some(id) => trace::arm(Trigger::Id(id)),
+            None => println!("invalid ID: {}", id),
+        },
+        ["trigger"] => trace::trigger(),
+        ["dump"] | ["dump", "candump"] => trace::dump(TraceFormat::Candump),
+        ["dump", "asc"] => trace::dump(TraceFormat::Asc),
+        _ => println!("usage: can trace [arm fault|manual|id <hex>] [trigger] [dump candump|asc]"),
+    }
+}
+
+fn send_command(args: &[&str]) {
+    match args {
+        [] => inject::print_jobs(),
+        ["stop"] => inject::stop(None),
+        ["stop", id] => match parse_hex(id) {
+            Some(id) => inject::stop(Some(id)),
+            None => println!("invalid ID: {}", id),
+        },
+        _ => match parse_frame(args) {
+            Ok((frame, period)) => {
+                if !inject::send(frame, period) {
+                    println!("too many frames, stop one with: can send stop <id>");
+                }
+            }
+            Err(e) => {
+                println!("{}", e);
+                println!("usage: can send <id> <hex data> [ext|std] [period ms] | stop [id]");
+            }
+        },
+    }
+}
+
+fn inject_command(args: &[&str]) {
+    match parse_frame(args) {
+        Ok((frame, None)) => {
+            if !inject::inject(frame) {
+                println!("inject queue is full");
+            }
+        }
+        Ok((_, Some(_))) => println!("periodic injection is not supported"),
+        Err(e) => {
+            println!("{}", e);
+            println!("usage: can inject <id> <hex data> [ext|std]");
+        }
+    }
+}
+
+// parse `<id> <hex data> [ext|std] [period ms]`, IDs above 0x7FF are extended by default
+fn parse_frame(args: &[&str]) -> Result<(Frame, Option<Duration>), &'static str> {
+    let [id, data, options @ ..] = args else {
+        return Err("missing ID or data");
+    };
+    let id = parse_hex(id).ok_or("invalid ID")?;
+    if data.len() % 2 != 0 {
+        return Err("invalid data");
+    }
+    let mut bytes: Vec<u8, 8> = Vec::new();
+    for index in (0..data.len()).step_by(2) {
+        let byte = data
+            .get(index..index + 2)
+            .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+            .ok_or("invalid data")?;
+        bytes.push(byte).map_err(|_| "more than 8 data bytes")?;
+    }
+
+    let mut extended = id > 0x7FF;
+    let mut period = None;
+    for option in options {
+        match *option {
+            "ext" => extended = true,
+            "std" => extended = false,
+            ms => {
+                let ms = ms.parse().map_err(|_| "invalid period")?;
+                period = Some(Duration::from_millis(ms));
+            }
+        }
+    }
+    let frame = if extended {
+        Frame::new_extended(id, &bytes)
+    } else if id <= 0x7FF {
+        Frame::new_standard(id as u16, &bytes)
+    } else {
+        return Err("invalid ID");
+    };
+    frame.map(|frame| (frame, period)).ok_or("invalid ID")
+}
+
+// parse a hexadecimal number with or without the 0x prefix
+fn parse_hex(text: &str) -> Option<u32> {
+    let text = text.trim_start_matches("0x").trim_start_matches("0X");
+    u32::from_str_radix(text, 16).ok()
+}
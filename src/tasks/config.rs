@@ -0,0 +1,23 @@
+use embassy_time::Timer;
+use log::{info, warn};
+
+use crate::{config, state_machine, tasks::CONFIG_SAVE_INTERVAL};
+
+// writes the settings changed while riding, the CPU stalls while the flash
+// sector is erased, so this never runs on the high priority executor
+#[embassy_executor::task]
+pub async fn config_task() {
+    info!("Started Config Task !!!");
+    loop {
+        config::wait_save_request().await;
+        // the request is repeated while the vehicle stands
+        if state_machine::is_riding() {
+            continue;
+        }
+        if let Err(e) = config::save_pending() {
+            warn!("Config not saved: {:?}", e);
+        }
+        // every save takes a record of the sector
+        Timer::after_millis(CONFIG_SAVE_INTERVAL).await;
+    }
+}
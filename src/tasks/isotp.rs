@@ -0,0 +1,86 @@
+use crate::{
+    can::isotp::{self, IsoTp, IsoTpUser, ISOTP_CONFIG},
+    system_reset,
+    tasks::{ISOTP_CYCLE, ISOTP_RESET_DELAY},
+    uds::{self, UdsServer, NEGATIVE_RESPONSE},
+    CanTxBox, IsoTpBox,
+};
+use embassy_futures::select::{select, Either};
+use embassy_time::{Instant, Timer};
+use log::{info, warn};
+use static_cell::StaticCell;
+
+#[embassy_executor::task]
+pub async fn isotp_task(channel: &'static IsoTpBox, tx_channel: &'static CanTxBox) {
+    // about 8 KB of buffers per channel, kept out of the task arena
+    static CHANNELS: StaticCell<[IsoTp; ISOTP_CONFIG.len()]> = StaticCell::new();
+    let channels = CHANNELS.init_with(|| {
+        ISOTP_CONFIG
+            .each_ref()
+            .map(|channel| IsoTp::init(&channel.config))
+    });
+    let mut server = UdsServer::init(&uds::IDENTITY, Instant::now());
+    info!("Started ISO-TP Task !!!");
+    loop {
+        // wake up on every frame, and periodically for STmin and timeouts
+        if let Either::First(frame) =
+            select(channel.receive(), Timer::after_millis(ISOTP_CYCLE)).await
+        {
+            let id = frame.id();
+            if let Some(index) = isotp::channel_of(id) {
+                let now = Instant::now();
+                match channels[index].receive(frame.data(), now) {
+                    Ok(Some(payload)) => match ISOTP_CONFIG[index].user {
+                        IsoTpUser::Diagnostic => {
+                            let session = server.session();
+                            let response = server.handle(payload, now, &uds::vehicle_data());
+                            if server.session() != session {
+                                info!("UDS session changed to {:?}", server.session());
+                            }
+                            if let Some(response) = response {
+                                if let [NEGATIVE_RESPONSE, sid, nrc] = response[..] {
+                                    info!("UDS negative response {:#x} to service {:#x}", nrc, sid);
+                                }
+                                if let Err(e) = channels[index].send(&response) {
+                                    warn!("UDS response dropped: {:?}", e);
+                                }
+                            }
+                        }
+                        IsoTpUser::Bms => {
+                            info!("ISO-TP message of {} bytes on {:#x}", payload.len(), id);
+                        }
+                    },
+                    Ok(None) => {}
+                    Err(e) => warn!("ISO-TP receive error on {:#x}: {:?}", id, e),
+                }
+            }
+        }
+
+        let now = Instant::now();
+        if server.poll(now) {
+            info!("UDS session timeout, back to default session");
+        }
+        for (index, channel) in channels.iter_mut().enumerate() {
+            loop {
+                match channel.poll(now) {
+                    Ok(Some(frame)) => tx_channel.send(frame).await,
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!(
+                            "ISO-TP error on {:#x}: {:?}",
+                            ISOTP_CONFIG[index].config.tx_id, e
+                        );
+                        break;
+                    }
+                }
+            }
+        }
+
+        // reset only after the positive response went out
+        if server.take_reset() {
+            info!("UDS ECU reset");
+            Timer::after_millis(ISOTP_RESET_DELAY).await;
+            system_reset();
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use crate::{
+    can::j1939::{dm, J1939, PGN_REQUEST, VCU_ADDRESS, VCU_NAME},
+    tasks::J1939_CYCLE,
+    CanTxBox, J1939Box,
+};
+use embassy_futures::select::{select, Either};
+use embassy_time::{Instant, Timer};
+use log::info;
+use static_cell::StaticCell;
+
+#[embassy_executor::task]
+pub async fn j1939_task(channel: &'static J1939Box, tx_channel: &'static CanTxBox) {
+    // about 7 KB of transport sessions, kept out of the task arena
+    static STACK: StaticCell<J1939> = StaticCell::new();
+    let j1939 = STACK.init_with(init_j1939);
+    let mut diagnostic = dm::DiagnosticMessages::init();
+    j1939.start(Instant::now());
+    info!("Started J1939 Task !!!");
+    loop {
+        // wake up on every frame, and periodically for the protocol timers
+        if let Either::First(frame) =
+            select(channel.receive(), Timer::after_millis(J1939_CYCLE)).await
+        {
+            j1939.receive(&frame, Instant::now());
+        }
+        j1939.poll(Instant::now());
+        diagnostic.poll(j1939, Instant::now());
+        while let Some(frame) = j1939.next_frame() {
+            tx_channel.send(frame).await;
+        }
+    }
+}
+
+// init the J1939 stack and the PGN handlers
+fn init_j1939() -> J1939 {
+    let mut j1939 = J1939::init(VCU_NAME, VCU_ADDRESS);
+    j1939.subscribe(PGN_REQUEST, dm::on_request);
+    j1939
+}
@@ -1,22 +1,40 @@
 mod bms_handler;
+mod can_gateway;
+mod can_inject;
+mod can_monitor;
 mod can_rx;
 mod can_tx;
 mod cmd;
+mod config;
+mod isotp;
+mod j1939;
 mod motor_handler;
 mod obc_handler;
 mod simulink;
 
 const CAN_RX_CYCLE: u64 = 50; // in ms
-const CAN_TX_CYCLE: u64 = 50; // in ms
+const CAN_TX_POLL: u64 = 5; // in ms
+const CAN_MON_CYCLE: u64 = 10; // in ms
 const SIM_APP_CYCLE: u64 = 50; // in ms
 const BMS_CYCLE: u64 = 50; // in ms
 const MOTOR_CYCLE: u64 = 50; // in ms
 const OBC_CYCLE: u64 = 50; // in ms
+const ISOTP_CYCLE: u64 = 1; // in ms
+const ISOTP_RESET_DELAY: u64 = 50; // in ms
+const J1939_CYCLE: u64 = 10; // in ms
+const CAN_INJECT_CYCLE: u64 = 10; // in ms
+const CONFIG_SAVE_INTERVAL: u64 = 60_000; // in ms
 
 pub use bms_handler::bms_task;
+pub use can_gateway::can_gateway_task;
+pub use can_inject::can_inject_task;
+pub use can_monitor::can_monitor_task;
 pub use can_rx::can_rx_task;
 pub use can_tx::can_tx_task;
 pub use cmd::cmd_task;
+pub use config::config_task;
+pub use isotp::isotp_task;
+pub use j1939::j1939_task;
 pub use motor_handler::motor_task;
 pub use obc_handler::obc_task;
 pub use simulink::state_machine_task;
@@ -1,20 +1,441 @@
-use embassy_time::{Instant, Timer};
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Instant, Timer};
 use log::{info, warn};
 
-use crate::{tasks::MOTOR_CYCLE, CanMotorBox};
+use crate::{
+    battery::range,
+    can::{
+        canopen::{
+            CanOpen, CanOpenEvent, NmtCommand, NodeState, PdoEntry, PdoMap, CANOPEN_CONFIG,
+            OD_HEARTBEAT_PRODUCER, SDO_MAX_REQUESTS,
+        },
+        j1939::dm::{self, Dtc, DtcSource, Lamp, FMI_CONDITION_EXISTS, SPN_MOTOR_EMERGENCY},
+        supervision::CanNode,
+    },
+    contactor,
+    motor::{self, MotorFaults, MotorStatus},
+    power::PowerLimiter,
+    tasks::MOTOR_CYCLE,
+    CanMotorBox, CanTxBox,
+};
+
+// device type, the low word is the device profile
+const DEVICE_TYPE: u16 = 0x1000;
+const PROFILE_DRIVE: u32 = 402;
+
+// CiA 402 objects of the motor controller
+const STATUSWORD: u16 = 0x6041;
+const VELOCITY_ACTUAL: u16 = 0x606C;
+// in 0.1 % of the rated torque
+const TORQUE_ACTUAL: u16 = 0x6077;
+// in mV
+const DC_LINK_VOLTAGE: u16 = 0x6079;
+// manufacturer specific DC bus current of the controller, in 0.1 A
+const DC_LINK_CURRENT: u16 = 0x2011;
+// manufacturer specific motor and controller temperature, in °C
+const TEMPERATURES: u16 = 0x2012;
+const SUB_MOTOR_TEMPERATURE: u8 = 1;
+const SUB_CONTROLLER_TEMPERATURE: u8 = 2;
+// manufacturer specific fault bits, see `motor::MotorFaults`
+const FAULTS: u16 = 0x2013;
+// manufacturer specific RMS phase current, in 0.1 A
+const PHASE_CURRENT: u16 = 0x2014;
+// manufacturer specific battery current limits of the controller, in 0.1 A
+const BATTERY_LIMITS: u16 = 0x2010;
+const SUB_DISCHARGE_LIMIT: u8 = 1;
+const SUB_CHARGE_LIMIT: u8 = 2;
+
+// status sent by the motor controller
+const MOTOR_TPDO1: PdoMap = PdoMap {
+    number: 1,
+    entries: &[
+        PdoEntry {
+            index: STATUSWORD,
+            subindex: 0,
+            bits: 16,
+        },
+        PdoEntry {
+            index: VELOCITY_ACTUAL,
+            subindex: 0,
+            bits: 32,
+        },
+        PdoEntry {
+            index: TORQUE_ACTUAL,
+            subindex: 0,
+            bits: 16,
+        },
+    ],
+};
+
+// DC bus voltage and current, used for the precharge and the consumption
+const MOTOR_TPDO2: PdoMap = PdoMap {
+    number: 2,
+    entries: &[
+        PdoEntry {
+            index: DC_LINK_VOLTAGE,
+            subindex: 0,
+            bits: 32,
+        },
+        PdoEntry {
+            index: DC_LINK_CURRENT,
+            subindex: 0,
+            bits: 16,
+        },
+        PdoEntry {
+            index: PHASE_CURRENT,
+            subindex: 0,
+            bits: 16,
+        },
+    ],
+};
+
+// temperatures and faults of the motor and the controller
+const MOTOR_TPDO3: PdoMap = PdoMap {
+    number: 3,
+    entries: &[
+        PdoEntry {
+            index: TEMPERATURES,
+            subindex: SUB_MOTOR_TEMPERATURE,
+            bits: 8,
+        },
+        PdoEntry {
+            index: TEMPERATURES,
+            subindex: SUB_CONTROLLER_TEMPERATURE,
+            bits: 8,
+        },
+        PdoEntry {
+            index: FAULTS,
+            subindex: 0,
+            bits: 16,
+        },
+    ],
+};
+
+// the status is published once every TPDO was received
+const TPDO1_RECEIVED: u8 = 1 << 0;
+const TPDO2_RECEIVED: u8 = 1 << 1;
+const TPDO3_RECEIVED: u8 = 1 << 2;
+const ALL_RECEIVED: u8 = TPDO1_RECEIVED | TPDO2_RECEIVED | TPDO3_RECEIVED;
+// values of a PDO older than this are not integrated
+const MAX_STEP: Duration = Duration::from_secs(1);
+
+// battery current limits sent to the motor controller, there is no torque
+// request yet, so the controlword is never sent and the drive stays in
+// "switch on disabled" with the power stage off
+const MOTOR_RPDO1: PdoMap = PdoMap {
+    number: 1,
+    entries: &[
+        PdoEntry {
+            index: BATTERY_LIMITS,
+            subindex: SUB_DISCHARGE_LIMIT,
+            bits: 16,
+        },
+        PdoEntry {
+            index: BATTERY_LIMITS,
+            subindex: SUB_CHARGE_LIMIT,
+            bits: 16,
+        },
+    ],
+};
+
+// heartbeat and status period requested from the motor controller, in ms
+const MOTOR_HEARTBEAT: u16 = 100;
+const MOTOR_STATUS_PERIOD: u16 = 50;
+const MOTOR_TEMPERATURE_PERIOD: u16 = 500;
+
+// the whole configuration is queued at once, see `MotorController::configure`
+const CONFIG_REQUESTS: usize = 2
+    + MOTOR_TPDO1.tpdo_requests()
+    + MOTOR_TPDO2.tpdo_requests()
+    + MOTOR_TPDO3.tpdo_requests()
+    + MOTOR_RPDO1.rpdo_requests();
+const _: () = assert!(CONFIG_REQUESTS <= SDO_MAX_REQUESTS);
 
 #[embassy_executor::task]
-pub async fn motor_task(channel: &'static CanMotorBox) {
+pub async fn motor_task(channel: &'static CanMotorBox, tx_channel: &'static CanTxBox) {
+    let mut motor = MotorController::init();
     info!("Started MOTOR Task !!!");
     loop {
+        // wake up on every frame, and periodically for the timers and the command
+        if let Either::First(frame) =
+            select(channel.receive(), Timer::after_millis(MOTOR_CYCLE)).await
+        {
+            if let Some(event) = motor.canopen.receive(&frame, Instant::now()) {
+                motor.on_event(event);
+            }
+        }
         let start = Instant::now();
+        while let Some(event) = motor.canopen.poll(start) {
+            motor.on_event(event);
+        }
+        motor.update(start);
+        while let Some(frame) = motor.canopen.next_frame() {
+            tx_channel.send(frame).await;
+        }
 
-        let frame =  channel.receive().await;
         let ms = Instant::now().duration_since(start).as_millis();
         if ms > MOTOR_CYCLE {
             warn!("MOTOR task done after {ms}ms > {MOTOR_CYCLE}ms");
-        } else {
-            Timer::after_millis(MOTOR_CYCLE - ms).await;
         }
     }
 }
+
+struct MotorController {
+    canopen: CanOpen,
+    node_id: Option<u8>,
+    // SDO configuration in progress, the node is started when it is done
+    configuring: bool,
+    config_failed: bool,
+    next_command: Instant,
+    limiter: PowerLimiter,
+    status: MotorStatus,
+    // TPDOs received since the start, see `ALL_RECEIVED`
+    received: u8,
+    // reception of the last status and DC bus PDO
+    last_status: Option<Instant>,
+    last_dc_bus: Option<Instant>,
+}
+
+impl MotorController {
+    fn init() -> Self {
+        let mut canopen = CanOpen::init();
+        let node_id = CANOPEN_CONFIG
+            .iter()
+            .find(|config| config.owner == CanNode::Motor)
+            .map(|config| {
+                canopen.add_node(config.node_id, config.heartbeat_timeout);
+                // restart the node so it is configured from a known state
+                canopen.nmt(NmtCommand::ResetCommunication, config.node_id);
+                config.node_id
+            });
+        MotorController {
+            canopen,
+            node_id,
+            configuring: false,
+            config_failed: false,
+            next_command: Instant::now(),
+            limiter: PowerLimiter::init(),
+            status: MotorStatus::new(),
+            received: 0,
+            last_status: None,
+            last_dc_bus: None,
+        }
+    }
+
+    fn on_event(&mut self, event: CanOpenEvent) {
+        match event {
+            CanOpenEvent::BootUp(node) => {
+                info!("Motor controller {:#x} booted", node);
+                self.configure(node);
+            }
+            // pre-operational without a boot-up, our reset was missed
+            CanOpenEvent::StateChanged(node, NodeState::PreOperational) if !self.configuring => {
+                self.configure(node);
+            }
+            CanOpenEvent::StateChanged(node, state) => {
+                info!("Motor controller {:#x} is {:?}", node, state);
+            }
+            CanOpenEvent::HeartbeatLost(node) => {
+                warn!("Motor controller {:#x} heartbeat lost", node);
+                motor::invalidate();
+                self.received = 0;
+            }
+            // code 0 is sent when the controller has no error any more
+            CanOpenEvent::Emergency { node, code: 0, .. } => {
+                info!("Motor controller {:#x} emergency reset", node);
+                dm::clear_source(DtcSource::Motor);
+            }
+            CanOpenEvent::Emergency {
+                node,
+                code,
+                register,
+            } => {
+                warn!(
+                    "Motor controller {:#x} emergency {:#x}, error register {:#x}",
+                    node, code, register
+                );
+                let dtc = Dtc {
+                    spn: SPN_MOTOR_EMERGENCY + (code >> 8) as u32,
+                    fmi: FMI_CONDITION_EXISTS,
+                    lamp: Lamp::AmberWarning,
+                };
+                dm::report(DtcSource::Motor, dtc, true);
+            }
+            CanOpenEvent::Sdo(sdo) => match sdo.result {
+                Ok(data) if sdo.index == DEVICE_TYPE && data.len() >= 2 => {
+                    let profile = u16::from_le_bytes([data[0], data[1]]) as u32;
+                    if profile != PROFILE_DRIVE {
+                        warn!("Motor controller {:#x} has profile {}", sdo.node, profile);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!(
+                        "Motor controller {:#x} SDO {:#x}:{} failed: {:?}",
+                        sdo.node, sdo.index, sdo.subindex, e
+                    );
+                    self.config_failed = true;
+                }
+            },
+            CanOpenEvent::Pdo {
+                node,
+                number: 1,
+                data,
+            } => {
+                let value = |index| MOTOR_TPDO1.value(&data, index, 0);
+                let (Some(statusword), Some(velocity), Some(torque)) = (
+                    value(STATUSWORD),
+                    value(VELOCITY_ACTUAL),
+                    value(TORQUE_ACTUAL),
+                ) else {
+                    warn!("Motor controller {:#x} TPDO1 too short", node);
+                    return;
+                };
+                self.status.statusword = statusword as u16;
+                self.status.rpm = velocity as i32;
+                self.status.torque = torque as u16 as i16;
+                if let Some(seconds) = step(&mut self.last_status) {
+                    range::add_distance(motor::vehicle_speed(self.status.rpm) * seconds);
+                }
+                self.received(TPDO1_RECEIVED);
+            }
+            CanOpenEvent::Pdo {
+                node,
+                number: 2,
+                data,
+            } => {
+                let value = |index| MOTOR_TPDO2.value(&data, index, 0);
+                let (Some(voltage), Some(current), Some(phase_current)) = (
+                    value(DC_LINK_VOLTAGE),
+                    value(DC_LINK_CURRENT),
+                    value(PHASE_CURRENT),
+                ) else {
+                    warn!("Motor controller {:#x} TPDO2 too short", node);
+                    return;
+                };
+                self.status.dc_voltage = (voltage / 100) as u16;
+                self.status.dc_current = current as u16 as i16;
+                self.status.phase_current = phase_current as u16 as i16;
+                contactor::report_dc_bus(self.status.dc_voltage);
+                if let Some(seconds) = step(&mut self.last_dc_bus) {
+                    let power = voltage as f32 / 1000.0 * self.status.dc_current as f32 / 10.0;
+                    range::add_energy(power * seconds / 3600.0);
+                }
+                self.received(TPDO2_RECEIVED);
+            }
+            CanOpenEvent::Pdo {
+                node,
+                number: 3,
+                data,
+            } => {
+                let value = |subindex| MOTOR_TPDO3.value(&data, TEMPERATURES, subindex);
+                let (Some(motor), Some(controller), Some(faults)) = (
+                    value(SUB_MOTOR_TEMPERATURE),
+                    value(SUB_CONTROLLER_TEMPERATURE),
+                    MOTOR_TPDO3.value(&data, FAULTS, 0),
+                ) else {
+                    warn!("Motor controller {:#x} TPDO3 too short", node);
+                    return;
+                };
+                self.status.motor_temperature = motor as u8 as i8;
+                self.status.controller_temperature = controller as u8 as i8;
+                let faults = MotorFaults(faults as u16);
+                if faults != self.status.faults {
+                    report_faults(self.status.faults, faults);
+                    self.status.faults = faults;
+                }
+                self.received(TPDO3_RECEIVED);
+            }
+            CanOpenEvent::Pdo { .. } => {}
+        }
+    }
+
+    fn received(&mut self, tpdo: u8) {
+        self.received |= tpdo;
+        if self.received == ALL_RECEIVED {
+            motor::publish(self.status);
+        }
+    }
+
+    fn configure(&mut self, node: u8) {
+        self.configuring = true;
+        self.config_failed = false;
+        let result = self
+            .canopen
+            .sdo_read(node, DEVICE_TYPE, 0)
+            .and_then(|_| {
+                self.canopen.sdo_write(
+                    node,
+                    OD_HEARTBEAT_PRODUCER,
+                    0,
+                    &MOTOR_HEARTBEAT.to_le_bytes(),
+                )
+            })
+            .and_then(|_| {
+                self.canopen
+                    .map_tpdo(node, &MOTOR_TPDO1, MOTOR_STATUS_PERIOD)
+            })
+            .and_then(|_| {
+                self.canopen
+                    .map_tpdo(node, &MOTOR_TPDO2, MOTOR_STATUS_PERIOD)
+            })
+            .and_then(|_| {
+                self.canopen
+                    .map_tpdo(node, &MOTOR_TPDO3, MOTOR_TEMPERATURE_PERIOD)
+            })
+            .and_then(|_| self.canopen.map_rpdo(node, &MOTOR_RPDO1));
+        if let Err(e) = result {
+            warn!("Motor controller configuration failed: {:?}", e);
+            self.configuring = false;
+        }
+    }
+
+    fn update(&mut self, now: Instant) {
+        let limit = self.limiter.update(now);
+        let Some(node) = self.node_id else {
+            return;
+        };
+        if self.configuring && self.canopen.sdo_idle() {
+            self.configuring = false;
+            if self.config_failed {
+                warn!("Motor controller {:#x} not configured, stop it", node);
+                self.canopen.nmt(NmtCommand::Stop, node);
+            } else {
+                info!("Motor controller {:#x} configured, start it", node);
+                self.canopen.nmt(NmtCommand::Start, node);
+            }
+        }
+        if self.canopen.node_state(node) == Some(NodeState::Operational) && now >= self.next_command
+        {
+            let result = self.canopen.send_rpdo(
+                node,
+                &MOTOR_RPDO1,
+                &[limit.discharge as u32, limit.charge as u32],
+            );
+            if let Err(e) = result {
+                warn!("Motor command not sent: {:?}", e);
+            }
+            self.next_command = now + Duration::from_millis(MOTOR_CYCLE);
+        }
+    }
+}
+
+fn report_faults(previous: MotorFaults, current: MotorFaults) {
+    for (fault, name) in MotorFaults::ALL.iter() {
+        match (previous.contains(*fault), current.contains(*fault)) {
+            (false, true) => warn!("Motor controller fault: {}", name),
+            (true, false) => info!("Motor controller fault cleared: {}", name),
+            _ => {}
+        }
+    }
+}
+
+// seconds since the last call, none after a gap
+fn step(last: &mut Option<Instant>) -> Option<f32> {
+    let now = Instant::now();
+    let elapsed = last.map(|last| now.duration_since(last));
+    *last = Some(now);
+    elapsed
+        .filter(|elapsed| *elapsed <= MAX_STEP)
+        .map(|elapsed| elapsed.as_millis() as f32 / 1000.0)
+}
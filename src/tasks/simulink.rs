@@ -1,8 +1,10 @@
 use crate::{
+    battery, config,
     io::{BikeOutput, SwitchGearInput},
+    motor,
     state_machine::{StateControl, Vehiclestate},
     tasks::SIM_APP_CYCLE,
-    ScreenBox, ScreenRequest, SimulinkBox, SimulinkType,
+    CanTxBox, ScreenBox, ScreenRequest, SimulinkBox, SimulinkType,
 };
 use embassy_time::{Instant, Timer};
 use log::{info, warn};
@@ -13,8 +15,10 @@ pub async fn state_machine_task(
     mut bike_output: BikeOutput,
     channel0: &'static ScreenBox,
     channel1: &'static SimulinkBox,
+    tx_channel: &'static CanTxBox,
 ) {
     let mut state_control = StateControl::init(sw_gear);
+    let mut screen = ScreenFeed::init();
     bike_output.set_all(false);
     info!("hello simulink!");
     loop {
@@ -29,6 +33,14 @@ pub async fn state_machine_task(
                     info!("Receive Can Frame {:?}", frame);
                     channel0.send(ScreenRequest::LeftIndicator).await;
                 }
+                SimulinkType::Fault(code) => {
+                    info!("Receive fault {}", code.name());
+                    state_control.report_fault(code);
+                }
+                SimulinkType::NodeStatus(node, status) => {
+                    info!("Receive {} node status {:?}", node.name(), status);
+                    state_control.update_node(node, status);
+                }
             }
         }
         // update state depends on current input
@@ -36,8 +48,9 @@ pub async fn state_machine_task(
 
         // execute the specific task depending on current state
         match current_state {
-            Vehiclestate::Lock => { /* do something in Lock state */ }
+            Vehiclestate::Lock => config::request_save(),
             Vehiclestate::Parking => {
+                config::request_save();
                 channel0.send(ScreenRequest::Power(true)).await;
             }
             Vehiclestate::Unlock => { /* do something in Unlock state */ }
@@ -46,6 +59,16 @@ pub async fn state_machine_task(
             }
             Vehiclestate::PreRiding => { /* do something in PreRiding state */ }
             Vehiclestate::Charging => { /* do something in Charging state */ }
+            Vehiclestate::Fault => { /* do something in Fault state */ }
+        }
+        if *current_state != Vehiclestate::Lock {
+            let headlight = state_control.headlight_on();
+            screen.update(channel0, headlight).await;
+        }
+        for frame in state_control.contactor_frames() {
+            if tx_channel.try_send(frame).is_err() {
+                warn!("Drop contactor command, CAN TX queue is full");
+            }
         }
 
         let ms = Instant::now().duration_since(start).as_millis();
@@ -56,3 +79,48 @@ pub async fn state_machine_task(
         }
     }
 }
+
+// values shown on the screen, a request is only sent when one changes
+struct ScreenFeed {
+    speed: Option<u8>,
+    soc: Option<u8>,
+    range: Option<u16>,
+    headlight: Option<bool>,
+}
+
+impl ScreenFeed {
+    fn init() -> Self {
+        ScreenFeed {
+            speed: None,
+            soc: None,
+            range: None,
+            headlight: None,
+        }
+    }
+
+    async fn update(&mut self, channel: &ScreenBox, headlight: bool) {
+        // a stale speed is not shown, 0 while the motor controller is silent
+        let speed = motor::speed().unwrap_or(0);
+        if self.speed != Some(speed) {
+            self.speed = Some(speed);
+            channel.send(ScreenRequest::Speed(speed)).await;
+        }
+        // the last SOC stays on the screen while the BMS is silent
+        if let Some(status) = battery::status() {
+            if self.soc != Some(status.soc) {
+                self.soc = Some(status.soc);
+                channel.send(ScreenRequest::Soc(status.soc)).await;
+            }
+        }
+        if let Some(km) = battery::range::remaining() {
+            if self.range != Some(km) {
+                self.range = Some(km);
+                channel.send(ScreenRequest::Range(km)).await;
+            }
+        }
+        if self.headlight != Some(headlight) {
+            self.headlight = Some(headlight);
+            channel.send(ScreenRequest::HeadLight(headlight)).await;
+        }
+    }
+}
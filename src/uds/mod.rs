@@ -0,0 +1,18 @@
+// UDS server of the VCU, the request handling is in `nuen_can::uds`
+pub use nuen_can::uds::{UdsIdentity, UdsServer, VehicleData, NEGATIVE_RESPONSE};
+
+use crate::{battery, state_machine};
+
+pub static IDENTITY: UdsIdentity = UdsIdentity {
+    vin: *b"NUEN0000000000000",
+    sw_version: env!("CARGO_PKG_VERSION"),
+};
+
+// state of the vehicle read by the diagnostic services
+pub fn vehicle_data() -> VehicleData {
+    VehicleData {
+        riding: state_machine::is_riding(),
+        state: state_machine::state() as u8,
+        soc: battery::status().map(|status| status.soc),
+    }
+}